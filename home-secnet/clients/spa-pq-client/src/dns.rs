@@ -0,0 +1,155 @@
+// Knock over DNS queries; mirrors router/spa-pq/src/dns.rs.
+
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+const CHUNK_LEN: usize = 100;
+const MAX_CHUNKS: usize = 15;
+/// 253 minus the data labels (163) and the header label (21)
+const MAX_ZONE_LEN: usize = 68;
+
+/// First `nameserver` in /etc/resolv.conf, port 53.
+pub fn system_resolver() -> Result<SocketAddr> {
+    let conf = fs::read_to_string("/etc/resolv.conf")
+        .map_err(|e| anyhow!("read /etc/resolv.conf: {}", e))?;
+    conf.lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+        .ok_or_else(|| anyhow!("no nameserver in /etc/resolv.conf; pass --dns-server"))
+}
+
+/// Split `frame` into TXT query names under `zone`.
+pub fn query_names(frame: &[u8], zone: &str) -> Result<Vec<String>> {
+    let mut session = [0u8; 8];
+    getrandom::getrandom(&mut session).map_err(|e| anyhow!(e))?;
+    names_for(frame, zone, &session)
+}
+
+fn names_for(frame: &[u8], zone: &str, session: &[u8; 8]) -> Result<Vec<String>> {
+    let zone = zone.trim_end_matches('.');
+    if zone.is_empty() || zone.len() > MAX_ZONE_LEN {
+        return Err(anyhow!("dns zone must be 1..={} bytes", MAX_ZONE_LEN));
+    }
+    let chunks: Vec<&[u8]> = frame.chunks(CHUNK_LEN).collect();
+    if chunks.len() > MAX_CHUNKS {
+        return Err(anyhow!("knock too large for dns transport"));
+    }
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let b32 = base32_encode(c);
+            let labels: Vec<&str> = b32
+                .as_bytes()
+                .chunks(63)
+                .map(|l| std::str::from_utf8(l).unwrap_or_default())
+                .collect();
+            let mut hdr = session.to_vec();
+            hdr.extend_from_slice(&[i as u8, chunks.len() as u8]);
+            let hex: String = hdr.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{}.{}.{}", labels.join("."), hex, zone)
+        })
+        .collect())
+}
+
+/// Resolve each name (TXT) through `sock`, retrying unanswered queries.
+pub fn send_queries(sock: &UdpSocket, names: &[String]) -> Result<()> {
+    sock.set_read_timeout(Some(Duration::from_millis(1500)))?;
+    for name in names {
+        let mut id = [0u8; 2];
+        getrandom::getrandom(&mut id).map_err(|e| anyhow!(e))?;
+        let msg = txt_query(id, name);
+        let mut answered = false;
+        for _ in 0..3 {
+            sock.send(&msg)?;
+            let mut buf = [0u8; 512];
+            match sock.recv(&mut buf) {
+                Ok(n) if n >= 2 && buf[..2] == id => {
+                    answered = true;
+                    break;
+                }
+                _ => continue,
+            }
+        }
+        if !answered {
+            return Err(anyhow!("no dns answer for {}", name));
+        }
+    }
+    Ok(())
+}
+
+fn txt_query(id: [u8; 2], name: &str) -> Vec<u8> {
+    // recursion desired, one question
+    let mut q = vec![id[0], id[1], 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for l in name.split('.') {
+        q.push(l.len() as u8);
+        q.extend_from_slice(l.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&[0, 16, 0, 1]);
+    q
+}
+
+/// RFC 4648 base32, lowercase, without padding.
+fn base32_encode(data: &[u8]) -> String {
+    const ALPHA: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut acc, mut bits) = (0u32, 0u32);
+    for b in data {
+        acc = (acc << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHA[((acc >> bits) & 31) as usize] as char);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(ALPHA[((acc << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_match_the_daemon_vector() {
+        // produced by dns::tests::names_the_client_vector in router/spa-pq,
+        // whose decode_chunk reassembles them into 0..150
+        let frame: Vec<u8> = (0..150u8).collect();
+        let names = names_for(&frame, "k.example.net.", &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(
+            names,
+            [
+                "aaaqeayeaudaocajbifqydiob4ibceqtcqkrmfyydenbwha5dypsaijcemsckjr.\
+                 hfausukzmfuxc6mbrgiztinjwg44dsor3hq6t4p2aifbegrcfizduqskkjnge2t.\
+                 spkbiveu2ukvlfowczljnvyxk6l5qgcytd.01020304050607080002.k.example.net",
+                "mrswmz3infvgw3dnnzxxa4lson2hk5txpb4xu634pv7h7aebqkbyjbmgq6eitcu.\
+                 lrsgy5d4qsgjjhfev.01020304050607080102.k.example.net",
+            ]
+        );
+    }
+
+    #[test]
+    fn names_fit_dns_limits() {
+        let zone = format!("{}.{}", "y".repeat(40), "z".repeat(MAX_ZONE_LEN - 41));
+        let frame = [0xffu8; CHUNK_LEN * MAX_CHUNKS];
+        for name in names_for(&frame, &zone, &[0; 8]).unwrap() {
+            assert!(name.len() <= 253);
+            assert!(name.split('.').all(|l| !l.is_empty() && l.len() <= 63));
+        }
+        assert!(names_for(&[0; CHUNK_LEN * MAX_CHUNKS + 1], "k.example.net", &[0; 8]).is_err());
+        assert!(names_for(&frame, &format!("{}z", zone), &[0; 8]).is_err());
+        assert!(names_for(&frame, ".", &[0; 8]).is_err());
+
+        let q = txt_query([0x12, 0x34], "a.k.example.net");
+        assert_eq!(q[..4], [0x12, 0x34, 0x01, 0x00]);
+        assert!(q.ends_with(b"\x01a\x01k\x07example\x03net\x00\x00\x10\x00\x01"));
+    }
+}
@@ -0,0 +1,97 @@
+// Knock via ICMP echo payload; mirrors router/spa-pq/src/icmp.rs.
+
+use anyhow::{anyhow, Context, Result};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::SocketAddr;
+
+const CHUNK_LEN: usize = 600;
+const MAX_CHUNKS: usize = 3;
+
+/// Send `frame` as echo requests to `dst`. Uses an unprivileged ping socket
+/// when `net.ipv4.ping_group_range` allows it, otherwise a raw socket.
+pub fn send_echoes(dst: SocketAddr, frame: &[u8]) -> Result<()> {
+    let SocketAddr::V4(dst) = dst else {
+        return Err(anyhow!("icmp transport needs an IPv4 router address"));
+    };
+    let mut session = [0u8; 8];
+    getrandom::getrandom(&mut session).map_err(|e| anyhow!(e))?;
+    let mut ident = [0u8; 2];
+    getrandom::getrandom(&mut ident).map_err(|e| anyhow!(e))?;
+    let requests = echo_requests(frame, &session, ident)?;
+    let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))
+        .or_else(|_| Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)))
+        .context("open ICMP socket (allow ping_group_range or run with CAP_NET_RAW)")?;
+    let to = SockAddr::from(SocketAddr::V4(dst));
+    for pkt in requests {
+        sock.send_to(&pkt, &to)
+            .with_context(|| format!("send echo request to {}", dst.ip()))?;
+    }
+    Ok(())
+}
+
+/// ICMP echo requests carrying `frame`, one per chunk.
+fn echo_requests(frame: &[u8], session: &[u8; 8], ident: [u8; 2]) -> Result<Vec<Vec<u8>>> {
+    let chunks: Vec<&[u8]> = frame.chunks(CHUNK_LEN).collect();
+    if chunks.len() > MAX_CHUNKS {
+        return Err(anyhow!("knock too large for icmp transport"));
+    }
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            // type 8 (echo request), code 0, checksum, identifier, sequence
+            let mut pkt = vec![8, 0, 0, 0, ident[0], ident[1], 0, i as u8 + 1];
+            pkt.extend_from_slice(session);
+            pkt.extend_from_slice(&[i as u8, chunks.len() as u8]);
+            pkt.extend_from_slice(c);
+            let sum = checksum(&pkt);
+            pkt[2..4].copy_from_slice(&sum.to_be_bytes());
+            pkt
+        })
+        .collect())
+}
+
+/// RFC 1071 Internet checksum.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(b: &[u8]) -> String {
+        b.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    #[test]
+    fn echo_requests_match_the_daemon_vector() {
+        // produced by icmp::tests::parses_the_client_vector in router/spa-pq,
+        // whose parse_echo reads session 1..=8, chunk 0 of 1 and b"knock"
+        let pkts = echo_requests(b"knock", &[1, 2, 3, 4, 5, 6, 7, 8], [0xab, 0xcd]).unwrap();
+        assert_eq!(
+            pkts.iter().map(|p| hex(p)).collect::<Vec<_>>(),
+            ["0800f649abcd0001010203040506070800016b6e6f636b"]
+        );
+        // the checksum covers the whole message
+        assert_eq!(checksum(&pkts[0]), 0);
+    }
+
+    #[test]
+    fn frames_split_into_at_most_three_requests() {
+        let frame = [7u8; CHUNK_LEN * 2 + 1];
+        let pkts = echo_requests(&frame, &[0; 8], [0, 1]).unwrap();
+        assert_eq!(pkts.len(), 3);
+        assert_eq!(pkts[2][16..18], [2, 3]);
+        assert_eq!(pkts[2].len(), 8 + 10 + 1);
+        let too_big = [7u8; CHUNK_LEN * MAX_CHUNKS + 1];
+        assert!(echo_requests(&too_big, &[0; 8], [0, 1]).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+// --keepalive: stay running and re-knock before the grant runs out.
+//
+// The next knock is due after two thirds of the grant, as reported in an
+// encrypted ack or assumed from --grant-secs. A change of local address or
+// default route knocks at once, since the grant belongs to the old address.
+// Failed knocks back off exponentially. With --wg-interface the loop ends
+// once that interface, having come up, disappears (tunnel down).
+
+use anyhow::Result;
+use std::fs;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{knock_once, Cli, Config, Outcome, Setup};
+
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const TICK: Duration = Duration::from_secs(1);
+
+pub fn run(cli: &Cli, cfg: &Config, setup: &Setup) -> Result<()> {
+    let mut route = route_state(&cfg.router_host);
+    let mut tunnel_seen = false;
+    let mut failures = 0u32;
+    let mut next = Instant::now();
+    loop {
+        if let Some(iface) = &cli.wg_interface {
+            let up = Path::new("/sys/class/net").join(iface).exists();
+            if tunnel_seen && !up {
+                println!("{} is down; stopping keepalive.", iface);
+                return Ok(());
+            }
+            tunnel_seen |= up;
+        }
+        let now_route = route_state(&cfg.router_host);
+        if now_route != route {
+            println!("Local address or default route changed; re-knocking.");
+            route = now_route;
+            failures = 0;
+            next = Instant::now();
+        }
+        if Instant::now() >= next {
+            let wait = match knock_once(cli, cfg, setup, false) {
+                Ok(Outcome::Confirmed(secs)) => {
+                    failures = 0;
+                    interval(secs.unwrap_or(cli.grant_secs))
+                }
+                Ok(Outcome::Sent) => {
+                    failures = 0;
+                    interval(cli.grant_secs)
+                }
+                Ok(Outcome::NoReply) => backoff(&mut failures),
+                Err(e) => {
+                    eprintln!("knock failed: {:#}", e);
+                    backoff(&mut failures)
+                }
+            };
+            next = Instant::now() + wait;
+        }
+        thread::sleep(TICK);
+    }
+}
+
+fn interval(grant_secs: u64) -> Duration {
+    Duration::from_secs(grant_secs * 2 / 3).max(MIN_INTERVAL)
+}
+
+fn backoff(failures: &mut u32) -> Duration {
+    *failures += 1;
+    let d = MIN_INTERVAL * 2u32.saturating_pow(*failures - 1).min(64);
+    d.min(MAX_BACKOFF)
+}
+
+/// Local address used to reach the router plus the default route line.
+fn route_state(router_host: &str) -> (Option<String>, Option<String>) {
+    let local = (router_host, 0)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut a| a.next())
+        .and_then(|dst| {
+            let sock = UdpSocket::bind("0.0.0.0:0").ok()?;
+            sock.connect(dst).ok()?;
+            sock.local_addr().ok()
+        })
+        .map(|a| a.ip().to_string());
+    // Iface Destination Gateway ...; destination 00000000 is the default route
+    let default = fs::read_to_string("/proc/net/route").ok().and_then(|t| {
+        t.lines()
+            .skip(1)
+            .find(|l| l.split_whitespace().nth(1) == Some("00000000"))
+            .map(|l| l.split_whitespace().take(3).collect::<Vec<_>>().join(" "))
+    });
+    (local, default)
+}
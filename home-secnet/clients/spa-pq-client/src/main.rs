@@ -3,16 +3,34 @@
 use anyhow::{anyhow, Context, Result};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use hmac::{Hmac, Mac};
 use pqcrypto_mlkem::mlkem768 as kem;
 use pqcrypto_traits::kem::{Ciphertext as CtTrait, PublicKey as PkTrait, SharedSecret as SsTrait};
 use sha2::Sha256;
+use std::collections::BTreeMap;
 use std::fs;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
 use std::path::PathBuf;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+mod dns;
+mod fingerprint;
+mod icmp;
+mod keepalive;
+mod obfs;
+mod payload;
+mod reply;
+mod rotate;
+mod secret;
+mod ticket;
+
+use fingerprint::Fingerprint;
+use obfs::Encoding;
+use reply::ReplyMode;
+use secret::{Secret, SecretBytes};
+
 type HmacSha256 = Hmac<Sha256>;
 
 #[derive(Debug, serde::Deserialize)]
@@ -22,14 +40,148 @@ struct Config {
     wg_port: u16,
     kem_pub_b64: String,
     psk_b64: String,
+    /// Client identifier for the encrypted payload (enables packet v2)
+    #[serde(default)]
+    client_id: Option<String>,
+    /// Rotating knock port range "MIN-MAX" (daemon --rotate-ports)
+    #[serde(default)]
+    rotate_ports: Option<String>,
+    /// Rotation slot length in seconds (daemon --rotate-slot-secs)
+    #[serde(default)]
+    rotate_slot_secs: Option<u64>,
+    /// Secret the rotating ports derive from (daemon --port-secret-file)
+    #[serde(default)]
+    port_secret_b64: Option<String>,
+    /// TCP knock port (daemon --tcp-listen)
+    #[serde(default)]
+    tcp_port: Option<u16>,
+    /// Zone delegated to the daemon's DNS responder (daemon --dns-zone)
+    #[serde(default)]
+    dns_zone: Option<String>,
+    /// UDP source port of the local WireGuard endpoint (daemon --port-grants)
+    #[serde(default)]
+    wg_src_port: Option<u16>,
+    /// Hysteria2 wrapper port (daemon --wrap-port)
+    #[serde(default)]
+    wrap_port: Option<u16>,
+    /// Pinned fingerprint of `kem_pub_b64` (router `fingerprint`)
+    #[serde(default)]
+    kem_fingerprint: Option<String>,
+    /// Pinned fingerprint of the PSK commitment
+    #[serde(default)]
+    psk_fingerprint: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Transport {
+    /// Single UDP datagram
+    Udp,
+    /// Length-prefixed frame over TCP (no reply is ever sent)
+    Tcp,
+    /// TXT queries for a zone delegated to the daemon (no reply is ever sent)
+    Dns,
+    /// Payload of ICMP echo requests (no reply is ever sent)
+    Icmp,
+    /// QUIC-framed datagram to the Hysteria2 wrapper port (no reply is ever sent)
+    Wrapper,
+}
+
+#[derive(Subcommand, Debug)]
+enum ClientCommand {
+    /// Print fingerprints of the configured KEM public key and PSK
+    Fingerprint,
 }
 
 #[derive(Parser, Debug)]
 #[command(name = "spa-pq-client", version)]
 struct Cli {
+    #[command(subcommand)]
+    cmd: Option<ClientCommand>,
     /// Path to client config JSON
     #[arg(long, default_value = "clients/spa-pq-client.json")]
     config: PathBuf,
+    /// Client identifier sent in the encrypted payload (overrides config `client_id`)
+    #[arg(long)]
+    client_id: Option<String>,
+    /// Service to request, e.g. wg (repeatable)
+    #[arg(long = "service")]
+    services: Vec<String>,
+    /// Requested open duration in seconds (capped by the daemon's open_secs)
+    #[arg(long)]
+    duration: Option<u64>,
+    /// IPv4 address the grant is requested for
+    #[arg(long)]
+    target: Option<Ipv4Addr>,
+    /// Ask for a grant bound to this WireGuard source port, normally the
+    /// interface's ListenPort (overrides config `wg_src_port`)
+    #[arg(long)]
+    wg_src_port: Option<u16>,
+    /// Extra metadata as key=value (repeatable)
+    #[arg(long = "meta", value_parser = parse_key_val)]
+    meta: Vec<(String, String)>,
+    /// Wire encoding of the knock (must be accepted by the daemon's --encoding)
+    #[arg(long, value_enum, default_value_t = Encoding::Plain)]
+    encoding: Encoding,
+    /// Minimum random padding in bytes (obfs/quic)
+    #[arg(long, default_value_t = 0)]
+    pad_min: usize,
+    /// Maximum random padding in bytes (obfs/quic)
+    #[arg(long, default_value_t = 128)]
+    pad_max: usize,
+    /// Path MTU to the daemon; padding is cut so a UDP knock is not fragmented
+    #[arg(long, default_value_t = 1500)]
+    mtu: usize,
+    /// Reply mode configured on the daemon (--reply)
+    #[arg(long, value_enum, default_value_t = ReplyMode::Ok)]
+    reply: ReplyMode,
+    /// Local WireGuard interface used to confirm success in silent mode
+    #[arg(long)]
+    wg_interface: Option<String>,
+    /// How long to wait for a WireGuard handshake in silent mode (seconds)
+    #[arg(long, default_value_t = 10)]
+    probe_secs: u64,
+    /// Rotating knock port range MIN-MAX (overrides config `rotate_ports`)
+    #[arg(long)]
+    rotate_ports: Option<String>,
+    /// Rotation slot length in seconds (overrides config, default 60)
+    #[arg(long)]
+    rotate_slot_secs: Option<u64>,
+    /// Send a compact knock when a valid session ticket is saved
+    #[arg(long)]
+    compact: bool,
+    /// Where session tickets are kept (default: <config>.ticket.json)
+    #[arg(long)]
+    ticket_file: Option<PathBuf>,
+    /// Send the knock over UDP, TCP, DNS, ICMP (for networks that block UDP)
+    /// or to the wrapper port (where only the wrapper is reachable)
+    #[arg(long, value_enum, default_value_t = Transport::Udp)]
+    transport: Transport,
+    /// TCP knock port (overrides config `tcp_port`)
+    #[arg(long)]
+    tcp_port: Option<u16>,
+    /// Wrapper port for --transport wrapper (overrides config `wrap_port`)
+    #[arg(long)]
+    wrap_port: Option<u16>,
+    /// Knock zone for --transport dns (overrides config `dns_zone`)
+    #[arg(long)]
+    dns_zone: Option<String>,
+    /// Resolver for --transport dns (default: first nameserver in /etc/resolv.conf)
+    #[arg(long)]
+    dns_server: Option<SocketAddr>,
+    /// Keep running and re-knock before the grant expires; stops once
+    /// --wg-interface, after coming up, goes away
+    #[arg(long)]
+    keepalive: bool,
+    /// Grant length to assume when the daemon does not report one (seconds)
+    #[arg(long, default_value_t = 45)]
+    grant_secs: u64,
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String)> {
+    let (k, v) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected key=value, got {}", s))?;
+    Ok((k.to_string(), v.to_string()))
 }
 
 fn now_unix() -> i64 {
@@ -40,23 +192,174 @@ fn now_unix() -> i64 {
 }
 
 fn main() -> Result<()> {
+    secret::harden().context("disable core dumps")?;
     let cli = Cli::parse();
     let cfg_data = fs::read_to_string(&cli.config)
         .with_context(|| format!("read {}", cli.config.display()))?;
     let cfg: Config = serde_json::from_str(&cfg_data)?;
-    let _wg_port = cfg.wg_port; // referenced to satisfy dead_code lint
+
+    // Payload options require a client id; without one we send a plain v1 knock.
+    let client_id = cli.client_id.clone().or(cfg.client_id.clone());
+    let wg_src_port = cli.wg_src_port.or(cfg.wg_src_port);
+    if wg_src_port == Some(0) {
+        return Err(anyhow!("wg source port must be nonzero"));
+    }
+    let wants_payload = !cli.services.is_empty()
+        || cli.duration.is_some()
+        || cli.target.is_some()
+        || wg_src_port.is_some()
+        || !cli.meta.is_empty();
+    let knock_payload = match client_id {
+        Some(id) => {
+            let mut meta: BTreeMap<String, String> = cli.meta.iter().cloned().collect();
+            meta.entry("client_version".to_string())
+                .or_insert_with(|| env!("CARGO_PKG_VERSION").to_string());
+            Some(payload::KnockPayload {
+                client_id: id,
+                services: cli.services.clone(),
+                duration_secs: cli.duration.unwrap_or(0),
+                target: cli.target,
+                wg_src_port,
+                meta,
+            })
+        }
+        None if wants_payload => {
+            return Err(anyhow!(
+                "payload options need --client-id (or client_id in config)"
+            ))
+        }
+        None => None,
+    };
+    let ver: u8 = if knock_payload.is_some() { 2 } else { 1 };
 
     let pub_bytes = STANDARD.decode(cfg.kem_pub_b64.trim())?;
-    let psk = STANDARD.decode(cfg.psk_b64.trim())?;
-    if psk.len() != 32 {
+    let psk = Secret::new(STANDARD.decode(cfg.psk_b64.trim())?);
+    if psk.expose().len() != 32 {
         return Err(anyhow!("psk must be 32 bytes"));
     }
+    let kem_fp = Fingerprint::kem_pub(&pub_bytes);
+    let psk_fp = Fingerprint::psk(psk.expose());
+    if let Some(ClientCommand::Fingerprint) = cli.cmd {
+        println!(
+            "KEM public key  {}\n                {}",
+            kem_fp.hex(),
+            kem_fp.words()
+        );
+        println!(
+            "PSK commitment  {}\n                {}",
+            psk_fp.hex(),
+            psk_fp.words()
+        );
+        return Ok(());
+    }
+    // a pasted key that differs from the router's must never be used
+    for (name, pin, fp) in [
+        ("kem_fingerprint", &cfg.kem_fingerprint, &kem_fp),
+        ("psk_fingerprint", &cfg.psk_fingerprint, &psk_fp),
+    ] {
+        if let Some(pin) = pin {
+            if !fp.matches(pin) {
+                return Err(anyhow!(
+                    "{} does not match the configured key ({}); refusing to knock",
+                    name,
+                    fp.hex()
+                ));
+            }
+        }
+    }
     let pk =
         <kem::PublicKey as PkTrait>::from_bytes(&pub_bytes).map_err(|_| anyhow!("bad pubkey"))?;
 
-    let addr = format!("{}:{}", cfg.router_host, cfg.spa_port);
-    let mut addrs = addr.to_socket_addrs()?;
-    let dst = addrs.next().ok_or_else(|| anyhow!("resolve {}", addr))?;
+    let ticket_path = cli.ticket_file.clone().unwrap_or_else(|| {
+        let mut p = cli.config.clone().into_os_string();
+        p.push(".ticket.json");
+        PathBuf::from(p)
+    });
+    let setup = Setup {
+        pub_bytes,
+        pk,
+        psk,
+        ver,
+        knock_payload,
+        ticket_path,
+    };
+    if cli.keepalive {
+        return keepalive::run(&cli, &cfg, &setup);
+    }
+    knock_once(&cli, &cfg, &setup, true).map(|_| ())
+}
+
+/// Keys and payload shared by every knock of a run.
+struct Setup {
+    pub_bytes: Vec<u8>,
+    pk: kem::PublicKey,
+    psk: SecretBytes,
+    ver: u8,
+    knock_payload: Option<payload::KnockPayload>,
+    ticket_path: PathBuf,
+}
+
+/// What a knock achieved, as far as the client can tell.
+enum Outcome {
+    /// The daemon confirmed the grant; an encrypted ack reports its length
+    Confirmed(Option<u64>),
+    /// Sent over a path that never confirms anything
+    Sent,
+    /// A reply was expected but none arrived or it did not authenticate
+    NoReply,
+}
+
+/// Send one knock and report the result. `verify` lets silent mode wait for
+/// a fresh WireGuard handshake.
+fn knock_once(cli: &Cli, cfg: &Config, setup: &Setup, verify: bool) -> Result<Outcome> {
+    let Setup {
+        pub_bytes,
+        pk,
+        psk,
+        ver,
+        knock_payload,
+        ticket_path,
+    } = setup;
+    let spa_port = match cli.transport {
+        Transport::Tcp => cli
+            .tcp_port
+            .or(cfg.tcp_port)
+            .ok_or_else(|| anyhow!("--transport tcp needs --tcp-port (or tcp_port in config)"))?,
+        Transport::Wrapper => cli.wrap_port.or(cfg.wrap_port).ok_or_else(|| {
+            anyhow!("--transport wrapper needs --wrap-port (or wrap_port in config)")
+        })?,
+        _ => match cli.rotate_ports.as_ref().or(cfg.rotate_ports.as_ref()) {
+            Some(range) => {
+                let secret = cfg.port_secret_b64.as_deref().ok_or_else(|| {
+                    anyhow!("rotating ports need port_secret_b64 in config (from `provision --rotate-ports`)")
+                })?;
+                let secret = Secret::new(STANDARD.decode(secret.trim())?);
+                if secret.expose().len() != 32 {
+                    return Err(anyhow!("port_secret_b64 must be 32 bytes"));
+                }
+                rotate::current_port(
+                    secret.expose(),
+                    rotate::parse_port_range(range)?,
+                    cli.rotate_slot_secs.or(cfg.rotate_slot_secs).unwrap_or(60),
+                    now_unix(),
+                )
+            }
+            None => cfg.spa_port,
+        },
+    };
+    let dns_zone = cli.dns_zone.clone().or(cfg.dns_zone.clone());
+    let dst = match cli.transport {
+        // DNS knocks go to the local resolver, not to the router
+        Transport::Dns => match cli.dns_server {
+            Some(server) => server,
+            None => dns::system_resolver()?,
+        },
+        _ => {
+            let addr = format!("{}:{}", cfg.router_host, spa_port);
+            let mut addrs = addr.to_socket_addrs()?;
+            addrs.next().ok_or_else(|| anyhow!("resolve {}", addr))?
+        }
+    };
 
     let sock = UdpSocket::bind("0.0.0.0:0")?;
     sock.connect(dst)?;
@@ -73,43 +376,274 @@ fn main() -> Result<()> {
     getrandom::getrandom(&mut nonce).map_err(|e| anyhow!(e))?;
     let ts = now_unix();
 
+    let saved = if cli.compact {
+        ticket::load(ticket_path, ts)
+    } else {
+        None
+    };
+    let knock = match &saved {
+        Some(t) => {
+            let (pkt, secret) = ticket::compact_knock(t, &nonce, ts)?;
+            Knock {
+                pkt,
+                ack_key: reply::ack_key(secret.expose(), &nonce)?,
+                ticket_secret: None,
+            }
+        }
+        None => full_knock(
+            *ver,
+            pk,
+            psk.expose(),
+            client_ip_u32,
+            &nonce,
+            ts,
+            knock_payload.as_ref(),
+        )?,
+    };
+
+    // the wrapper port carries QUIC; anything else would stand out
+    let encoding = match cli.transport {
+        Transport::Wrapper => Encoding::Quic,
+        _ => cli.encoding,
+    };
+    // a TCP frame is not bound by the path, only by what the daemon accepts
+    let max_dgram = match cli.transport {
+        Transport::Tcp => TCP_MAX_FRAME,
+        _ => obfs::max_dgram(cli.mtu),
+    };
+    let dgram = obfs::encode(
+        &knock.pkt,
+        pub_bytes,
+        encoding,
+        cli.pad_min,
+        cli.pad_max,
+        max_dgram,
+    )?;
+    // DNS, ICMP and TCP carry the frame in pieces of their own
+    if matches!(cli.transport, Transport::Udp | Transport::Wrapper) && dgram.len() > max_dgram {
+        eprintln!(
+            "warning: {}-byte knock exceeds one datagram at MTU {} and will be fragmented; \
+             fragments may be dropped on the way (try --transport tcp)",
+            dgram.len(),
+            cli.mtu
+        );
+    }
+    match cli.transport {
+        Transport::Udp => {
+            sock.send(&dgram)?;
+        }
+        Transport::Tcp => {
+            send_tcp(dst, &dgram)?;
+            if cli.reply != ReplyMode::Silent {
+                println!("Knock sent over TCP. If valid, port should open shortly.");
+                return Ok(Outcome::Sent);
+            }
+        }
+        Transport::Dns => {
+            let zone = dns_zone.as_deref().ok_or_else(|| {
+                anyhow!("--transport dns needs --dns-zone (or dns_zone in config)")
+            })?;
+            dns::send_queries(&sock, &dns::query_names(&dgram, zone)?)?;
+            if cli.reply != ReplyMode::Silent {
+                println!("Knock sent over DNS. If valid, port should open shortly.");
+                return Ok(Outcome::Sent);
+            }
+        }
+        Transport::Icmp => {
+            icmp::send_echoes(dst, &dgram)?;
+            if cli.reply != ReplyMode::Silent {
+                println!("Knock sent over ICMP. If valid, port should open shortly.");
+                return Ok(Outcome::Sent);
+            }
+        }
+        Transport::Wrapper => {
+            sock.send(&dgram)?;
+            if cli.reply != ReplyMode::Silent {
+                println!("Knock sent to the wrapper port. If valid, it should open shortly.");
+                return Ok(Outcome::Sent);
+            }
+        }
+    }
+    let outcome = match cli.reply {
+        ReplyMode::Ok => {
+            sock.set_read_timeout(Some(Duration::from_millis(1000)))?;
+            let mut buf = [0u8; 16];
+            match sock.recv(&mut buf) {
+                Ok(n) if n >= 2 && &buf[..2] == b"OK" => {
+                    println!("OK.");
+                    Outcome::Confirmed(None)
+                }
+                _ => {
+                    println!("Knock sent. If valid, port should open shortly.");
+                    Outcome::NoReply
+                }
+            }
+        }
+        ReplyMode::Encrypted => {
+            sock.set_read_timeout(Some(Duration::from_millis(1000)))?;
+            let mut buf = [0u8; 512];
+            match sock.recv(&mut buf) {
+                Ok(n) => match reply::open_ack(knock.ack_key.expose(), &buf[..n]) {
+                    Some(ack) => {
+                        if let (Some(id), Some(secs), Some(secret)) =
+                            (&ack.ticket, ack.ticket_secs, &knock.ticket_secret)
+                        {
+                            let t = ticket::SavedTicket {
+                                id_b64: id.clone(),
+                                secret_b64: STANDARD.encode(secret.expose()),
+                                expires_unix: ts + secs as i64,
+                            };
+                            if let Err(e) = ticket::save(ticket_path, &t) {
+                                eprintln!("warning: could not save ticket: {:#}", e);
+                            }
+                        }
+                        println!("OK, port open for {} seconds.", ack.grant_secs);
+                        Outcome::Confirmed(Some(ack.grant_secs))
+                    }
+                    None => {
+                        println!("Knock sent; reply did not authenticate.");
+                        Outcome::NoReply
+                    }
+                },
+                Err(_) => {
+                    println!("Knock sent; no acknowledgement received.");
+                    Outcome::NoReply
+                }
+            }
+        }
+        ReplyMode::Silent => match &cli.wg_interface {
+            Some(iface) if verify => {
+                let ok = reply::probe_wg_handshake(
+                    iface,
+                    cfg.wg_port,
+                    ts,
+                    Duration::from_secs(cli.probe_secs),
+                )?;
+                if ok {
+                    println!("OK, WireGuard handshake on {} succeeded.", iface);
+                    Outcome::Confirmed(None)
+                } else {
+                    return Err(anyhow!(
+                        "no WireGuard handshake on {} within {}s",
+                        iface,
+                        cli.probe_secs
+                    ));
+                }
+            }
+            Some(_) => {
+                println!("Knock sent (silent mode).");
+                Outcome::Sent
+            }
+            None => {
+                println!("Knock sent (silent mode; pass --wg-interface to verify).");
+                Outcome::Sent
+            }
+        },
+    };
+    Ok(outcome)
+}
+
+/// Largest TCP frame the daemon accepts (mirrors router/spa-pq/src/tcp.rs
+/// MAX_FRAME); it drops the connection on anything longer.
+const TCP_MAX_FRAME: usize = 1472;
+
+/// Deliver one `u16 len | frame` knock and wait for the daemon to close.
+fn send_tcp(dst: SocketAddr, frame: &[u8]) -> Result<()> {
+    if frame.len() > TCP_MAX_FRAME {
+        return Err(anyhow!(
+            "{}-byte knock is longer than the daemon accepts over tcp ({} bytes)",
+            frame.len(),
+            TCP_MAX_FRAME
+        ));
+    }
+    let mut stream = TcpStream::connect_timeout(&dst, Duration::from_secs(3))
+        .with_context(|| format!("connect tcp {}", dst))?;
+    stream.set_write_timeout(Some(Duration::from_secs(3)))?;
+    let mut msg = Vec::with_capacity(2 + frame.len());
+    msg.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+    msg.extend_from_slice(frame);
+    stream.write_all(&msg)?;
+    // the daemon reads the frame and closes without replying
+    stream.set_read_timeout(Some(Duration::from_secs(3)))?;
+    let _ = stream.read(&mut [0u8; 1]);
+    Ok(())
+}
+
+struct Knock {
+    pkt: Vec<u8>,
+    ack_key: Secret<[u8; 32]>,
+    /// Secret of any ticket issued in reply (full knocks only)
+    ticket_secret: Option<Secret<[u8; 32]>>,
+}
+
+/// Build a full (v1/v2) knock.
+fn full_knock(
+    ver: u8,
+    pk: &kem::PublicKey,
+    psk: &[u8],
+    client_ip_u32: u32,
+    nonce: &[u8; 16],
+    ts: i64,
+    knock_payload: Option<&payload::KnockPayload>,
+) -> Result<Knock> {
     // encapsulate (crate returns (SharedSecret, Ciphertext))
-    let (shared, ct) = kem::encapsulate(&pk);
+    let (shared, ct) = kem::encapsulate(pk);
     let ct_bytes = <kem::Ciphertext as CtTrait>::as_bytes(&ct);
-    let key = <kem::SharedSecret as SsTrait>::as_bytes(&shared);
+    // pqcrypto's SharedSecret cannot be wiped; keep only a wiped copy
+    let mut key = Secret::new([0u8; 32]);
+    key.expose_mut()
+        .copy_from_slice(<kem::SharedSecret as SsTrait>::as_bytes(&shared));
+    let key = key.expose();
+
+    // v2: seal the payload; AAD binds it to ver || nonce || ts || client_ip
+    let sealed = match knock_payload {
+        Some(p) => {
+            let mut aad = Vec::with_capacity(1 + 16 + 8 + 4);
+            aad.push(ver);
+            aad.extend_from_slice(nonce);
+            aad.extend_from_slice(&ts.to_be_bytes());
+            aad.extend_from_slice(&client_ip_u32.to_be_bytes());
+            Some(payload::seal(key, psk, nonce, &aad, p)?)
+        }
+        None => None,
+    };
 
-    // HMAC over PSK || ver || nonce || ts (client_ip is NOT included)
+    // HMAC over PSK || ver || nonce || ts (v1: client_ip is NOT included;
+    // v2 also covers client_ip || sealed_len || sealed)
     let mut mac = HmacSha256::new_from_slice(key).map_err(|_| anyhow!("hmac key"))?;
-    mac.update(&psk);
-    mac.update(&[1u8]);
-    mac.update(&nonce);
+    mac.update(psk);
+    mac.update(&[ver]);
+    mac.update(nonce);
     mac.update(&ts.to_be_bytes());
+    if let Some(sealed) = &sealed {
+        mac.update(&client_ip_u32.to_be_bytes());
+        mac.update(&(sealed.len() as u16).to_be_bytes());
+        mac.update(sealed);
+    }
     let tag = mac.finalize().into_bytes();
 
     // packet v1: u8 ver(1) | u16 ct_len | ct | nonce(16) | ts(i64) | client_ip(u32) | tag(32)
+    // packet v2: u8 ver(2) | ... | client_ip(u32) | u16 sealed_len | sealed | tag(32)
     let ct_len = ct_bytes.len();
     if ct_len > u16::MAX as usize {
         return Err(anyhow!("ct too large"));
     }
-    let mut pkt = Vec::with_capacity(1 + 2 + ct_len + 16 + 8 + 4 + 32);
-    pkt.push(1u8);
+    let sealed_len = sealed.as_ref().map(|s| 2 + s.len()).unwrap_or(0);
+    let mut pkt = Vec::with_capacity(1 + 2 + ct_len + 16 + 8 + 4 + sealed_len + 32);
+    pkt.push(ver);
     pkt.extend_from_slice(&(ct_len as u16).to_be_bytes());
     pkt.extend_from_slice(ct_bytes);
-    pkt.extend_from_slice(&nonce);
+    pkt.extend_from_slice(nonce);
     pkt.extend_from_slice(&ts.to_be_bytes());
     pkt.extend_from_slice(&client_ip_u32.to_be_bytes());
-    pkt.extend_from_slice(&tag);
-
-    sock.send(&pkt)?;
-    sock.set_read_timeout(Some(Duration::from_millis(1000)))?;
-    let mut buf = [0u8; 16];
-    match sock.recv(&mut buf) {
-        Ok(n) if n >= 2 && &buf[..2] == b"OK" => {
-            println!("OK.");
-        }
-        _ => {
-            println!("Knock sent. If valid, port should open shortly.");
-        }
+    if let Some(sealed) = &sealed {
+        pkt.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+        pkt.extend_from_slice(sealed);
     }
-    Ok(())
+    pkt.extend_from_slice(&tag);
+    Ok(Knock {
+        pkt,
+        ack_key: reply::ack_key(key, psk)?,
+        ticket_secret: Some(ticket::ticket_secret(key, psk)?),
+    })
 }
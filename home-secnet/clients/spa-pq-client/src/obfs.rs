@@ -0,0 +1,210 @@
+// Obfuscated knock encoding; mirrors router/spa-pq/src/obfs.rs.
+//
+// Frame: salt(16) | E(knock_len u16 | check[6]) | E(knock) | random padding,
+// optionally behind a QUIC v1 Initial long header.
+
+use anyhow::{anyhow, Result};
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use hmac::Mac;
+use sha2::{Digest, Sha256};
+
+use crate::HmacSha256;
+
+const SALT_LEN: usize = 16;
+const CHECK_LEN: usize = 6;
+const MASK_DOMAIN: &[u8] = b"open-winder spa-pq obfs v1";
+const QUIC_VERSION_1: [u8; 4] = [0, 0, 0, 1];
+const QUIC_DCID_LEN: usize = 8;
+const QUIC_HDR_LEN: usize = 1 + 4 + 1 + QUIC_DCID_LEN + 1 + 1 + 2;
+// QUIC requires client Initial datagrams of at least 1200 bytes
+const QUIC_MIN_DGRAM: usize = 1200;
+// IPv4 and UDP headers
+const UDP_IP_OVERHEAD: usize = 28;
+// The daemon reads knocks into a 4096-byte buffer; longer ones are truncated
+const DAEMON_MAX_DGRAM: usize = 4096;
+
+/// Largest UDP payload that leaves in one packet on a path with `mtu`.
+pub fn max_dgram(mtu: usize) -> usize {
+    mtu.saturating_sub(UDP_IP_OVERHEAD)
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Raw knock (v1/v2)
+    Plain,
+    /// Masked and padded frame
+    Obfs,
+    /// Masked frame behind a QUIC Initial long header
+    Quic,
+}
+
+fn random_bytes(buf: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(buf).map_err(|e| anyhow!(e))
+}
+
+fn random_in(min: usize, max: usize) -> Result<usize> {
+    if max <= min {
+        return Ok(min);
+    }
+    let mut b = [0u8; 4];
+    random_bytes(&mut b)?;
+    Ok(min + (u32::from_be_bytes(b) as usize) % (max - min + 1))
+}
+
+/// Wrap `knock` according to `encoding`; padding length is drawn uniformly
+/// from `pad_min..=pad_max` and clamped so the datagram stays within
+/// `max_dgram` (see `max_dgram`). Only padding is cut: a knock that does not
+/// fit without it still goes out, fragmented, up to what the daemon reads.
+pub fn encode(
+    knock: &[u8],
+    kem_pub: &[u8],
+    encoding: Encoding,
+    pad_min: usize,
+    pad_max: usize,
+    max_dgram: usize,
+) -> Result<Vec<u8>> {
+    let quic = encoding == Encoding::Quic;
+    let overhead = match encoding {
+        Encoding::Plain => knock.len(),
+        Encoding::Obfs => SALT_LEN + 2 + CHECK_LEN + knock.len(),
+        Encoding::Quic => QUIC_HDR_LEN + SALT_LEN + 2 + CHECK_LEN + knock.len(),
+    };
+    if overhead > DAEMON_MAX_DGRAM {
+        return Err(anyhow!(
+            "{}-byte knock is longer than the daemon reads ({} bytes)",
+            overhead,
+            DAEMON_MAX_DGRAM
+        ));
+    }
+    if encoding == Encoding::Plain {
+        return Ok(knock.to_vec());
+    }
+    let mut salt = [0u8; SALT_LEN];
+    random_bytes(&mut salt)?;
+    let masked = mask(knock, kem_pub, &salt)?;
+
+    let room = max_dgram.min(DAEMON_MAX_DGRAM).saturating_sub(overhead);
+    let mut pad_len = random_in(pad_min, pad_max)?.min(room);
+    // the QUIC minimum gives way to the path: a short Initial beats fragments
+    if quic && overhead + pad_len < QUIC_MIN_DGRAM {
+        pad_len = (QUIC_MIN_DGRAM - overhead).min(room);
+    }
+    let mut frame = masked;
+    let start = frame.len();
+    frame.resize(start + pad_len, 0);
+    random_bytes(&mut frame[start..])?;
+    if !quic {
+        return Ok(frame);
+    }
+
+    // flags: long header, Initial; low bits are header-protected on the wire
+    let mut flags = [0u8; 1];
+    random_bytes(&mut flags)?;
+    let mut out = Vec::with_capacity(QUIC_HDR_LEN + frame.len());
+    out.push(0xc0 | (flags[0] & 0x0f));
+    out.extend_from_slice(&QUIC_VERSION_1);
+    out.push(QUIC_DCID_LEN as u8);
+    let mut dcid = [0u8; QUIC_DCID_LEN];
+    random_bytes(&mut dcid)?;
+    out.extend_from_slice(&dcid);
+    out.extend_from_slice(&[0, 0]); // scid_len, token_len
+    out.extend_from_slice(&(0x4000 | frame.len() as u16).to_be_bytes());
+    out.extend_from_slice(&frame);
+    Ok(out)
+}
+
+/// `salt | E(knock_len | check) | E(knock)`: the frame without padding.
+fn mask(knock: &[u8], kem_pub: &[u8], salt: &[u8; SALT_LEN]) -> Result<Vec<u8>> {
+    let mut h = Sha256::new();
+    h.update(MASK_DOMAIN);
+    h.update(kem_pub);
+    let mask_key: [u8; 32] = h.finalize().into();
+
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(&mask_key).map_err(|_| anyhow!("mask key"))?;
+    mac.update(salt);
+    let stream_key: [u8; 32] = mac.finalize().into_bytes().into();
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(&stream_key).map_err(|_| anyhow!("mask key"))?;
+    mac.update(&(knock.len() as u16).to_be_bytes());
+    let check = mac.finalize().into_bytes();
+
+    let mut enc = Vec::with_capacity(2 + CHECK_LEN + knock.len());
+    enc.extend_from_slice(&(knock.len() as u16).to_be_bytes());
+    enc.extend_from_slice(&check[..CHECK_LEN]);
+    enc.extend_from_slice(knock);
+    ChaCha20::new(&stream_key.into(), &[0u8; 12].into()).apply_keystream(&mut enc);
+
+    let mut frame = Vec::with_capacity(SALT_LEN + enc.len());
+    frame.extend_from_slice(salt);
+    frame.extend_from_slice(&enc);
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEM_PUB: &[u8] = b"open-winder test kem_pub";
+    const MAX: usize = 1472;
+
+    fn hex(b: &[u8]) -> String {
+        b.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    #[test]
+    fn masked_frame_matches_the_daemon_vector() {
+        // produced by obfs::tests::masks_the_client_vector in router/spa-pq,
+        // whose decode returns b"\x02knock" for it
+        let frame = mask(b"\x02knock", KEM_PUB, &[0x11; SALT_LEN]).unwrap();
+        assert_eq!(
+            hex(&frame),
+            "111111111111111111111111111111110ee1c6e5711a59834070a4ab511d"
+        );
+    }
+
+    #[test]
+    fn padding_and_quic_framing() {
+        let knock = [2u8; 300];
+        assert_eq!(
+            encode(&knock, KEM_PUB, Encoding::Plain, 0, 64, MAX).unwrap(),
+            knock
+        );
+        let obfs = encode(&knock, KEM_PUB, Encoding::Obfs, 10, 10, MAX).unwrap();
+        assert_eq!(obfs.len(), SALT_LEN + 2 + CHECK_LEN + knock.len() + 10);
+
+        // an Initial the daemon's strip_quic accepts, padded to 1200 bytes
+        let quic = encode(&knock, KEM_PUB, Encoding::Quic, 0, 0, MAX).unwrap();
+        assert_eq!(quic.len(), QUIC_MIN_DGRAM);
+        assert_eq!(quic[0] & 0xf0, 0xc0);
+        assert_eq!(quic[1..5], QUIC_VERSION_1);
+        assert_eq!(quic[5] as usize, QUIC_DCID_LEN);
+        let len = u16::from_be_bytes([quic[16], quic[17]]);
+        assert_eq!(len & 0xc000, 0x4000);
+        assert_eq!((len & 0x3fff) as usize, quic.len() - QUIC_HDR_LEN);
+    }
+
+    #[test]
+    fn largest_v2_knock_fits_one_datagram() {
+        // v2 with the largest payload: ver, ct_len, ct, nonce, ts, ip,
+        // sealed_len, sealed (256 + tag), tag
+        let knock = vec![2u8; 1 + 2 + 1088 + 16 + 8 + 4 + 2 + 256 + 16 + 32];
+        assert_eq!(max_dgram(1500), MAX);
+        for encoding in [Encoding::Obfs, Encoding::Quic] {
+            for _ in 0..32 {
+                let dgram = encode(&knock, KEM_PUB, encoding, 0, 1500, MAX).unwrap();
+                assert!(dgram.len() <= MAX, "{:?}: {}", encoding, dgram.len());
+            }
+        }
+        // a smaller path loses the padding first; QUIC's 1200 gives way too
+        let pppoe = max_dgram(1492);
+        let obfs = encode(&knock, KEM_PUB, Encoding::Obfs, 64, 64, pppoe).unwrap();
+        assert_eq!(obfs.len(), pppoe);
+        let short = [2u8; 300];
+        let quic = encode(&short, KEM_PUB, Encoding::Quic, 0, 0, 1000).unwrap();
+        assert_eq!(quic.len(), 1000);
+        // nothing the daemon would truncate goes out
+        assert!(encode(&[2u8; 4096], KEM_PUB, Encoding::Obfs, 0, 0, MAX).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+// Encrypted knock payload (packet v2); mirrors router/spa-pq/src/payload.rs.
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use zeroize::Zeroizing;
+
+const HKDF_INFO: &[u8] = b"open-winder spa-pq v2 payload";
+const AEAD_NONCE_LEN: usize = 12;
+/// Must match the daemon's limit (keeps a v2 knock below a 1500-byte MTU).
+const MAX_PLAIN_LEN: usize = 256;
+
+#[derive(Debug, Default, Serialize)]
+pub struct KnockPayload {
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<String>,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub duration_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wg_src_port: Option<u16>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+/// Seal `payload` under a key derived from the KEM shared secret (PSK as HKDF salt).
+pub fn seal(
+    shared: &[u8],
+    psk: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    payload: &KnockPayload,
+) -> Result<Vec<u8>> {
+    let plain = serde_json::to_vec(payload)?;
+    if plain.len() > MAX_PLAIN_LEN {
+        return Err(anyhow!(
+            "payload too large ({} > {} bytes)",
+            plain.len(),
+            MAX_PLAIN_LEN
+        ));
+    }
+    let hk = Hkdf::<Sha256>::new(Some(psk), shared);
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(HKDF_INFO, okm.as_mut())
+        .map_err(|_| anyhow!("hkdf expand"))?;
+    ChaCha20Poly1305::new(Key::from_slice(okm.as_ref()))
+        .encrypt(
+            Nonce::from_slice(&nonce[..AEAD_NONCE_LEN]),
+            Payload { msg: &plain, aad },
+        )
+        .map_err(|_| anyhow!("payload seal"))
+}
@@ -0,0 +1,132 @@
+// Knock acknowledgement handling; mirrors router/spa-pq/src/reply.rs.
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::secret::Secret;
+
+const HKDF_INFO: &[u8] = b"open-winder spa-pq ack";
+const ACK_NONCE_LEN: usize = 12;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyMode {
+    /// Expect plaintext "OK"
+    Ok,
+    /// Expect an encrypted acknowledgement
+    Encrypted,
+    /// Expect nothing; confirm via a WireGuard handshake instead
+    Silent,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Ack {
+    pub grant_secs: u64,
+    /// Base64 session ticket id for compact knocks
+    #[serde(default)]
+    pub ticket: Option<String>,
+    #[serde(default)]
+    pub ticket_secs: Option<u64>,
+}
+
+pub fn ack_key(shared: &[u8], psk: &[u8]) -> Result<Secret<[u8; 32]>> {
+    let hk = Hkdf::<Sha256>::new(Some(psk), shared);
+    let mut okm = Secret::new([0u8; 32]);
+    hk.expand(HKDF_INFO, okm.expose_mut())
+        .map_err(|_| anyhow!("hkdf expand"))?;
+    Ok(okm)
+}
+
+/// Authenticate and decode an encrypted acknowledgement.
+pub fn open_ack(key: &[u8; 32], data: &[u8]) -> Option<Ack> {
+    if data.len() <= ACK_NONCE_LEN {
+        return None;
+    }
+    let (nonce, ct) = data.split_at(ACK_NONCE_LEN);
+    let plain = ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), ct)
+        .ok()?;
+    serde_json::from_slice(&plain).ok()
+}
+
+/// Wait until `wg show <iface> dump` reports a handshake at or after `since`
+/// with a peer whose endpoint port is `wg_port`.
+pub fn probe_wg_handshake(
+    iface: &str,
+    wg_port: u16,
+    since: i64,
+    timeout: Duration,
+) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let out = Command::new("wg")
+            .args(["show", iface, "dump"])
+            .output()
+            .map_err(|e| anyhow!("run wg show {}: {}", iface, e))?;
+        if !out.status.success() {
+            return Err(anyhow!("wg show {} dump failed", iface));
+        }
+        let text = String::from_utf8_lossy(&out.stdout);
+        // first line describes the interface; peers follow:
+        // pubkey psk endpoint allowed-ips latest-handshake rx tx keepalive
+        let fresh = text.lines().skip(1).any(|line| {
+            let f: Vec<&str> = line.split('\t').collect();
+            let port_ok = f
+                .get(2)
+                .and_then(|ep| ep.rsplit_once(':'))
+                .and_then(|(_, p)| p.parse::<u16>().ok())
+                == Some(wg_port);
+            let hs = f.get(4).and_then(|h| h.parse::<i64>().ok()).unwrap_or(0);
+            port_ok && hs >= since
+        });
+        if fresh {
+            return Ok(true);
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn opens_an_ack_sealed_by_the_daemon() {
+        // produced by reply::tests::seals_the_client_vector in router/spa-pq,
+        // under the ack key for shared secret [1; 32] and PSK [2; 32]
+        let key = ack_key(&[1u8; 32], &[2u8; 32]).unwrap();
+        let sealed = unhex(
+            "03631864930e7840673c17bbca9c54cc945e7d32f997d193743942313736a0d6a7c5d1d78f062aacce\
+             7111c7e713b88df69cf3fa1e73f9458af23fa8abf80181217abbfbdb827b150917b983a39b21c2b023\
+             31b7fc1b5ed5fe57b69fbac4d1682ccbcbd94e",
+        );
+        let ack = open_ack(key.expose(), &sealed).unwrap();
+        assert_eq!(ack.grant_secs, 45);
+        assert_eq!(ack.ticket.as_deref(), Some("BQUFBQUFBQUFBQUFBQUFBQ=="));
+        assert_eq!(ack.ticket_secs, Some(86400));
+
+        // another knock's key, a flipped bit, the plaintext "OK"
+        let other = ack_key(&[1u8; 32], &[3u8; 32]).unwrap();
+        assert!(open_ack(other.expose(), &sealed).is_none());
+        let mut flipped = sealed.clone();
+        flipped[20] ^= 1;
+        assert!(open_ack(key.expose(), &flipped).is_none());
+        assert!(open_ack(key.expose(), b"OK").is_none());
+    }
+}
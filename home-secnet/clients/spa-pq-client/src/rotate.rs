@@ -0,0 +1,59 @@
+// Rotating knock port; mirrors router/spa-pq/src/listen.rs.
+
+use anyhow::{anyhow, Result};
+use hmac::Mac;
+use sha2::{Digest, Sha256};
+
+use crate::HmacSha256;
+
+const PORT_DOMAIN: &[u8] = b"open-winder spa-pq port v2";
+
+/// Parse `MIN-MAX` (inclusive).
+pub fn parse_port_range(s: &str) -> Result<(u16, u16)> {
+    let (a, b) = s
+        .split_once('-')
+        .ok_or_else(|| anyhow!("expected MIN-MAX, got {}", s))?;
+    let (min, max): (u16, u16) = (a.trim().parse()?, b.trim().parse()?);
+    if min == 0 || min > max {
+        return Err(anyhow!("invalid port range {}", s));
+    }
+    Ok((min, max))
+}
+
+/// Knock port for the time slot containing `now_unix`, under the router's
+/// port secret (`port_secret_b64`).
+pub fn current_port(port_secret: &[u8], range: (u16, u16), slot_secs: u64, now_unix: i64) -> u16 {
+    let mut h = Sha256::new();
+    h.update(PORT_DOMAIN);
+    h.update(port_secret);
+    let key: [u8; 32] = h.finalize().into();
+    let slot = now_unix.max(0) as u64 / slot_secs.max(1);
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&key).expect("hmac key");
+    mac.update(&slot.to_be_bytes());
+    let d = mac.finalize().into_bytes();
+    let span = (range.1 - range.0) as u32 + 1;
+    range.0 + (u32::from_be_bytes([d[0], d[1], d[2], d[3]]) % span) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_matches_the_daemon_schedule() {
+        // produced by listen::tests::rotates_the_client_vector in
+        // router/spa-pq: port_for_slot(1_700_000_000 / 30) under this secret
+        let secret = [9u8; 32];
+        let port = current_port(&secret, (40000, 40999), 30, 1_700_000_000);
+        assert_eq!(port, 40942);
+        // the same for the whole slot, within the range
+        assert_eq!(
+            current_port(&secret, (40000, 40999), 30, 1_700_000_009),
+            port
+        );
+        assert_eq!(current_port(&secret, (40000, 40000), 30, 0), 40000);
+        assert!(parse_port_range("40000-40999").is_ok());
+        assert!(parse_port_range("0-10").is_err());
+        assert!(parse_port_range("50-10").is_err());
+    }
+}
@@ -0,0 +1,43 @@
+// Wiped, redacted key material; mirrors the Secret type in
+// router/spa-pq/src/secrets.rs.
+
+use std::fmt;
+use zeroize::Zeroize;
+
+/// Zeroed on drop; `{:?}` never shows the bytes.
+pub struct Secret<T: Zeroize>(T);
+
+pub type SecretBytes = Secret<Vec<u8>>;
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+/// Keep the PSK and session keys out of core dumps.
+pub fn harden() -> nix::Result<()> {
+    use nix::sys::resource::{setrlimit, Resource};
+    nix::sys::prctl::set_dumpable(false)?;
+    setrlimit(Resource::RLIMIT_CORE, 0, 0)
+}
@@ -0,0 +1,114 @@
+// Session tickets for compact knocks; mirrors router/spa-pq/src/ticket.rs.
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hkdf::Hkdf;
+use hmac::Mac;
+use sha2::Sha256;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::secret::Secret;
+use crate::HmacSha256;
+
+pub const PROTO_VER_COMPACT: u8 = 3;
+const HKDF_INFO: &[u8] = b"open-winder spa-pq ticket";
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SavedTicket {
+    pub id_b64: String,
+    pub secret_b64: String,
+    pub expires_unix: i64,
+}
+
+pub fn ticket_secret(shared: &[u8], psk: &[u8]) -> Result<Secret<[u8; 32]>> {
+    let hk = Hkdf::<Sha256>::new(Some(psk), shared);
+    let mut okm = Secret::new([0u8; 32]);
+    hk.expand(HKDF_INFO, okm.expose_mut())
+        .map_err(|_| anyhow!("hkdf expand"))?;
+    Ok(okm)
+}
+
+/// Load a saved ticket that is still valid at `now`, if any.
+pub fn load(path: &Path, now: i64) -> Option<SavedTicket> {
+    let data = fs::read_to_string(path).ok()?;
+    let t: SavedTicket = serde_json::from_str(&data).ok()?;
+    (t.expires_unix > now).then_some(t)
+}
+
+pub fn save(path: &Path, t: &SavedTicket) -> Result<()> {
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("write {}", path.display()))?;
+    f.write_all(serde_json::to_string(t)?.as_bytes())?;
+    Ok(())
+}
+
+/// Build a compact knock: ver(3) | ticket_id[16] | nonce[16] | ts | tag[32].
+/// Returns the packet and the 32-byte ticket secret.
+pub fn compact_knock(
+    t: &SavedTicket,
+    nonce: &[u8; 16],
+    ts: i64,
+) -> Result<(Vec<u8>, Secret<[u8; 32]>)> {
+    let id = STANDARD.decode(t.id_b64.trim())?;
+    let decoded = Zeroizing::new(STANDARD.decode(t.secret_b64.trim())?);
+    if decoded.len() != 32 {
+        return Err(anyhow!("ticket secret must be 32 bytes"));
+    }
+    let mut secret = Secret::new([0u8; 32]);
+    secret.expose_mut().copy_from_slice(&decoded);
+    if id.len() != 16 {
+        return Err(anyhow!("ticket id must be 16 bytes"));
+    }
+    let mut mac = HmacSha256::new_from_slice(secret.expose()).map_err(|_| anyhow!("hmac key"))?;
+    mac.update(&[PROTO_VER_COMPACT]);
+    mac.update(&id);
+    mac.update(nonce);
+    mac.update(&ts.to_be_bytes());
+    let mut pkt = Vec::with_capacity(1 + 16 + 16 + 8 + 32);
+    pkt.push(PROTO_VER_COMPACT);
+    pkt.extend_from_slice(&id);
+    pkt.extend_from_slice(nonce);
+    pkt.extend_from_slice(&ts.to_be_bytes());
+    pkt.extend_from_slice(&mac.finalize().into_bytes());
+    Ok((pkt, secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_knock_matches_the_daemon_vector() {
+        // produced by ticket::tests::builds_the_client_vector in router/spa-pq,
+        // which also checks parse_compact and verify accept it
+        let t = SavedTicket {
+            id_b64: STANDARD.encode([5u8; 16]),
+            secret_b64: STANDARD.encode([6u8; 32]),
+            expires_unix: 0,
+        };
+        let (pkt, secret) = compact_knock(&t, &[7u8; 16], 1_700_000_000).unwrap();
+        let hex: String = pkt.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "03050505050505050505050505050505050707070707070707070707070707070700000000\
+             6553f100f90777240c1cc6837a08fdd002b65c08271f74c320810c26bc4a28ba47321c6a"
+        );
+        assert_eq!(secret.expose(), &[6u8; 32]);
+
+        let short = SavedTicket {
+            secret_b64: STANDARD.encode([6u8; 31]),
+            ..t
+        };
+        assert!(compact_knock(&short, &[7u8; 16], 0).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+// Passive capture: read knocks off the wire instead of from a bound socket.
+//
+// With `--capture-iface` the daemon opens an AF_PACKET socket on that
+// interface (CAP_NET_RAW) and picks out IPv4/UDP packets addressed to the knock
+// port. Nothing is bound, so the port never shows in `ss -ulnp`, and it stays
+// dropped by the firewall: the kernel answers nothing whether or not the
+// daemon runs. Packet sockets see traffic before the input chain, so the nft
+// rules need no knock-port accept. Replies are never sent in this mode.
+//
+// A cBPF filter on the socket passes only unfragmented IPv4/UDP addressed to
+// this host and the knock port (the whole range with rotation), so the rest
+// of the link's traffic is never copied to the daemon. Userspace repeats every
+// check: frames queued before the filter went in are unfiltered.
+
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::libc::{self, sock_filter};
+use nix::net::if_::if_nametoindex;
+use nix::sys::socket::{
+    recvfrom, setsockopt, socket, sockopt, AddressFamily, LinkAddr, SockFlag, SockProtocol,
+    SockType,
+};
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+
+use crate::listen::PortRotation;
+
+const ETH_P_IP: u16 = 0x0800;
+/// sll_pkttype for frames addressed to this host
+const PACKET_HOST: u8 = 0;
+const IPPROTO_UDP: u8 = 17;
+/// Frames read per `poll`, wanted or not
+const POLL_BUDGET: usize = 64;
+
+pub struct Capture {
+    fd: OwnedFd,
+    ifindex: usize,
+    listen: SocketAddr,
+    rotation: Option<PortRotation>,
+}
+
+/// A UDP datagram seen on the wire.
+pub struct Datagram<'a> {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub payload: &'a [u8],
+}
+
+impl Capture {
+    /// Watch `iface` for knocks to `listen` (an unspecified IP matches any
+    /// destination); with `rotation` the active rotating ports replace its port.
+    pub fn open(iface: &str, listen: SocketAddr, rotation: Option<PortRotation>) -> Result<Self> {
+        let ifindex =
+            if_nametoindex(iface).with_context(|| format!("no such interface {}", iface))? as usize;
+        let fd = socket(
+            AddressFamily::Packet,
+            SockType::Datagram,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            SockProtocol::EthAll,
+        )
+        .context("open AF_PACKET socket (needs CAP_NET_RAW)")?;
+        setsockopt(&fd, sockopt::BindToDevice, &OsString::from(iface))
+            .with_context(|| format!("bind capture to {}", iface))?;
+        let dst_ip = match listen.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+            _ => None,
+        };
+        let ports = match &rotation {
+            Some(rot) => rot.range(),
+            None => (listen.port(), listen.port()),
+        };
+        let sock = socket2::Socket::from(fd);
+        sock.attach_filter(&knock_filter(dst_ip, ports))
+            .context("attach capture filter")?;
+        let fd = OwnedFd::from(sock);
+        Ok(Self {
+            fd,
+            ifindex,
+            listen,
+            rotation,
+        })
+    }
+
+    /// Next knock seen on the wire, with its sender. Reads at most
+    /// POLL_BUDGET frames per call, skipped ones included, so a busy link
+    /// cannot starve other sources.
+    pub fn poll(&mut self, buf: &mut [u8], now_unix: i64) -> Result<Option<(Vec<u8>, SocketAddr)>> {
+        for _ in 0..POLL_BUDGET {
+            let Some((n, from)) = self.recv(buf)? else {
+                break;
+            };
+            if !self.ours(from) {
+                continue;
+            }
+            let Some(d) = parse_udp(&buf[..n]) else {
+                continue;
+            };
+            if self.wanted(d.dst, now_unix) {
+                return Ok(Some((d.payload.to_vec(), SocketAddr::V4(d.src))));
+            }
+        }
+        Ok(None)
+    }
+
+    fn wanted(&self, dst: SocketAddrV4, now_unix: i64) -> bool {
+        let ip_ok = self.listen.ip().is_unspecified() || self.listen.ip() == IpAddr::V4(*dst.ip());
+        let port_ok = match &self.rotation {
+            Some(rot) => rot.active_ports(now_unix).contains(&dst.port()),
+            None => self.listen.port() == dst.port(),
+        };
+        ip_ok && port_ok
+    }
+
+    /// Whether a frame is inbound IPv4 on our interface. Frames queued
+    /// before SO_BINDTODEVICE and the filter took effect may be anything.
+    fn ours(&self, from: Option<LinkAddr>) -> bool {
+        from.is_some_and(|l| {
+            l.ifindex() == self.ifindex
+                && l.pkttype() == PACKET_HOST
+                && l.protocol() == ETH_P_IP.to_be()
+        })
+    }
+
+    /// Next frame on the socket, if one is pending.
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Option<(usize, Option<LinkAddr>)>> {
+        loop {
+            match recvfrom::<LinkAddr>(self.fd.as_raw_fd(), buf) {
+                Ok(v) => return Ok(Some(v)),
+                Err(Errno::EAGAIN) => return Ok(None),
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(anyhow!("capture socket error: {}", e)),
+            }
+        }
+    }
+}
+
+impl AsFd for Capture {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// cBPF program for the capture socket: inbound, IPv4, UDP, not a fragment,
+/// destination port within `ports` and, if given, destination `ip`. On a
+/// SOCK_DGRAM packet socket offsets start at the IP header.
+fn knock_filter(ip: Option<Ipv4Addr>, ports: (u16, u16)) -> Vec<sock_filter> {
+    // (code, k, Some(v)): a jump to the final `ret 0` when the test is v
+    let ld = |size: u32, k: u32| ((libc::BPF_LD | size | libc::BPF_ABS) as u16, k, None);
+    let reject_unless =
+        |op: u32, k: u32| ((libc::BPF_JMP | op | libc::BPF_K) as u16, k, Some(false));
+    let reject_if = |op: u32, k: u32| ((libc::BPF_JMP | op | libc::BPF_K) as u16, k, Some(true));
+    let ancillary = |off: libc::c_int| (libc::SKF_AD_OFF + off) as u32;
+    let mut prog = vec![
+        ld(libc::BPF_W, ancillary(libc::SKF_AD_PKTTYPE)),
+        reject_unless(libc::BPF_JEQ, PACKET_HOST as u32),
+        ld(libc::BPF_W, ancillary(libc::SKF_AD_PROTOCOL)),
+        reject_unless(libc::BPF_JEQ, ETH_P_IP as u32),
+        ld(libc::BPF_B, 9),
+        reject_unless(libc::BPF_JEQ, IPPROTO_UDP as u32),
+        // more-fragments flag or a fragment offset
+        ld(libc::BPF_H, 6),
+        reject_if(libc::BPF_JSET, 0x3fff),
+    ];
+    if let Some(ip) = ip {
+        prog.push(ld(libc::BPF_W, 16));
+        prog.push(reject_unless(libc::BPF_JEQ, u32::from(ip)));
+    }
+    prog.extend([
+        // x = IP header length, then the UDP destination port
+        (
+            (libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH) as u16,
+            0,
+            None,
+        ),
+        ((libc::BPF_LD | libc::BPF_H | libc::BPF_IND) as u16, 2, None),
+        reject_unless(libc::BPF_JGE, ports.0 as u32),
+        reject_if(libc::BPF_JGT, ports.1 as u32),
+        ((libc::BPF_RET | libc::BPF_K) as u16, 0xffff, None),
+        ((libc::BPF_RET | libc::BPF_K) as u16, 0, None),
+    ]);
+    let reject = prog.len() - 1;
+    prog.iter()
+        .enumerate()
+        .map(|(i, &(code, k, jump))| {
+            // jumps are relative to the next instruction
+            let (jt, jf) = match jump {
+                Some(true) => ((reject - i - 1) as u8, 0),
+                Some(false) => (0, (reject - i - 1) as u8),
+                None => (0, 0),
+            };
+            sock_filter { code, jt, jf, k }
+        })
+        .collect()
+}
+
+/// Parse an unfragmented IPv4/UDP packet.
+pub fn parse_udp(pkt: &[u8]) -> Option<Datagram<'_>> {
+    let ihl = (*pkt.first()? & 0x0f) as usize * 4;
+    if pkt[0] >> 4 != 4 || ihl < 20 || pkt.len() < ihl + 8 {
+        return None;
+    }
+    let total = u16::from_be_bytes([pkt[2], pkt[3]]) as usize;
+    // more-fragments flag or a fragment offset: knocks fit in one packet
+    let frag = u16::from_be_bytes([pkt[6], pkt[7]]) & 0x3fff;
+    if pkt[9] != IPPROTO_UDP || frag != 0 || total > pkt.len() || total < ihl + 8 {
+        return None;
+    }
+    let src_ip = Ipv4Addr::new(pkt[12], pkt[13], pkt[14], pkt[15]);
+    let dst_ip = Ipv4Addr::new(pkt[16], pkt[17], pkt[18], pkt[19]);
+    let udp = &pkt[ihl..total];
+    let udp_len = u16::from_be_bytes([udp[4], udp[5]]) as usize;
+    if udp_len < 8 || udp_len > udp.len() {
+        return None;
+    }
+    Some(Datagram {
+        src: SocketAddrV4::new(src_ip, u16::from_be_bytes([udp[0], udp[1]])),
+        dst: SocketAddrV4::new(dst_ip, u16::from_be_bytes([udp[2], udp[3]])),
+        payload: &udp[8..udp_len],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(payload: &[u8], flags_frag: u16) -> Vec<u8> {
+        let total = (20 + 8 + payload.len()) as u16;
+        let mut p = vec![0x45, 0];
+        p.extend_from_slice(&total.to_be_bytes());
+        p.extend_from_slice(&[0, 0]);
+        p.extend_from_slice(&flags_frag.to_be_bytes());
+        p.extend_from_slice(&[64, IPPROTO_UDP, 0, 0, 198, 51, 100, 7, 192, 0, 2, 1]);
+        p.extend_from_slice(&40000u16.to_be_bytes());
+        p.extend_from_slice(&62201u16.to_be_bytes());
+        p.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        p.extend_from_slice(&[0, 0]);
+        p.extend_from_slice(payload);
+        p
+    }
+
+    #[test]
+    fn udp_is_parsed_and_fragments_skipped() {
+        let mut p = packet(b"knock", 0x4000); // DF only
+        p.extend_from_slice(&[0; 6]); // Ethernet padding past total length
+        let d = parse_udp(&p).unwrap();
+        assert_eq!(d.src, "198.51.100.7:40000".parse().unwrap());
+        assert_eq!(d.dst, "192.0.2.1:62201".parse().unwrap());
+        assert_eq!(d.payload, b"knock");
+        assert!(parse_udp(&packet(b"knock", 0x2000)).is_none());
+        assert!(parse_udp(&packet(b"knock", 0x0001)).is_none());
+        assert!(parse_udp(&p[..24]).is_none());
+    }
+
+    #[test]
+    fn filter_keeps_other_traffic_out_of_the_budget() {
+        let knock_port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let noise_port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listen = knock_port.local_addr().unwrap();
+        let mut c = match Capture::open("lo", listen, None) {
+            Ok(c) => c,
+            Err(e) if e.root_cause().downcast_ref() == Some(&Errno::EPERM) => {
+                eprintln!("skipped: needs CAP_NET_RAW");
+                return;
+            }
+            Err(e) => panic!("{:#}", e),
+        };
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for _ in 0..4 * POLL_BUDGET {
+            client
+                .send_to(b"noise", noise_port.local_addr().unwrap())
+                .unwrap();
+        }
+        client.send_to(b"knock", listen).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut buf = [0u8; 2048];
+        let (payload, from) = c.poll(&mut buf, 0).unwrap().expect("knock past the noise");
+        assert_eq!(payload, b"knock");
+        assert_eq!(from, client.local_addr().unwrap());
+        assert!(c.poll(&mut buf, 0).unwrap().is_none());
+    }
+}
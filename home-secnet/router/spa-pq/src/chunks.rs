@@ -0,0 +1,116 @@
+// Reassembly of knock frames split across several packets (DNS, ICMP).
+//
+// Every chunk names a random session id, its index and the chunk count. State
+// is bounded: at most MAX_SESSIONS partial frames, each dropped SESSION_TTL
+// after its first chunk. A new session arriving at a full table evicts the
+// oldest, so a flood of first chunks cannot lock out real knocks; a knock's
+// chunks arrive within moments and outlive the flood's. The transports bound
+// chunk sizes and counts.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const SESSION_LEN: usize = 8;
+const MAX_SESSIONS: usize = 256;
+const SESSION_TTL: Duration = Duration::from_secs(10);
+
+pub struct Chunk {
+    pub session: [u8; SESSION_LEN],
+    pub idx: usize,
+    pub total: usize,
+    pub data: Vec<u8>,
+}
+
+struct Partial {
+    started: Instant,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+/// Bounded reassembly of chunked frames, keyed by session id.
+pub struct Reassembly {
+    sessions: HashMap<[u8; SESSION_LEN], Partial>,
+}
+
+impl Reassembly {
+    pub fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Add a chunk (`idx < total` is the caller's job); returns the frame
+    /// once every chunk of its session is in.
+    pub fn add(&mut self, chunk: Chunk, now: Instant) -> Option<Vec<u8>> {
+        self.sessions
+            .retain(|_, p| now.duration_since(p.started) < SESSION_TTL);
+        if !self.sessions.contains_key(&chunk.session) && self.sessions.len() >= MAX_SESSIONS {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, p)| p.started)
+                .map(|(id, _)| *id)?;
+            self.sessions.remove(&oldest);
+        }
+        let p = self
+            .sessions
+            .entry(chunk.session)
+            .or_insert_with(|| Partial {
+                started: now,
+                chunks: vec![None; chunk.total],
+            });
+        if p.chunks.len() != chunk.total {
+            return None;
+        }
+        p.chunks[chunk.idx] = Some(chunk.data);
+        if p.chunks.iter().any(|c| c.is_none()) {
+            return None;
+        }
+        let p = self.sessions.remove(&chunk.session)?;
+        Some(p.chunks.into_iter().flatten().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(session: u8, idx: usize, total: usize) -> Chunk {
+        Chunk {
+            session: [session; SESSION_LEN],
+            idx,
+            total,
+            data: vec![idx as u8],
+        }
+    }
+
+    #[test]
+    fn sessions_expire_and_are_capped() {
+        let now = Instant::now();
+        let mut r = Reassembly::new();
+        assert!(r.add(chunk(1, 0, 2), now).is_none());
+        // a chunk count that disagrees with the session is ignored
+        assert!(r.add(chunk(1, 1, 3), now).is_none());
+        // too late: the session expired and this starts a new one
+        assert!(r.add(chunk(1, 1, 2), now + SESSION_TTL).is_none());
+        assert_eq!(
+            r.add(chunk(1, 0, 2), now + SESSION_TTL).unwrap(),
+            vec![0, 1]
+        );
+
+        let flood = |r: &mut Reassembly, from: u64, n: u64, at: Instant| {
+            for s in from..from + n {
+                let mut c = chunk(0, 0, 2);
+                c.session.copy_from_slice(&s.to_be_bytes());
+                assert!(r.add(c, at).is_none());
+            }
+        };
+        flood(&mut r, 0, MAX_SESSIONS as u64, now);
+        assert_eq!(r.sessions.len(), MAX_SESSIONS);
+        // a full table makes room by dropping the oldest sessions
+        let later = now + Duration::from_secs(1);
+        assert!(r.add(chunk(0xff, 0, 2), later).is_none());
+        flood(&mut r, 1000, MAX_SESSIONS as u64 - 1, later);
+        assert_eq!(r.sessions.len(), MAX_SESSIONS);
+        assert_eq!(r.add(chunk(0xff, 1, 2), later).unwrap(), vec![0, 1]);
+    }
+}
@@ -0,0 +1,270 @@
+// Per-client grant policy (--clients)
+//
+// Which address a valid knock opens is decided by the policy of its client id
+// (v2 payload, or the ticket of a compact knock); knocks without an id, and
+// ids missing from the file, get the daemon-wide --target-policy/--delegate.
+//
+//   {
+//     "laptop": { "target": "match" },
+//     "phone":  { "target": "declared", "delegate": ["192.168.10.0/24"] }
+//   }
+//
+// The declared address is the payload `target`, which is sealed, and for
+// `match` otherwise the v2 header client_ip, which the HMAC covers. v1 knocks
+// declare nothing.
+//
+// An entry may also name its own 32-byte PSK (`psk_file`, written by
+// `provision`). Knocks made with it are only valid for that client id, and
+// knocks claiming that id must use it.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::peers::WgPeer;
+use crate::secrets::{self, SecretBytes, Unseal};
+use crate::SpaError;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetPolicy {
+    /// Grant the observed source address
+    #[default]
+    Observed,
+    /// Grant the observed source only if it equals the declared address
+    Match,
+    /// Grant the declared target if it lies in a delegate CIDR
+    Declared,
+}
+
+/// An IPv4 network in CIDR notation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    net: u32,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(ip) & mask == self.net
+    }
+
+    /// Host addresses, lowest first; without network and broadcast below /31.
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let size = 1u64 << (32 - self.prefix as u32);
+        let range = if size > 2 { 1..size - 1 } else { 0..size };
+        let net = self.net as u64;
+        range.map(move |i| Ipv4Addr::from((net + i) as u32))
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (ip, prefix) = s.split_once('/').unwrap_or((s, "32"));
+        let ip: Ipv4Addr = ip.parse().with_context(|| format!("bad CIDR {}", s))?;
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|p| *p <= 32)
+            .ok_or_else(|| anyhow!("bad CIDR prefix in {}", s))?;
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        if u32::from(ip) & !mask != 0 {
+            return Err(anyhow!("{} has host bits set", s));
+        }
+        Ok(Self {
+            net: u32::from(ip),
+            prefix,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientPolicy {
+    #[serde(default)]
+    pub target: TargetPolicy,
+    /// Networks a `declared` client may open for
+    #[serde(default)]
+    pub delegate: Vec<Cidr>,
+    /// Longest a grant is kept alive by its WireGuard session (keepalive.rs)
+    #[serde(default)]
+    pub keepalive_max_secs: Option<u64>,
+    /// WireGuard peer enabled only while granted (peers.rs)
+    #[serde(default)]
+    pub wg_peer: Option<WgPeer>,
+    /// The device's own PSK instead of the shared --psk-file (secrets.rs)
+    #[serde(default)]
+    pub psk_file: Option<PathBuf>,
+}
+
+impl ClientPolicy {
+    /// Address to grant for a knock seen from `observed`. `target` is the
+    /// declared target; `claimed` the authenticated client_ip, if any.
+    pub fn grant_addr(
+        &self,
+        observed: Ipv4Addr,
+        target: Option<Ipv4Addr>,
+        claimed: Option<Ipv4Addr>,
+    ) -> Result<Ipv4Addr, SpaError> {
+        match self.target {
+            TargetPolicy::Observed => Ok(observed),
+            TargetPolicy::Match if target.or(claimed) == Some(observed) => Ok(observed),
+            TargetPolicy::Match => Err(SpaError::TargetMismatch),
+            TargetPolicy::Declared => match target {
+                None => Ok(observed),
+                Some(t) if t == observed || self.delegate.iter().any(|c| c.contains(t)) => Ok(t),
+                Some(_) => Err(SpaError::TargetDenied),
+            },
+        }
+    }
+
+    /// Address to grant for a knock relayed by a resolver that declares
+    /// `target`. The resolver's address says nothing about the client, so
+    /// the target must lie in one of the client's delegate networks.
+    pub fn relayed_addr(&self, target: Ipv4Addr) -> Result<Ipv4Addr, SpaError> {
+        match self.target {
+            TargetPolicy::Declared if self.delegate.iter().any(|c| c.contains(target)) => {
+                Ok(target)
+            }
+            TargetPolicy::Declared => Err(SpaError::TargetDenied),
+            TargetPolicy::Observed | TargetPolicy::Match => Err(SpaError::TargetMismatch),
+        }
+    }
+}
+
+pub struct Clients {
+    default: ClientPolicy,
+    by_id: BTreeMap<String, ClientPolicy>,
+    psks: BTreeMap<String, SecretBytes>,
+}
+
+impl Clients {
+    pub fn new(default: ClientPolicy) -> Self {
+        Self {
+            default,
+            by_id: BTreeMap::new(),
+            psks: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(path: &Path, default: ClientPolicy, unseal: &Unseal) -> Result<Self> {
+        let data = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let by_id: BTreeMap<String, ClientPolicy> = serde_json::from_str(&data)
+            .with_context(|| format!("parse client policies in {}", path.display()))?;
+        let mut psks = BTreeMap::new();
+        for (id, p) in &by_id {
+            if let Some(peer) = &p.wg_peer {
+                peer.validate().with_context(|| format!("client {}", id))?;
+            }
+            if let Some(path) = &p.psk_file {
+                let psk =
+                    secrets::load(path, 32, unseal).with_context(|| format!("client {}", id))?;
+                psks.insert(id.clone(), psk);
+            }
+        }
+        Ok(Self {
+            default,
+            by_id,
+            psks,
+        })
+    }
+
+    /// Device PSKs by client id.
+    pub fn psks(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.psks
+            .iter()
+            .map(|(id, k)| (id.as_str(), k.expose().as_slice()))
+    }
+
+    pub fn has_psk(&self, client_id: &str) -> bool {
+        self.psks.contains_key(client_id)
+    }
+
+    /// Every gated WireGuard peer.
+    pub fn peers(&self) -> impl Iterator<Item = &WgPeer> {
+        self.by_id.values().filter_map(|p| p.wg_peer.as_ref())
+    }
+
+    pub fn policy(&self, client_id: Option<&str>) -> &ClientPolicy {
+        client_id
+            .and_then(|id| self.by_id.get(id))
+            .unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OBSERVED: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    #[test]
+    fn cidrs_parse_and_match() {
+        let c: Cidr = "192.168.10.0/24".parse().unwrap();
+        assert!(c.contains(Ipv4Addr::new(192, 168, 10, 42)));
+        assert!(!c.contains(Ipv4Addr::new(192, 168, 11, 1)));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(OBSERVED));
+        assert!("198.51.100.7".parse::<Cidr>().unwrap().contains(OBSERVED));
+        assert!("192.168.10.1/24".parse::<Cidr>().is_err());
+        assert!("192.168.10.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn policies_pick_the_grant_address() {
+        let clients: BTreeMap<String, ClientPolicy> = serde_json::from_str(
+            r#"{"laptop": {"target": "match"},
+                "phone": {"target": "declared", "delegate": ["192.168.10.0/24"]}}"#,
+        )
+        .unwrap();
+        let clients = Clients {
+            default: ClientPolicy::default(),
+            by_id: clients,
+            psks: BTreeMap::new(),
+        };
+        let lan = Ipv4Addr::new(192, 168, 10, 5);
+        let other = Ipv4Addr::new(203, 0, 113, 9);
+
+        let anyone = clients.policy(Some("unknown"));
+        assert_eq!(anyone.grant_addr(OBSERVED, Some(lan), None), Ok(OBSERVED));
+
+        let laptop = clients.policy(Some("laptop"));
+        assert_eq!(
+            laptop.grant_addr(OBSERVED, None, Some(OBSERVED)),
+            Ok(OBSERVED)
+        );
+        assert_eq!(
+            laptop.grant_addr(OBSERVED, Some(OBSERVED), Some(lan)),
+            Ok(OBSERVED)
+        );
+        assert_eq!(
+            laptop.grant_addr(OBSERVED, None, Some(lan)),
+            Err(SpaError::TargetMismatch)
+        );
+        assert_eq!(
+            laptop.grant_addr(OBSERVED, None, None),
+            Err(SpaError::TargetMismatch)
+        );
+
+        let phone = clients.policy(Some("phone"));
+        assert_eq!(phone.grant_addr(OBSERVED, None, None), Ok(OBSERVED));
+        assert_eq!(phone.grant_addr(OBSERVED, Some(lan), None), Ok(lan));
+        assert_eq!(
+            phone.grant_addr(OBSERVED, Some(other), None),
+            Err(SpaError::TargetDenied)
+        );
+    }
+}
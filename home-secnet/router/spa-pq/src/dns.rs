@@ -0,0 +1,339 @@
+// Knocks carried in DNS queries, for networks where only DNS gets out.
+//
+// The client splits a knock frame (any --encoding) into chunks of at most
+// CHUNK_LEN bytes and asks its resolver for one name per chunk:
+//
+//   <base32 chunk, in labels of <= 63>.<hex session[8] idx total>.<zone>
+//
+// The zone is delegated (NS) to this daemon, which answers as a minimal
+// authoritative server: every in-zone query gets NOERROR (a TXT "1" for TXT
+// queries, TTL 0) whether or not it carried a valid chunk, and anything else
+// gets REFUSED. Chunks are reassembled per session under a small memory cap
+// and the complete frame goes through the normal knock checks. Base32 and hex
+// are decoded case-insensitively because resolvers may randomise case.
+
+use anyhow::{Context, Result};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::time::Instant;
+
+use crate::chunks::{Chunk, Reassembly, SESSION_LEN};
+
+/// Knock bytes per query; with the header label this leaves 68 bytes for the zone.
+pub const CHUNK_LEN: usize = 100;
+/// Enough chunks for the largest frame (1472 bytes).
+pub const MAX_CHUNKS: usize = 15;
+
+const TYPE_TXT: u16 = 16;
+const RCODE_REFUSED: u16 = 5;
+
+pub struct Query<'a> {
+    msg: &'a [u8],
+    question_end: usize,
+    pub labels: Vec<String>,
+    pub qtype: u16,
+}
+
+/// Parse a standard query with exactly one question (no name compression).
+pub fn parse_query(msg: &[u8]) -> Option<Query<'_>> {
+    if msg.len() < 12 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([msg[2], msg[3]]);
+    // QR must be 0 (query) and OPCODE 0 (standard query)
+    if flags & 0xf800 != 0 || u16::from_be_bytes([msg[4], msg[5]]) != 1 {
+        return None;
+    }
+    let mut off = 12;
+    let mut labels = Vec::new();
+    let mut name_len = 0usize;
+    loop {
+        let len = *msg.get(off)? as usize;
+        off += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+        name_len += len + 1;
+        if name_len > 255 {
+            return None;
+        }
+        let label = msg.get(off..off + len)?;
+        labels.push(String::from_utf8(label.to_vec()).ok()?.to_ascii_lowercase());
+        off += len;
+    }
+    let qtype = u16::from_be_bytes([*msg.get(off)?, *msg.get(off + 1)?]);
+    msg.get(off + 2..off + 4)?;
+    Some(Query {
+        msg,
+        question_end: off + 4,
+        labels,
+        qtype,
+    })
+}
+
+impl Query<'_> {
+    /// Authoritative response echoing the question; a TXT "1" for TXT queries.
+    pub fn respond(&self, in_zone: bool) -> Vec<u8> {
+        let rd = u16::from_be_bytes([self.msg[2], self.msg[3]]) & 0x0100;
+        let answer = in_zone && self.qtype == TYPE_TXT;
+        let rcode = if in_zone { 0 } else { RCODE_REFUSED };
+        // QR | AA (in zone) | RD copied | RCODE
+        let flags = 0x8000 | if in_zone { 0x0400 } else { 0 } | rd | rcode;
+        let mut out = Vec::with_capacity(self.question_end + 16);
+        out.extend_from_slice(&self.msg[..2]);
+        out.extend_from_slice(&flags.to_be_bytes());
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&(answer as u16).to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        out.extend_from_slice(&self.msg[12..self.question_end]);
+        if answer {
+            // name pointer to the question, TXT IN, TTL 0, "1"
+            out.extend_from_slice(&[0xc0, 0x0c, 0, 16, 0, 1, 0, 0, 0, 0, 0, 2, 1, b'1']);
+        }
+        out
+    }
+}
+
+/// Labels of `zone`, lowercased; empty labels are dropped.
+pub fn zone_labels(zone: &str) -> Vec<String> {
+    zone.trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_ascii_lowercase())
+        .collect()
+}
+
+pub fn in_zone(labels: &[String], zone: &[String]) -> bool {
+    labels.len() >= zone.len() && labels[labels.len() - zone.len()..] == *zone
+}
+
+/// Extract a knock chunk from an in-zone name, if it carries one.
+pub fn decode_chunk(labels: &[String], zone: &[String]) -> Option<Chunk> {
+    let rest = &labels[..labels.len().checked_sub(zone.len())?];
+    let (hdr, data) = rest.split_last()?;
+    if data.is_empty() {
+        return None;
+    }
+    let hdr = hex_decode(hdr)?;
+    if hdr.len() != SESSION_LEN + 2 {
+        return None;
+    }
+    let (idx, total) = (hdr[SESSION_LEN] as usize, hdr[SESSION_LEN + 1] as usize);
+    if total == 0 || total > MAX_CHUNKS || idx >= total {
+        return None;
+    }
+    let data = base32_decode(&data.concat())?;
+    if data.is_empty() || data.len() > CHUNK_LEN {
+        return None;
+    }
+    let mut session = [0u8; SESSION_LEN];
+    session.copy_from_slice(&hdr[..SESSION_LEN]);
+    Some(Chunk {
+        session,
+        idx,
+        total,
+        data,
+    })
+}
+
+pub struct DnsKnocks {
+    sock: UdpSocket,
+    zone: Vec<String>,
+    reassembly: Reassembly,
+}
+
+impl DnsKnocks {
+    pub fn bind(addr: &str, zone: &str) -> Result<Self> {
+        let sock = UdpSocket::bind(addr).with_context(|| format!("bind dns {}", addr))?;
+        sock.set_nonblocking(true)?;
+        Ok(Self {
+            sock,
+            zone: zone_labels(zone),
+            reassembly: Reassembly::new(),
+        })
+    }
+
+    /// Answer one pending query; returns a reassembled frame and the address
+    /// of the resolver that delivered its last chunk.
+    pub fn poll(&mut self, buf: &mut [u8]) -> Result<Option<(Vec<u8>, SocketAddr)>> {
+        let (n, src) = match self.sock.recv_from(buf) {
+            Ok(v) => v,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(anyhow::anyhow!("dns socket error: {}", e)),
+        };
+        let Some(q) = parse_query(&buf[..n]) else {
+            return Ok(None);
+        };
+        let ours = in_zone(&q.labels, &self.zone);
+        let _ = self.sock.send_to(&q.respond(ours), src);
+        if !ours {
+            return Ok(None);
+        }
+        Ok(decode_chunk(&q.labels, &self.zone)
+            .and_then(|c| self.reassembly.add(c, Instant::now()))
+            .map(|frame| (frame, src)))
+    }
+}
+
+impl AsFd for DnsKnocks {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.sock.as_fd()
+    }
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// RFC 4648 base32 without padding, case-insensitive.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u32, 0u32);
+    for c in s.bytes() {
+        let v = match c.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        acc = (acc << 5) | v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base32_encode(data: &[u8]) -> String {
+        const ALPHA: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+        let mut out = String::new();
+        let (mut acc, mut bits) = (0u32, 0u32);
+        for b in data {
+            acc = (acc << 8) | *b as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(ALPHA[((acc >> bits) & 31) as usize] as char);
+            }
+            acc &= (1 << bits) - 1;
+        }
+        if bits > 0 {
+            out.push(ALPHA[((acc << (5 - bits)) & 31) as usize] as char);
+        }
+        out
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for l in name.split('.') {
+            q.push(l.len() as u8);
+            q.extend_from_slice(l.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&1u16.to_be_bytes());
+        q
+    }
+
+    fn chunk_name(session: [u8; 8], idx: u8, total: u8, data: &[u8]) -> String {
+        let b32 = base32_encode(data);
+        let labels: Vec<&str> = b32
+            .as_bytes()
+            .chunks(63)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect();
+        let mut hdr = session.to_vec();
+        hdr.extend_from_slice(&[idx, total]);
+        let hex: String = hdr.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}.{}.K.Example.net", labels.join("."), hex.to_uppercase())
+    }
+
+    #[test]
+    fn chunks_reassemble_across_queries() {
+        let zone = zone_labels("k.example.net.");
+        let frame: Vec<u8> = (0..250u16).map(|i| i as u8).collect();
+        let mut r = Reassembly::new();
+        let now = Instant::now();
+        let parts: Vec<&[u8]> = frame.chunks(CHUNK_LEN).collect();
+        let mut got = None;
+        // out of order, with a duplicate as a resolver retry would send
+        for i in [2usize, 0, 0, 1] {
+            let msg = query(&chunk_name([7; 8], i as u8, 3, parts[i]), TYPE_TXT);
+            let q = parse_query(&msg).unwrap();
+            assert!(in_zone(&q.labels, &zone));
+            let resp = q.respond(true);
+            assert_eq!(&resp[..2], &[0x12, 0x34]);
+            assert_eq!(resp[3] & 0x0f, 0);
+            assert_eq!(u16::from_be_bytes([resp[6], resp[7]]), 1);
+            got = r.add(decode_chunk(&q.labels, &zone).unwrap(), now);
+        }
+        assert_eq!(got.unwrap(), frame);
+    }
+
+    #[test]
+    fn foreign_and_malformed_names() {
+        let zone = zone_labels("k.example.net");
+        let msg = query("www.example.org", 1);
+        let q = parse_query(&msg).unwrap();
+        assert!(!in_zone(&q.labels, &zone));
+        assert_eq!(q.respond(false)[3] & 0x0f, RCODE_REFUSED as u8);
+        // QNAME-minimised and junk in-zone names carry no chunk
+        for name in ["k.example.net", "abcd.k.example.net", "zz.00.k.example.net"] {
+            let msg = query(name, 1);
+            let q = parse_query(&msg).unwrap();
+            assert!(in_zone(&q.labels, &zone));
+            assert!(decode_chunk(&q.labels, &zone).is_none());
+        }
+        assert!(parse_query(&[0u8; 5]).is_none());
+    }
+    #[test]
+    fn names_the_client_vector() {
+        // the client's dns::tests::names_match_the_daemon_vector expects
+        // exactly these names
+        let zone = zone_labels("k.example.net");
+        let frame: Vec<u8> = (0..150u8).collect();
+        let parts: Vec<&[u8]> = frame.chunks(CHUNK_LEN).collect();
+        let names: Vec<String> = parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                chunk_name([1, 2, 3, 4, 5, 6, 7, 8], i as u8, parts.len() as u8, part)
+                    .to_lowercase()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "aaaqeayeaudaocajbifqydiob4ibceqtcqkrmfyydenbwha5dypsaijcemsckjr.\
+                 hfausukzmfuxc6mbrgiztinjwg44dsor3hq6t4p2aifbegrcfizduqskkjnge2t.\
+                 spkbiveu2ukvlfowczljnvyxk6l5qgcytd.01020304050607080002.k.example.net",
+                "mrswmz3infvgw3dnnzxxa4lson2hk5txpb4xu634pv7h7aebqkbyjbmgq6eitcu.\
+                 lrsgy5d4qsgjjhfev.01020304050607080102.k.example.net",
+            ]
+        );
+        let mut r = Reassembly::new();
+        let now = Instant::now();
+        let mut got = None;
+        for name in &names {
+            let q = query(name, TYPE_TXT);
+            let q = parse_query(&q).unwrap();
+            got = r.add(decode_chunk(&q.labels, &zone).unwrap(), now);
+        }
+        assert_eq!(got.unwrap(), frame);
+    }
+}
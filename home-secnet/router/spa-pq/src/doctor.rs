@@ -0,0 +1,680 @@
+// `doctor`: check a deployment against the flags the daemon runs with.
+//
+// Takes the `run` flags that name things outside the daemon (nft objects,
+// key files, ports) and looks at the live system:
+// - nftables (`nft -j list ruleset`, read as in nftables.rs): where grants
+//   go, found as `run` finds it; that the sets exist with the types and
+//   `flags timeout` the daemon's elements need; and that a chain hooked on
+//   input reaches a rule that accepts WG_PORT only from the allow set. A rule
+//   accepting WG_PORT from anyone is a failure.
+// - the KEM private key, PSK and, with --rotate-ports, the port secret: size
+//   (via the loader, so base64, hex and sealed files count), mode and owner,
+//   and that --kem-pub matches.
+// - the clock: knocks outside --window-secs are stale.
+// - the --listen ports: free, or held by a running daemon.
+// Each finding prints with a fix; any failure makes the exit status nonzero.
+
+use anyhow::{anyhow, Result};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::nftables::{self, Port, Ruleset, Want, IPV4, PAIR, SERVICE};
+use crate::secrets::{self, SecretError, Unseal};
+use crate::{kem_pub_of_secret, listen, PortGrants, SecretKind};
+
+#[derive(clap::Args, Debug)]
+pub struct DoctorArgs {
+    /// Listen address, e.g. 0.0.0.0:62201 (repeatable)
+    #[arg(long, default_value = "0.0.0.0:62201")]
+    listen: Vec<String>,
+    /// WireGuard UDP port the daemon opens
+    #[arg(long)]
+    wg_port: u16,
+    /// KEM private key path (as for `run`)
+    #[arg(long, default_value = "/etc/spa/kem_priv.bin")]
+    kem_priv: PathBuf,
+    /// KEM public key handed to clients; must belong to --kem-priv
+    #[arg(long, default_value = "/etc/spa/kem_pub.bin")]
+    kem_pub: PathBuf,
+    /// Path to 32-byte PSK file (as for `run`)
+    #[arg(long, default_value = "/etc/spa/psk.bin")]
+    psk_file: PathBuf,
+    /// Passphrase for secrets sealed with `seal-key --passphrase-file`
+    #[arg(long)]
+    unseal_passphrase_file: Option<PathBuf>,
+    /// Key file for secrets sealed with `seal-key --key-file`
+    #[arg(long)]
+    unseal_key_file: Option<PathBuf>,
+    /// Acceptable time skew for knocks (seconds)
+    #[arg(long, default_value_t = 30)]
+    window_secs: i64,
+    /// nftables family (default: discovered, as for `run`)
+    #[arg(long)]
+    nft_family: Option<String>,
+    /// nftables table (default: discovered, as for `run`)
+    #[arg(long)]
+    nft_table: Option<String>,
+    /// nftables set name to receive allowed source IPs (default: discovered)
+    #[arg(long)]
+    nft_set: Option<String>,
+    /// Port-bound grants; other than `off`, --nft-pair-set must be in place
+    #[arg(long, value_enum, default_value_t = PortGrants::Off)]
+    port_grants: PortGrants,
+    /// nftables set (type ipv4_addr . inet_service) for port-bound grants
+    #[arg(long, default_value = "wg_spa_allow_pair")]
+    nft_pair_set: String,
+    /// The wrapper port is gated; --nft-wrap-set must be in place
+    #[arg(long)]
+    wrap_gating: bool,
+    /// nftables set (type ipv4_addr) that admits sources to the wrapper port
+    #[arg(long, default_value = "wrap_spa_allow")]
+    nft_wrap_set: String,
+    /// Hysteria2 wrapper UDP port, to check its rule with --wrap-gating
+    #[arg(long)]
+    wrap_port: Option<u16>,
+    /// Rotating knock ports; --nft-port-set must be in place
+    #[arg(long, value_parser = listen::parse_port_range)]
+    rotate_ports: Option<(u16, u16)>,
+    /// nftables set (type inet_service) that admits the active rotating ports
+    #[arg(long, default_value = "spa_knock_ports")]
+    nft_port_set: String,
+    /// Secret the rotating ports derive from (as for `run`)
+    #[arg(long, default_value = "/etc/spa/port_secret.bin")]
+    port_secret_file: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Default)]
+struct Report {
+    counts: [usize; 3],
+    #[cfg(test)]
+    seen: Vec<(Status, String)>,
+}
+
+impl Report {
+    fn add(&mut self, status: Status, what: impl AsRef<str>, fix: Option<String>) {
+        let tag = match status {
+            Status::Ok => "[ ok ]",
+            Status::Warn => "[warn]",
+            Status::Fail => "[FAIL]",
+        };
+        println!("{} {}", tag, what.as_ref());
+        if let Some(fix) = fix {
+            for line in fix.lines() {
+                println!("       fix: {}", line);
+            }
+        }
+        self.counts[status as usize] += 1;
+        #[cfg(test)]
+        self.seen.push((status, what.as_ref().to_string()));
+    }
+
+    fn ok(&mut self, what: impl AsRef<str>) {
+        self.add(Status::Ok, what, None);
+    }
+
+    fn warn(&mut self, what: impl AsRef<str>, fix: impl Into<String>) {
+        self.add(Status::Warn, what, Some(fix.into()));
+    }
+
+    fn fail(&mut self, what: impl AsRef<str>, fix: impl Into<String>) {
+        self.add(Status::Fail, what, Some(fix.into()));
+    }
+
+    fn finish(self) -> Result<()> {
+        let [ok, warn, fail] = self.counts;
+        println!("{} ok, {} warnings, {} failures", ok, warn, fail);
+        if fail > 0 {
+            return Err(anyhow!("{} check(s) failed", fail));
+        }
+        Ok(())
+    }
+}
+
+pub fn run(args: &DoctorArgs) -> Result<()> {
+    let mut report = Report::default();
+    check_nft(args, &mut report);
+    check_secrets(args, &mut report);
+    check_clock(args, &mut report);
+    check_ports(args, &mut report);
+    report.finish()
+}
+
+fn check_nft(args: &DoctorArgs, report: &mut Report) {
+    let json = match nftables::list_ruleset() {
+        Ok(json) => json,
+        Err(e) => {
+            report.fail(
+                format!("cannot read the nftables ruleset: {:#}", e),
+                "run doctor as root, with nft (nftables) installed",
+            );
+            return;
+        }
+    };
+    check_ruleset(args, &json, report);
+}
+
+fn check_ruleset(args: &DoctorArgs, json: &str, report: &mut Report) {
+    let want = Want {
+        family: args.nft_family.as_deref(),
+        table: args.nft_table.as_deref(),
+        set: args.nft_set.as_deref(),
+        wg_port: args.wg_port,
+    };
+    let located = nftables::locate(json, &want, false)
+        .and_then(|l| Ok((Ruleset::parse(json, &l.family, &l.table)?, l)));
+    let (rs, layout) = match located {
+        Ok(found) => found,
+        Err(e) => {
+            let tables = Ruleset::parse(json, "", "")
+                .map(|rs| rs.tables)
+                .unwrap_or_default();
+            let present = if tables.is_empty() {
+                "no tables are loaded".to_string()
+            } else {
+                format!("tables present: {}", tables.join(", "))
+            };
+            report.fail(
+                format!("{:#} ({})", e, present),
+                "load router/configs/nftables.spa.conf (inet filter) or the OpenWRT include (inet fw4),\n\
+                 or let the daemon create its objects with --manage-objects",
+            );
+            return;
+        }
+    };
+    report.ok(format!(
+        "grants go to {} {} set {} ({})",
+        layout.family, layout.table, layout.set, layout.reason
+    ));
+
+    let sets = [
+        (&layout.set, IPV4, true, "--nft-set"),
+        (
+            &args.nft_pair_set,
+            PAIR,
+            args.port_grants != PortGrants::Off,
+            "--port-grants",
+        ),
+        (&args.nft_wrap_set, IPV4, args.wrap_gating, "--wrap-gating"),
+        (
+            &args.nft_port_set,
+            SERVICE,
+            args.rotate_ports.is_some(),
+            "--rotate-ports",
+        ),
+    ];
+    for (set, kind, needed, flag) in sets {
+        check_set(&rs, set, kind, needed, flag, report);
+    }
+
+    check_gate(
+        &rs,
+        Port::Num(args.wg_port),
+        Some((&layout.set, "ip saddr", "--nft-set")),
+        report,
+    );
+    if args.port_grants != PortGrants::Off {
+        check_gate(
+            &rs,
+            Port::Num(args.wg_port),
+            Some((&args.nft_pair_set, "ip saddr . udp sport", "--nft-pair-set")),
+            report,
+        );
+    }
+    if args.wrap_gating {
+        match args.wrap_port {
+            Some(port) => check_gate(
+                &rs,
+                Port::Num(port),
+                Some((&args.nft_wrap_set, "ip saddr", "--nft-wrap-set")),
+                report,
+            ),
+            None => report.warn(
+                "wrapper rule not checked",
+                "pass --wrap-port with --wrap-gating",
+            ),
+        }
+    }
+    if args.rotate_ports.is_some() {
+        check_gate(&rs, Port::Set(&args.nft_port_set), None, report);
+    }
+}
+
+fn check_set(
+    rs: &Ruleset,
+    name: &str,
+    kind: &[&str],
+    needed: bool,
+    flag: &str,
+    report: &mut Report,
+) {
+    let want = kind.join(" . ");
+    let create = format!(
+        "nft add set {} {} {} '{{ type {}; flags timeout; }}'",
+        rs.family, rs.table, name, want
+    );
+    let Some(set) = rs.set(name) else {
+        if needed {
+            report.fail(format!("nft set {} missing ({})", name, flag), create);
+        }
+        return;
+    };
+    let mut problems = Vec::new();
+    if set.kind != kind {
+        problems.push(format!("type {}, expected {}", set.kind.join(" . "), want));
+    }
+    if !set.flags.iter().any(|f| f == "timeout") {
+        problems.push("no `flags timeout`, so grants would never expire".to_string());
+    }
+    if problems.is_empty() {
+        report.ok(format!("nft set {}: {}, timeout", name, want));
+        return;
+    }
+    let what = format!("nft set {}: {}", name, problems.join("; "));
+    let fix = format!(
+        "nft delete set {} {} {}\n{}",
+        rs.family, rs.table, name, create
+    );
+    if needed {
+        report.fail(what, fix);
+    } else {
+        report.warn(format!("{} (unused unless {})", what, flag), fix);
+    }
+}
+
+/// `key` is what the rule matches against `set`; `flag` names the set.
+fn check_gate(rs: &Ruleset, port: Port, set: Option<(&str, &str, &str)>, report: &mut Report) {
+    let gate = rs.gate(port);
+    let port_text = match port {
+        Port::Num(n) => n.to_string(),
+        Port::Set(s) => format!("@{}", s),
+    };
+    let matched = set
+        .map(|(set, key, _)| format!(" {} @{}", key, set))
+        .unwrap_or_default();
+    // the rotating port set is meant to be open to anyone
+    let open = if matches!(port, Port::Num(_)) {
+        &gate.open[..]
+    } else {
+        &[]
+    };
+    for rule in open {
+        report.fail(
+            format!(
+                "udp dport {} is accepted without a knock ({})",
+                port_text, rule
+            ),
+            format!("nft delete rule {} {} {}", rs.family, rs.table, rule),
+        );
+    }
+    if gate.admits(set.map(|(set, _, _)| set)) {
+        report.ok(format!("input accepts udp dport {}{}", port_text, matched));
+        return;
+    }
+    let other = gate
+        .sets
+        .iter()
+        .find(|s| set.is_some_and(|(set, _, _)| set != s.as_str()));
+    let fix = match (other, set) {
+        (Some(other), Some((set, _, flag))) => format!(
+            "the rules for this port match @{} instead: run the daemon with {} {},\n\
+             or point the rules at @{}",
+            other, flag, other, set
+        ),
+        _ => format!(
+            "add to a chain hooked on input: udp dport {}{} accept",
+            port_text, matched
+        ),
+    };
+    report.fail(
+        format!("no input rule accepts udp dport {}{}", port_text, matched),
+        fix,
+    );
+}
+
+fn check_secrets(args: &DoctorArgs, report: &mut Report) {
+    let unseal = Unseal {
+        passphrase_file: args.unseal_passphrase_file.clone(),
+        key_file: args.unseal_key_file.clone(),
+    };
+    let kem_priv = check_secret(
+        "KEM private key",
+        &args.kem_priv,
+        SecretKind::KemPriv,
+        &unseal,
+        "home-secnet-spa-pq gen-keys --priv-out /etc/spa/kem_priv.bin --pub-out /etc/spa/kem_pub.bin",
+        report,
+    );
+    check_secret(
+        "PSK",
+        &args.psk_file,
+        SecretKind::Psk,
+        &unseal,
+        "head -c 32 /dev/urandom > /etc/spa/psk.bin && chmod 600 /etc/spa/psk.bin",
+        report,
+    );
+    if args.rotate_ports.is_some() {
+        check_secret(
+            "port secret",
+            &args.port_secret_file,
+            SecretKind::PortSecret,
+            &unseal,
+            "head -c 32 /dev/urandom > /etc/spa/port_secret.bin && chmod 600 /etc/spa/port_secret.bin\n\
+             then re-provision clients, or add its base64 to their JSON as port_secret_b64",
+            report,
+        );
+    }
+    let Some(kem_priv) = kem_priv else {
+        return;
+    };
+    match std::fs::read(&args.kem_pub) {
+        Ok(kem_pub) if kem_pub_of_secret(kem_priv.expose()) == Some(&kem_pub[..]) => report.ok(
+            format!("{} belongs to the KEM private key", args.kem_pub.display()),
+        ),
+        Ok(_) => report.fail(
+            format!(
+                "{} is not the public half of the KEM private key; clients with it cannot knock",
+                args.kem_pub.display()
+            ),
+            "restore the matching pair, or run gen-keys and hand clients the new public key",
+        ),
+        Err(e) => report.warn(
+            format!("{}: {}", args.kem_pub.display(), e),
+            "pass --kem-pub with the public key clients use",
+        ),
+    }
+}
+
+/// Check one secret's file and contents; the secret, if it loaded.
+fn check_secret(
+    label: &str,
+    path: &Path,
+    kind: SecretKind,
+    unseal: &Unseal,
+    create: &str,
+    report: &mut Report,
+) -> Option<secrets::SecretBytes> {
+    let shown = path.display().to_string();
+    if !shown.starts_with("cred:") {
+        match std::fs::metadata(path) {
+            Ok(meta) => check_mode(label, &shown, &meta, report),
+            Err(e) => {
+                report.fail(format!("{} {}: {}", label, shown, e), create);
+                return None;
+            }
+        }
+    }
+    match secrets::load(path, kind.len(), unseal) {
+        Ok(secret) => {
+            report.ok(format!("{} {}: {} bytes", label, shown, kind.len()));
+            Some(secret)
+        }
+        Err(e @ (SecretError::NeedsPassphrase(_) | SecretError::NeedsKeyFile(_))) => {
+            report.warn(
+                e.to_string(),
+                "pass it to doctor too to check the key opens",
+            );
+            None
+        }
+        Err(e @ SecretError::NoCredentialsDirectory(_)) => {
+            report.warn(
+                e.to_string(),
+                "check the unit's LoadCredential= line, or run doctor with the file path",
+            );
+            None
+        }
+        Err(e @ SecretError::WrongLength { .. }) => {
+            report.fail(e.to_string(), create);
+            None
+        }
+        Err(e) => {
+            report.fail(e.to_string(), format!("re-create it: {}", create));
+            None
+        }
+    }
+}
+
+fn check_mode(label: &str, path: &str, meta: &std::fs::Metadata, report: &mut Report) {
+    let mode = meta.mode() & 0o777;
+    if mode & 0o077 != 0 {
+        report.fail(
+            format!("{} {}: mode {:04o}, readable by others", label, path, mode),
+            format!("chmod 600 {}", path),
+        );
+    }
+    let euid = nix::unistd::geteuid().as_raw();
+    if meta.uid() != 0 && meta.uid() != euid {
+        report.warn(
+            format!("{} {}: owned by uid {}", label, path, meta.uid()),
+            format!(
+                "chown root {} (or the daemon's user, e.g. winder-spa)",
+                path
+            ),
+        );
+    }
+}
+
+fn check_clock(args: &DoctorArgs, report: &mut Report) {
+    let stale = format!(
+        "knocks from clocks more than {}s apart are rejected as stale",
+        args.window_secs
+    );
+    let output = |cmd: &str, args: &[&str]| {
+        Command::new(cmd)
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+    if let Some(synced) = output("timedatectl", &["show", "-p", "NTPSynchronized", "--value"]) {
+        if synced == "yes" {
+            report.ok("clock synchronized (timedatectl)");
+        } else {
+            report.warn(
+                format!("clock not NTP-synchronized; {}", stale),
+                "timedatectl set-ntp true (and check the NTP server is reachable)",
+            );
+        }
+    } else if let Some(enabled) = output("uci", &["-q", "get", "system.ntp.enabled"]) {
+        if enabled == "1" {
+            report.ok("NTP client enabled (uci system.ntp)");
+        } else {
+            report.warn(
+                format!("NTP client disabled; {}", stale),
+                "uci set system.ntp.enabled=1 && uci commit system && /etc/init.d/sysntpd restart",
+            );
+        }
+    } else {
+        report.warn(
+            format!("cannot tell whether the clock is synchronized; {}", stale),
+            "make sure an NTP client runs on the router",
+        );
+    }
+}
+
+fn check_ports(args: &DoctorArgs, report: &mut Report) {
+    if let Some(range) = args.rotate_ports {
+        let mut taken = vec![(args.wg_port, "--wg-port")];
+        taken.extend(args.wrap_port.map(|p| (p, "--wrap-port")));
+        if let Err(e) = listen::check_port_range(range, &taken) {
+            report.fail(
+                e.to_string(),
+                "pick a range clear of the daemon's other ports",
+            );
+        }
+    }
+    for listen in &args.listen {
+        let addr: SocketAddr = match listen.parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                report.fail(
+                    format!("--listen {}: {}", listen, e),
+                    "use ADDRESS:PORT, e.g. 0.0.0.0:62201",
+                );
+                continue;
+            }
+        };
+        if addr.port() == args.wg_port {
+            report.fail(
+                format!("--listen {} is the WireGuard port", listen),
+                "knock on a port of its own",
+            );
+            continue;
+        }
+        match UdpSocket::bind(addr) {
+            Ok(_) => report.ok(format!("udp {} free", addr)),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                let owner = Command::new("ss")
+                    .args(["-Hulnp", &format!("sport = :{}", addr.port())])
+                    .output()
+                    .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+                    .unwrap_or_default();
+                if owner.contains("spa-pq") {
+                    report.ok(format!("udp {} held by a running daemon", addr));
+                } else {
+                    let by = if owner.is_empty() {
+                        String::new()
+                    } else {
+                        format!(": {}", owner)
+                    };
+                    report.fail(
+                        format!("udp {} in use{}", addr, by),
+                        "stop the other service or pick another --listen port",
+                    );
+                }
+            }
+            Err(e) => report.warn(
+                format!("udp {}: {}", addr, e),
+                "run doctor as the daemon's user, with its capabilities",
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: DoctorArgs,
+    }
+
+    fn args(extra: &[&str]) -> DoctorArgs {
+        let argv = ["doctor", "--wg-port", "51820"].iter().chain(extra);
+        Cli::parse_from(argv).args
+    }
+
+    // `nft -j list ruleset` for fw4 with the 99-wg-spa.nft include, its rules
+    // reached through fw4's jump from input to input_wan
+    const FW4: &str = r#"{"nftables": [
+      {"metainfo": {"version": "1.0.9", "json_schema_version": 1}},
+      {"table": {"family": "inet", "name": "fw4", "handle": 1}},
+      {"set": {"family": "inet", "name": "wg_spa_allow", "table": "fw4", "type": "ipv4_addr", "handle": 2, "flags": ["timeout"]}},
+      {"set": {"family": "inet", "name": "wg_spa_allow_pair", "table": "fw4", "type": ["ipv4_addr", "inet_service"], "handle": 3, "flags": ["timeout"]}},
+      {"chain": {"family": "inet", "table": "fw4", "name": "input", "handle": 4, "type": "filter", "hook": "input", "prio": 0, "policy": "drop"}},
+      {"chain": {"family": "inet", "table": "fw4", "name": "input_wan", "handle": 5}},
+      {"rule": {"family": "inet", "table": "fw4", "chain": "input", "handle": 6, "expr": [
+        {"match": {"op": "==", "left": {"meta": {"key": "iifname"}}, "right": "wan"}},
+        {"jump": {"target": "input_wan"}}]}},
+      {"rule": {"family": "inet", "table": "fw4", "chain": "input_wan", "handle": 7, "expr": [
+        {"match": {"op": "==", "left": {"payload": {"protocol": "udp", "field": "dport"}}, "right": 51820}},
+        {"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": "@wg_spa_allow"}},
+        {"accept": null}]}},
+      {"rule": {"family": "inet", "table": "fw4", "chain": "input_wan", "handle": 8, "expr": [
+        {"match": {"op": "==", "left": {"payload": {"protocol": "udp", "field": "dport"}}, "right": 51820}},
+        {"match": {"op": "==", "left": {"concat": [{"payload": {"protocol": "ip", "field": "saddr"}}, {"payload": {"protocol": "udp", "field": "sport"}}]}, "right": "@wg_spa_allow_pair"}},
+        {"accept": null}]}}
+    ]}"#;
+
+    const JUMP: &str = r#"
+      {"rule": {"family": "inet", "table": "fw4", "chain": "input", "handle": 6, "expr": [
+        {"match": {"op": "==", "left": {"meta": {"key": "iifname"}}, "right": "wan"}},
+        {"jump": {"target": "input_wan"}}]}},"#;
+
+    fn check(args: &DoctorArgs, json: &str) -> Report {
+        let mut report = Report::default();
+        check_ruleset(args, json, &mut report);
+        report
+    }
+
+    fn failures(report: &Report) -> Vec<&str> {
+        report
+            .seen
+            .iter()
+            .filter(|(status, _)| *status == Status::Fail)
+            .map(|(_, what)| what.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn fw4_layout_passes() {
+        let report = check(&args(&["--port-grants", "allow"]), FW4);
+        assert!(failures(&report).is_empty(), "{:?}", report.seen);
+        assert_eq!(report.counts, [5, 0, 0]);
+        assert!(report.seen[0].1.contains("inet fw4 set wg_spa_allow"));
+    }
+
+    #[test]
+    fn missing_wg_port_jump_fails() {
+        assert!(FW4.contains(JUMP));
+        let report = check(&args(&[]), &FW4.replace(JUMP, ""));
+        // the set is still found by name, but nothing reaches the rule using it
+        assert!(report.seen[0].1.contains("found by name"));
+        assert_eq!(
+            failures(&report),
+            ["no input rule accepts udp dport 51820 ip saddr @wg_spa_allow"]
+        );
+    }
+
+    #[test]
+    fn set_type_and_timeout_mismatch() {
+        let pair = r#""type": ["ipv4_addr", "inet_service"], "handle": 3, "flags": ["timeout"]"#;
+        let json = FW4.replace(pair, r#""type": "ipv4_addr", "handle": 3"#);
+        let what = "nft set wg_spa_allow_pair: type ipv4_addr, expected ipv4_addr . inet_service; \
+                    no `flags timeout`, so grants would never expire";
+        // a failure when port grants use the set, a warning when nothing does
+        let report = check(&args(&["--port-grants", "require"]), &json);
+        assert_eq!(failures(&report), [what]);
+        let report = check(&args(&[]), &json);
+        assert!(failures(&report).is_empty());
+        assert_eq!(report.counts[Status::Warn as usize], 1);
+    }
+
+    #[test]
+    fn psk_readable_by_others_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("psk.bin");
+        std::fs::write(&path, [7u8; 32]).unwrap();
+        for (mode, fails) in [(0o600, false), (0o640, true), (0o604, true)] {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+            let mut report = Report::default();
+            let psk = check_secret(
+                "PSK",
+                &path,
+                SecretKind::Psk,
+                &Unseal::default(),
+                "",
+                &mut report,
+            );
+            assert!(psk.is_some());
+            let failed = failures(&report);
+            assert_eq!(!failed.is_empty(), fails, "{:04o}: {:?}", mode, report.seen);
+            if fails {
+                assert!(failed[0].ends_with(&format!("mode {:04o}, readable by others", mode)));
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// Key fingerprints for out-of-band comparison (`fingerprint`).
+//
+// A fingerprint is SHA-256 over a domain tag and the key material. It is shown
+// as eight groups of four hex digits (its first 128 bits) and six words (its
+// first 48 bits, one word per byte). The PSK is never shown; its fingerprint
+// is a commitment to it. The client carries the same code and can pin both
+// fingerprints in its config.
+
+use sha2::{Digest, Sha256};
+
+const KEM_TAG: &[u8] = b"open-winder spa-pq kem-pub v1";
+const PSK_TAG: &[u8] = b"open-winder spa-pq psk-commitment v1";
+
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    pub fn kem_pub(pk: &[u8]) -> Self {
+        Self::of(KEM_TAG, pk)
+    }
+
+    pub fn psk(psk: &[u8]) -> Self {
+        Self::of(PSK_TAG, psk)
+    }
+
+    fn of(tag: &[u8], data: &[u8]) -> Self {
+        let mut h = Sha256::new();
+        h.update(tag);
+        h.update(data);
+        Self(h.finalize().into())
+    }
+
+    pub fn hex(&self) -> String {
+        self.0[..16]
+            .chunks(2)
+            .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn words(&self) -> String {
+        self.0[..6]
+            .iter()
+            .map(|b| WORDS[*b as usize])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+const WORDS: [&str; 256] = [
+    "acorn", "adobe", "agent", "album", "alpha", "amber", "anchor", "angle", "ankle", "apple",
+    "armor", "arrow", "atlas", "attic", "audio", "autumn", "avenue", "bacon", "badge", "bagel",
+    "baker", "bamboo", "banjo", "barley", "basil", "basin", "beach", "beacon", "berry", "bison",
+    "blade", "blanket", "blossom", "bolt", "border", "bottle", "brick", "bridge", "bronze",
+    "brush", "bucket", "buffalo", "bugle", "butter", "cabin", "cactus", "camel", "canal", "candle",
+    "canoe", "canyon", "carbon", "cargo", "carpet", "castle", "cedar", "cello", "chalk", "cherry",
+    "chess", "chimney", "cider", "cinema", "circus", "citrus", "clover", "cobalt", "cocoa",
+    "comet", "copper", "coral", "cotton", "cougar", "crater", "cricket", "crystal", "cupcake",
+    "curtain", "cyclone", "dagger", "dairy", "delta", "denim", "desert", "diesel", "dingo",
+    "dolphin", "domino", "donkey", "dragon", "eagle", "eclipse", "elbow", "ember", "emerald",
+    "engine", "falcon", "fathom", "feather", "fennel", "ferry", "fiddle", "flannel", "flute",
+    "fossil", "fountain", "fox", "galaxy", "garlic", "gazelle", "gecko", "geyser", "ginger",
+    "glacier", "goblet", "gorilla", "granite", "gravel", "guitar", "hammer", "harbor", "hazel",
+    "helmet", "hermit", "hickory", "honey", "hornet", "husky", "igloo", "iguana", "indigo",
+    "island", "ivory", "jacket", "jaguar", "jasmine", "jelly", "jersey", "jigsaw", "jungle",
+    "kayak", "kernel", "kettle", "kiwi", "koala", "ladder", "lagoon", "lantern", "laser", "lemon",
+    "lentil", "lilac", "lizard", "lobster", "locket", "lotus", "lunar", "magnet", "mango", "maple",
+    "marble", "meadow", "melon", "mercury", "meteor", "mimosa", "mitten", "mosaic", "mustard",
+    "nectar", "needle", "nickel", "noodle", "nutmeg", "oasis", "ocean", "olive", "onion", "opal",
+    "orbit", "orchid", "otter", "oyster", "paddle", "panda", "panther", "papaya", "parrot",
+    "peanut", "pebble", "pepper", "piano", "pickle", "pigeon", "pillow", "pilot", "pirate",
+    "plasma", "plum", "polar", "pony", "poppy", "prism", "puffin", "pumpkin", "puzzle", "quartz",
+    "quiver", "rabbit", "radar", "radish", "raven", "ribbon", "rocket", "saddle", "salmon",
+    "satin", "scarf", "sequoia", "shadow", "sierra", "silver", "sketch", "sparrow", "spider",
+    "spruce", "squid", "walnut", "tiger", "timber", "tomato", "topaz", "tornado", "trumpet",
+    "tulip", "tundra", "turtle", "tuxedo", "umbrella", "unicorn", "velvet", "violet", "viper",
+    "volcano", "waffle", "wagon", "walrus", "wasabi", "willow", "wizard", "yacht", "yogurt",
+    "zebra", "zenith", "zephyr", "zigzag",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_are_short_and_domain_separated() {
+        let key = [7u8; 32];
+        let kem = Fingerprint::kem_pub(&key);
+        let psk = Fingerprint::psk(&key);
+        assert_eq!(kem.hex().len(), 39);
+        assert_eq!(kem.words().split(' ').count(), 6);
+        assert_ne!(kem.hex(), psk.hex());
+        assert_eq!(kem.hex(), Fingerprint::kem_pub(&key).hex());
+    }
+    #[test]
+    fn prints_the_client_vector() {
+        // the client's fingerprint::tests::fingerprints_match_the_daemon
+        // expects exactly these
+        let kem = Fingerprint::kem_pub(b"open-winder test kem_pub");
+        assert_eq!(kem.hex(), "d31f 95d6 d1af b32d bfad e1ca 803c a3cc");
+        assert_eq!(kem.words(), "raven blanket lemon saddle radar ocean");
+        let psk = Fingerprint::psk(&[6u8; 32]);
+        assert_eq!(psk.hex(), "67ef 5b9b 3d3c 844a 02c7 c770 9f19 6cfa");
+        assert_eq!(psk.words(), "flute unicorn eclipse lotus cider chimney");
+    }
+}
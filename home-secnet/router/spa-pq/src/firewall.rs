@@ -0,0 +1,584 @@
+// Firewall mutation: nft set elements and gated WireGuard peers.
+//
+// Everything the daemon changes outside itself is one of a few `Op`s, checked
+// against a `Policy` fixed at startup: which sets exist, which peers may be
+// enabled, the longest timeout a grant can ask for. With --privsep the ops
+// are carried out by a helper process (privsep.rs) and the network-facing
+// side gives up CAP_NET_ADMIN; without it, in-process by the same `Backend`.
+//
+// Every element the daemon adds carries the comment "spa-pq", or
+// "spa-pq:<client id>", so a restarted daemon can tell its own grants from
+// elements added by hand or by other tools.
+
+use crate::keepalive::{self, Peer};
+use crate::nftables::{self, Ruleset};
+use crate::payload;
+use crate::peers::{self, WgPeer};
+use crate::privsep::Helper;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::Ipv4Addr;
+
+/// Comment on every element the daemon adds.
+pub const COMMENT: &str = "spa-pq";
+
+/// The element comment for a grant to `client_id`.
+fn comment(client_id: Option<&str>) -> String {
+    match client_id {
+        Some(id) => format!("{}:{}", COMMENT, id),
+        None => COMMENT.to_string(),
+    }
+}
+
+/// Whether a comment marks a daemon element, and for which client.
+pub fn daemon_comment(comment: Option<&str>) -> Option<Option<&str>> {
+    match comment?.strip_prefix(COMMENT)? {
+        "" => Some(None),
+        rest => rest.strip_prefix(':').map(Some),
+    }
+}
+
+/// What a grant admits its address to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Service {
+    /// The WireGuard port (--nft-set, or --nft-pair-set with a source port)
+    Wg,
+    /// The Hysteria2 wrapper port (--nft-wrap-set)
+    Wrapper,
+}
+
+/// One firewall change.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Op {
+    /// Admit `ip` (from source `port`, wg only) to `service` for `ttl` seconds
+    Grant {
+        service: Service,
+        ip: Ipv4Addr,
+        port: Option<u16>,
+        ttl: u64,
+        client_id: Option<String>,
+    },
+    /// Give a WireGuard grant a fresh timeout (grant keepalive)
+    Extend {
+        ip: Ipv4Addr,
+        port: Option<u16>,
+        ttl: u64,
+        client_id: Option<String>,
+    },
+    /// Admit a rotating knock port for `ttl` seconds
+    OpenPort { port: u16, ttl: u64 },
+    /// Add a gated peer, configured as in the policy
+    EnablePeer { public_key: String },
+    /// Remove a gated peer
+    RevokePeer { public_key: String },
+    /// `wg show <iface> dump`, for the grant keepalive
+    WgDump,
+    /// Delete every daemon element from the policy's sets (--on-exit flush)
+    Flush,
+}
+
+/// What the firewall may be asked to do; fixed before any knock is read.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub family: String,
+    pub table: String,
+    pub wg_set: String,
+    pub pair_set: Option<String>,
+    pub wrap_set: Option<String>,
+    pub port_set: Option<String>,
+    pub wg_interface: Option<String>,
+    /// Peers that --peer-gating may enable
+    pub peers: Vec<WgPeer>,
+    /// Longest timeout of any element
+    pub max_ttl: u64,
+}
+
+impl Policy {
+    /// Refuse anything this daemon would never ask for.
+    pub fn check(&self, op: &Op) -> Result<()> {
+        match op {
+            Op::Grant {
+                service,
+                ip,
+                port,
+                ttl,
+                client_id,
+            } => {
+                check_ip(*ip)?;
+                check_client(client_id.as_deref())?;
+                self.check_ttl(*ttl)?;
+                self.set_for(*service, *port).map(|_| ())
+            }
+            Op::Extend {
+                ip,
+                port,
+                ttl,
+                client_id,
+            } => {
+                self.iface()?;
+                check_ip(*ip)?;
+                check_client(client_id.as_deref())?;
+                self.check_ttl(*ttl)?;
+                self.set_for(Service::Wg, *port).map(|_| ())
+            }
+            Op::OpenPort { port, ttl } => {
+                self.check_ttl(*ttl)?;
+                if *port == 0 {
+                    return Err(anyhow!("port 0"));
+                }
+                self.port_set
+                    .as_ref()
+                    .map(|_| ())
+                    .ok_or_else(|| anyhow!("no port set"))
+            }
+            Op::EnablePeer { public_key } | Op::RevokePeer { public_key } => {
+                self.iface()?;
+                self.peer(public_key).map(|_| ())
+            }
+            Op::WgDump => self.iface().map(|_| ()),
+            Op::Flush => Ok(()),
+        }
+    }
+
+    /// The sets grants and rotating ports go to.
+    fn sets(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(&self.wg_set)
+            .chain(&self.pair_set)
+            .chain(&self.wrap_set)
+            .chain(&self.port_set)
+            .map(String::as_str)
+    }
+
+    /// Daemon grants already in the sets of `ruleset`, and the number of
+    /// elements there that the daemon did not add.
+    pub fn held(&self, ruleset: &Ruleset) -> (Vec<Held>, usize) {
+        let mut held = Vec::new();
+        let mut foreign = 0;
+        let grant_sets = [
+            (Service::Wg, Some(&self.wg_set)),
+            (Service::Wg, self.pair_set.as_ref()),
+            (Service::Wrapper, self.wrap_set.as_ref()),
+        ];
+        for (service, name) in grant_sets {
+            let Some(set) = name.and_then(|n| ruleset.set(n)) else {
+                continue;
+            };
+            for e in &set.elements {
+                let Some(client_id) = daemon_comment(e.comment.as_deref()) else {
+                    foreign += 1;
+                    continue;
+                };
+                // without a timeout it was not a grant; let it be
+                let (Some(ip), Some(secs)) = (e.ip, e.expires.filter(|s| *s > 0)) else {
+                    continue;
+                };
+                held.push(Held {
+                    service,
+                    ip,
+                    port: e.port,
+                    secs: secs.min(self.max_ttl),
+                    client_id: client_id
+                        .filter(|id| payload::valid_client_id(id))
+                        .map(str::to_string),
+                });
+            }
+        }
+        (held, foreign)
+    }
+
+    fn check_ttl(&self, ttl: u64) -> Result<()> {
+        if ttl == 0 || ttl > self.max_ttl {
+            return Err(anyhow!("timeout {}s outside 1..={}", ttl, self.max_ttl));
+        }
+        Ok(())
+    }
+
+    fn set_for(&self, service: Service, port: Option<u16>) -> Result<&str> {
+        let set = match (service, port) {
+            (_, Some(0)) => return Err(anyhow!("port 0")),
+            (Service::Wg, None) => Some(&self.wg_set),
+            (Service::Wg, Some(_)) => self.pair_set.as_ref(),
+            (Service::Wrapper, None) => self.wrap_set.as_ref(),
+            (Service::Wrapper, Some(_)) => None,
+        };
+        set.map(String::as_str)
+            .ok_or_else(|| anyhow!("no set for {:?} grants with port {:?}", service, port))
+    }
+
+    fn iface(&self) -> Result<&str> {
+        self.wg_interface
+            .as_deref()
+            .ok_or_else(|| anyhow!("no WireGuard interface"))
+    }
+
+    fn peer(&self, public_key: &str) -> Result<&WgPeer> {
+        self.peers
+            .iter()
+            .find(|p| p.public_key == public_key)
+            .ok_or_else(|| anyhow!("unknown peer {}", public_key))
+    }
+}
+
+/// A grant found in the firewall at startup.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Held {
+    pub service: Service,
+    pub ip: Ipv4Addr,
+    pub port: Option<u16>,
+    /// Seconds left
+    pub secs: u64,
+    pub client_id: Option<String>,
+}
+
+/// Client ids end up in element comments; keep them to what a knock allows.
+fn check_client(client_id: Option<&str>) -> Result<()> {
+    match client_id {
+        Some(id) if !payload::valid_client_id(id) => Err(anyhow!("invalid client id")),
+        _ => Ok(()),
+    }
+}
+
+/// Addresses no grant is ever for.
+fn check_ip(ip: Ipv4Addr) -> Result<()> {
+    if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+        return Err(anyhow!("address {} cannot be granted", ip));
+    }
+    Ok(())
+}
+
+/// Carries out checked ops with nft and wg; needs CAP_NET_ADMIN.
+pub struct Backend {
+    policy: Policy,
+}
+
+impl Backend {
+    pub fn new(policy: Policy) -> Self {
+        Self { policy }
+    }
+
+    /// Check and apply `op`. Only `WgDump` returns output.
+    pub fn apply(&self, op: &Op) -> Result<Option<String>> {
+        self.policy.check(op)?;
+        let p = &self.policy;
+        match op {
+            Op::Grant {
+                service,
+                ip,
+                port,
+                ttl,
+                client_id,
+            } => {
+                let set = p.set_for(*service, *port)?;
+                let key = element_key(*ip, *port);
+                self.add_element(set, &key, *ttl, &comment(client_id.as_deref()))?;
+            }
+            Op::Extend {
+                ip,
+                port,
+                ttl,
+                client_id,
+            } => {
+                let set = p.set_for(Service::Wg, *port)?;
+                let key = element_key(*ip, *port);
+                self.refresh_element(set, &key, *ttl, &comment(client_id.as_deref()))?;
+            }
+            Op::OpenPort { port, ttl } => {
+                let set = p.port_set.as_deref().expect("checked");
+                self.add_element(set, &port.to_string(), *ttl, COMMENT)?;
+            }
+            Op::EnablePeer { public_key } => peers::add_peer(p.iface()?, p.peer(public_key)?)?,
+            Op::RevokePeer { public_key } => peers::remove_peer(p.iface()?, public_key)?,
+            Op::WgDump => return keepalive::show_dump(p.iface()?).map(Some),
+            Op::Flush => self.flush()?,
+        }
+        Ok(None)
+    }
+
+    fn add_element(&self, set: &str, key: &str, timeout_secs: u64, comment: &str) -> Result<()> {
+        // add element to set with timeout
+        let elem = format!(
+            "{{ {} timeout {}s comment \"{}\" }}",
+            key, timeout_secs, comment
+        );
+        let status = std::process::Command::new("nft")
+            .args([
+                "add",
+                "element",
+                &self.policy.family,
+                &self.policy.table,
+                set,
+                &elem,
+            ])
+            .status()
+            .context("nft add element")?;
+        if !status.success() {
+            return Err(anyhow!("nft add element failed"));
+        }
+        Ok(())
+    }
+
+    /// Re-add an element with a fresh timeout. It may have expired in the
+    /// meantime, so one atomic batch adds, deletes and adds it again.
+    fn refresh_element(
+        &self,
+        set: &str,
+        key: &str,
+        timeout_secs: u64,
+        comment: &str,
+    ) -> Result<()> {
+        let target = format!(
+            "element {} {} {}",
+            self.policy.family, self.policy.table, set
+        );
+        let batch = format!(
+            "add {t} {{ {k} }}\ndelete {t} {{ {k} }}\nadd {t} {{ {k} timeout {s}s comment \"{c}\" }}\n",
+            t = target,
+            k = key,
+            s = timeout_secs,
+            c = comment
+        );
+        let mut child = std::process::Command::new("nft")
+            .args(["-f", "-"])
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .context("nft -f -")?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(batch.as_bytes())
+                .context("write nft batch")?;
+        }
+        if !child.wait().context("nft -f -")?.success() {
+            return Err(anyhow!("nft element refresh failed"));
+        }
+        Ok(())
+    }
+
+    /// Delete the daemon's elements, leaving everything else in the sets.
+    /// Each is added before it is deleted, as in `refresh_element`, so one
+    /// that times out meanwhile does not fail the batch.
+    fn flush(&self) -> Result<()> {
+        let (family, table) = (&self.policy.family, &self.policy.table);
+        let mut batch = Vec::new();
+        for name in self.policy.sets() {
+            let json = nftables::list_set(family, table, name)?;
+            let ruleset = Ruleset::parse(&json, family, table)?;
+            let Some(set) = ruleset.set(name) else {
+                continue;
+            };
+            for e in &set.elements {
+                if daemon_comment(e.comment.as_deref()).is_none() {
+                    continue;
+                }
+                let target = format!("element {} {} {}", family, table, name);
+                batch.push(format!("add {} {{ {} }}", target, e.key()));
+                batch.push(format!("delete {} {{ {} }}", target, e.key()));
+            }
+        }
+        eprintln!("nft: flushing {} daemon element(s)", batch.len() / 2);
+        if batch.is_empty() {
+            return Ok(());
+        }
+        nftables::apply(&batch.join("\n"))
+    }
+}
+
+fn element_key(ip: Ipv4Addr, port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("{} . {}", ip, port),
+        None => ip.to_string(),
+    }
+}
+
+/// Where firewall ops go: this process, or the privileged helper.
+pub enum Firewall {
+    Direct(Backend),
+    Privsep(Helper),
+}
+
+impl Firewall {
+    fn call(&mut self, op: Op) -> Result<Option<String>> {
+        match self {
+            Firewall::Direct(b) => b.apply(&op),
+            Firewall::Privsep(h) => h.call(&op),
+        }
+    }
+
+    pub fn grant(
+        &mut self,
+        service: Service,
+        ip: Ipv4Addr,
+        port: Option<u16>,
+        ttl: u64,
+        client_id: Option<&str>,
+    ) -> Result<()> {
+        self.call(Op::Grant {
+            service,
+            ip,
+            port,
+            ttl,
+            client_id: client_id.map(str::to_string),
+        })
+        .map(|_| ())
+    }
+
+    pub fn extend(
+        &mut self,
+        ip: Ipv4Addr,
+        port: Option<u16>,
+        ttl: u64,
+        client_id: Option<&str>,
+    ) -> Result<()> {
+        self.call(Op::Extend {
+            ip,
+            port,
+            ttl,
+            client_id: client_id.map(str::to_string),
+        })
+        .map(|_| ())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.call(Op::Flush).map(|_| ())
+    }
+
+    pub fn open_port(&mut self, port: u16, ttl: u64) -> Result<()> {
+        self.call(Op::OpenPort { port, ttl }).map(|_| ())
+    }
+
+    pub fn enable_peer(&mut self, public_key: &str) -> Result<()> {
+        self.call(Op::EnablePeer {
+            public_key: public_key.to_string(),
+        })
+        .map(|_| ())
+    }
+
+    pub fn revoke_peer(&mut self, public_key: &str) -> Result<()> {
+        self.call(Op::RevokePeer {
+            public_key: public_key.to_string(),
+        })
+        .map(|_| ())
+    }
+
+    pub fn wg_dump(&mut self) -> Result<Vec<Peer>> {
+        let dump = self
+            .call(Op::WgDump)?
+            .ok_or_else(|| anyhow!("wg dump: no output"))?;
+        Ok(keepalive::parse_dump(&dump))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_refuses_what_the_daemon_never_asks_for() {
+        let policy = Policy {
+            family: "inet".into(),
+            table: "fw4".into(),
+            wg_set: "wg_spa_allow".into(),
+            wrap_set: Some("wrap_spa_allow".into()),
+            max_ttl: 45,
+            ..Default::default()
+        };
+        let ip = Ipv4Addr::new(198, 51, 100, 7);
+        let grant = |service, ip, port, ttl| Op::Grant {
+            service,
+            ip,
+            port,
+            ttl,
+            client_id: None,
+        };
+        assert!(policy.check(&grant(Service::Wg, ip, None, 45)).is_ok());
+        assert!(policy.check(&grant(Service::Wrapper, ip, None, 1)).is_ok());
+        // too long, no pair set, no port set, no interface, bad address
+        assert!(policy.check(&grant(Service::Wg, ip, None, 46)).is_err());
+        assert!(policy.check(&grant(Service::Wg, ip, None, 0)).is_err());
+        assert!(policy
+            .check(&grant(Service::Wg, ip, Some(51820), 45))
+            .is_err());
+        assert!(policy
+            .check(&Op::OpenPort {
+                port: 62201,
+                ttl: 45
+            })
+            .is_err());
+        assert!(policy.check(&Op::WgDump).is_err());
+        let bcast = Ipv4Addr::BROADCAST;
+        assert!(policy.check(&grant(Service::Wg, bcast, None, 45)).is_err());
+        // client ids go into element comments
+        assert!(policy
+            .check(&Op::Grant {
+                service: Service::Wg,
+                ip,
+                port: None,
+                ttl: 45,
+                client_id: Some("laptop\" flush".into()),
+            })
+            .is_err());
+        // ops are plain JSON; unknown fields are not
+        let op: Op = serde_json::from_str(
+            r#"{"op":"grant","service":"wg","ip":"198.51.100.7","port":null,"ttl":45}"#,
+        )
+        .unwrap();
+        assert_eq!(op, grant(Service::Wg, ip, None, 45));
+        assert!(serde_json::from_str::<Op>(
+            r#"{"op":"grant","service":"wg","ip":"198.51.100.7","port":null,"ttl":45,"set":"x"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn held_grants_are_the_commented_ones() {
+        let policy = Policy {
+            family: "inet".into(),
+            table: "fw4".into(),
+            wg_set: "wg_spa_allow".into(),
+            pair_set: Some("wg_spa_allow_pair".into()),
+            max_ttl: 45,
+            ..Default::default()
+        };
+        let ruleset = Ruleset::parse(
+            r#"{"nftables": [
+              {"table": {"family": "inet", "name": "fw4", "handle": 1}},
+              {"set": {"family": "inet", "name": "wg_spa_allow", "table": "fw4", "type": "ipv4_addr", "handle": 2, "flags": ["timeout"], "elem": [
+                {"elem": {"val": "198.51.100.7", "timeout": 45, "expires": 30, "comment": "spa-pq:laptop"}},
+                {"elem": {"val": "198.51.100.8", "timeout": 600, "expires": 500, "comment": "spa-pq"}},
+                {"elem": {"val": "192.0.2.1", "timeout": 45, "expires": 12, "comment": "added by hand"}},
+                "192.0.2.2"]}},
+              {"set": {"family": "inet", "name": "wg_spa_allow_pair", "table": "fw4", "type": ["ipv4_addr", "inet_service"], "handle": 3, "flags": ["timeout"], "elem": [
+                {"elem": {"val": {"concat": ["203.0.113.5", 40000]}, "timeout": 45, "expires": 44, "comment": "spa-pq"}}]}}
+            ]}"#,
+            "inet",
+            "fw4",
+        )
+        .unwrap();
+        let (held, foreign) = policy.held(&ruleset);
+        let wg = |ip: [u8; 4], port, secs, client_id: Option<&str>| Held {
+            service: Service::Wg,
+            ip: Ipv4Addr::from(ip),
+            port,
+            secs,
+            client_id: client_id.map(str::to_string),
+        };
+        assert_eq!(
+            held,
+            vec![
+                wg([198, 51, 100, 7], None, 30, Some("laptop")),
+                // never longer than this daemon would grant
+                wg([198, 51, 100, 8], None, 45, None),
+                wg([203, 0, 113, 5], Some(40000), 44, None),
+            ]
+        );
+        assert_eq!(foreign, 2);
+        assert_eq!(daemon_comment(Some("spa-pqx")), None);
+        assert_eq!(
+            ruleset.set("wg_spa_allow_pair").unwrap().elements[0].key(),
+            "203.0.113.5 . 40000"
+        );
+    }
+}
@@ -0,0 +1,230 @@
+// Grant keepalive: extend grants while their WireGuard session is active.
+//
+// A grant expires after open_secs, and once conntrack also forgets the flow a
+// roaming peer's re-handshake is dropped mid-session. With --wg-interface the
+// daemon polls `wg show <iface> dump` and, for every peer whose endpoint
+// matches a live grant and that completed a handshake within ACTIVE_SECS,
+// pushes the grant's expiry forward by open_secs. Each grant carries a hard
+// limit (--keepalive-max-secs, or the client's `keepalive_max_secs`) counted
+// from its knock; after that the client has to knock again.
+//
+// Behind CGNAT several peers share the granted address, so only one of them
+// may keep a grant alive: the client's `wg_peer` if it has one, otherwise
+// the peer whose session first extended the grant.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+/// WireGuard re-keys every 2 minutes while traffic flows; a session with no
+/// handshake for 3 minutes is idle or gone.
+const ACTIVE_SECS: i64 = 180;
+
+/// One peer line of `wg show <iface> dump`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub public_key: String,
+    pub endpoint: Option<SocketAddrV4>,
+    pub latest_handshake: i64,
+}
+
+/// Raw `wg show <iface> dump` output; see `parse_dump`.
+pub fn show_dump(iface: &str) -> Result<String> {
+    let out = std::process::Command::new("wg")
+        .args(["show", iface, "dump"])
+        .output()
+        .context("run wg show")?;
+    if !out.status.success() {
+        return Err(anyhow!("wg show {} dump failed", iface));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Peers from a dump; the first line describes the interface itself.
+pub fn parse_dump(out: &str) -> Vec<Peer> {
+    out.lines()
+        .skip(1)
+        .filter_map(|l| {
+            // public-key preshared-key endpoint allowed-ips latest-handshake ...
+            let f: Vec<&str> = l.split('\t').collect();
+            let endpoint = match f.get(2)?.parse() {
+                Ok(SocketAddr::V4(ep)) => Some(ep),
+                _ => None,
+            };
+            Some(Peer {
+                public_key: f.first()?.to_string(),
+                endpoint,
+                latest_handshake: f.get(4)?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// A grant as the keepalive sees it: the nft element and its limits.
+struct Grant {
+    client_id: Option<String>,
+    expires: i64,
+    max_until: i64,
+    /// The only peer that may extend the grant, once known
+    peer: Option<String>,
+    extended: bool,
+}
+
+/// An element to re-add with a fresh timeout.
+pub struct Extension {
+    pub ip: Ipv4Addr,
+    pub port: Option<u16>,
+    pub secs: u64,
+    pub client_id: Option<String>,
+    pub peer: String,
+    /// First extension for this peer: the grant is now tied to its session
+    pub new_session: bool,
+}
+
+/// Live grants, keyed like their nft element: address, or address and port.
+#[derive(Default)]
+pub struct GrantTable {
+    grants: HashMap<(Ipv4Addr, Option<u16>), Grant>,
+}
+
+impl GrantTable {
+    /// Track a grant just made by a knock. A new knock restarts the limit.
+    /// `wg_peer` is the public key from the client's policy, if any.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &mut self,
+        ip: Ipv4Addr,
+        port: Option<u16>,
+        client_id: Option<String>,
+        wg_peer: Option<String>,
+        secs: u64,
+        max_secs: u64,
+        now: i64,
+    ) {
+        let expires = now + secs as i64;
+        self.grants.insert(
+            (ip, port),
+            Grant {
+                client_id,
+                expires,
+                max_until: expires.max(now + max_secs as i64),
+                peer: wg_peer,
+                extended: false,
+            },
+        );
+    }
+
+    /// Extensions due for grants whose peer handshook recently. Grants are
+    /// extended once less than half of `open_secs` is left.
+    pub fn extend(&mut self, peers: &[Peer], open_secs: u64, now: i64) -> Vec<Extension> {
+        self.grants.retain(|_, g| g.expires > now);
+        let mut out = Vec::new();
+        for p in peers {
+            let Some(ep) = p.endpoint else { continue };
+            if now - p.latest_handshake > ACTIVE_SECS {
+                continue;
+            }
+            let key = [(*ep.ip(), Some(ep.port())), (*ep.ip(), None)]
+                .into_iter()
+                .find(|k| self.grants.contains_key(k));
+            let Some(key) = key else { continue };
+            let g = self.grants.get_mut(&key).expect("key was just found");
+            if g.peer.as_deref().is_some_and(|k| k != p.public_key) {
+                continue;
+            }
+            let until = (now + open_secs as i64).min(g.max_until);
+            if g.expires - now >= open_secs as i64 / 2 || until <= g.expires {
+                continue;
+            }
+            let new_session = !g.extended;
+            g.extended = true;
+            g.peer = Some(p.public_key.clone());
+            g.expires = until;
+            out.push(Extension {
+                ip: key.0,
+                port: key.1,
+                secs: (until - now) as u64,
+                client_id: g.client_id.clone(),
+                peer: p.public_key.clone(),
+                new_session,
+            });
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = "cHJpdg=\tcHVi=\t51820\toff\n\
+        QUFB=\t(none)\t198.51.100.7:40123\t10.8.0.2/32\t1000\t10\t20\toff\n\
+        QkJC=\t(none)\t(none)\t10.8.0.3/32\t0\t0\t0\toff\n";
+
+    #[test]
+    fn dump_is_parsed() {
+        let peers = parse_dump(DUMP);
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].public_key, "QUFB=");
+        assert_eq!(
+            peers[0].endpoint,
+            Some("198.51.100.7:40123".parse().unwrap())
+        );
+        assert_eq!(peers[0].latest_handshake, 1000);
+        assert_eq!(peers[1].endpoint, None);
+    }
+
+    #[test]
+    fn active_sessions_extend_grants_up_to_the_limit() {
+        let peers = parse_dump(DUMP);
+        let ip = Ipv4Addr::new(198, 51, 100, 7);
+        let mut t = GrantTable::default();
+        t.record(ip, Some(40123), Some("laptop".into()), None, 45, 100, 1000);
+        // plenty of time left: nothing to do yet
+        assert!(t.extend(&peers, 45, 1010).is_empty());
+        let ext = t.extend(&peers, 45, 1030);
+        assert_eq!(ext.len(), 1);
+        assert_eq!((ext[0].port, ext[0].secs), (Some(40123), 45));
+        assert!(ext[0].new_session);
+        // capped at 100s after the knock
+        let ext = t.extend(&peers, 45, 1060);
+        assert_eq!(ext[0].secs, 40);
+        assert!(!ext[0].new_session);
+        assert!(t.extend(&peers, 45, 1090).is_empty());
+        // a stale handshake does not keep a grant alive
+        let mut t = GrantTable::default();
+        t.record(ip, None, None, None, 45, 100, 1300);
+        assert!(t.extend(&peers, 45, 1330).is_empty());
+    }
+
+    #[test]
+    fn only_the_grants_peer_extends_it() {
+        // two peers behind one CGNAT address
+        let dump = "cHJpdg=\tcHVi=\t51820\toff\n\
+            QUFB=\t(none)\t198.51.100.7:40123\t10.8.0.2/32\t1000\t10\t20\toff\n\
+            QkJC=\t(none)\t198.51.100.7:40999\t10.8.0.3/32\t1000\t10\t20\toff\n";
+        let peers = parse_dump(dump);
+        let ip = Ipv4Addr::new(198, 51, 100, 7);
+        let mut t = GrantTable::default();
+        t.record(
+            ip,
+            None,
+            Some("phone".into()),
+            Some("QkJC=".into()),
+            45,
+            500,
+            1000,
+        );
+        let ext = t.extend(&peers, 45, 1030);
+        assert_eq!(ext.len(), 1);
+        assert_eq!(ext[0].peer, "QkJC=");
+        // the wrong key never extends it
+        assert!(t.extend(&peers[..1], 45, 1060).is_empty());
+        // without a wg_peer the first session's peer holds the grant
+        let mut t = GrantTable::default();
+        t.record(ip, None, None, None, 45, 500, 1000);
+        let ext = t.extend(&peers, 45, 1030);
+        assert_eq!((ext.len(), ext[0].peer.as_str()), (1, "QUFB="));
+        assert!(t.extend(&peers[1..], 45, 1060).is_empty());
+    }
+}
@@ -0,0 +1,391 @@
+// Knock listeners: a single static port, or a port that rotates per time slot.
+//
+// Every listen address (repeatable --listen) gets a socket per --interface,
+// pinned with SO_BINDTODEVICE so knocks arriving elsewhere (e.g. LAN VLANs)
+// never reach the daemon. Replies go out with IP_PKTINFO/IPV6_PKTINFO copied
+// from the knock, so on multi-WAN routers they leave from the local address
+// and interface the knock came in on.
+//
+// In rotating mode the port for slot `s = unix_time / slot_secs` is
+// `min + HMAC(port_key, s)[..4] mod (max - min + 1)`, with
+// `port_key = SHA256("open-winder spa-pq port v2" || port_secret)`: a 32-byte
+// secret (--port-secret-file) that `provision` hands to clients, so the
+// schedule is hidden from anyone holding only public material. The daemon keeps
+// sockets for slots s-1, s and s+1 open so knocks near a slot boundary (or
+// from clients with slightly skewed clocks) still land, and adds each active
+// port to an nft set with a timeout so the input chain admits it.
+
+use anyhow::{anyhow, Context, Result};
+use hmac::Mac;
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::socket::{
+    recvmsg, sendmsg, setsockopt, sockopt, ControlMessage, ControlMessageOwned, MsgFlags,
+    SockaddrIn, SockaddrIn6, SockaddrStorage,
+};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{IoSlice, IoSliceMut};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+
+use crate::HmacSha256;
+
+const PORT_DOMAIN: &[u8] = b"open-winder spa-pq port v2";
+
+#[derive(Clone)]
+pub struct PortRotation {
+    key: [u8; 32],
+    min: u16,
+    max: u16,
+    slot_secs: u64,
+}
+
+impl PortRotation {
+    pub fn new(port_secret: &[u8], range: (u16, u16), slot_secs: u64) -> Self {
+        let mut h = Sha256::new();
+        h.update(PORT_DOMAIN);
+        h.update(port_secret);
+        Self {
+            key: h.finalize().into(),
+            min: range.0,
+            max: range.1,
+            slot_secs: slot_secs.max(1),
+        }
+    }
+
+    pub fn slot_secs(&self) -> u64 {
+        self.slot_secs
+    }
+
+    /// The ports this rotation may pick, inclusive.
+    pub fn range(&self) -> (u16, u16) {
+        (self.min, self.max)
+    }
+
+    pub fn port_for_slot(&self, slot: u64) -> u16 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.key).expect("hmac key");
+        mac.update(&slot.to_be_bytes());
+        let d = mac.finalize().into_bytes();
+        let span = (self.max - self.min) as u32 + 1;
+        self.min + (u32::from_be_bytes([d[0], d[1], d[2], d[3]]) % span) as u16
+    }
+
+    /// Ports for the previous, current and next slot (deduplicated).
+    pub fn active_ports(&self, now_unix: i64) -> Vec<u16> {
+        let slot = now_unix.max(0) as u64 / self.slot_secs;
+        let mut ports: Vec<u16> = [slot.saturating_sub(1), slot, slot + 1]
+            .iter()
+            .map(|s| self.port_for_slot(*s))
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+}
+
+/// Parse `MIN-MAX` (inclusive).
+pub fn parse_port_range(s: &str) -> Result<(u16, u16)> {
+    let (a, b) = s
+        .split_once('-')
+        .ok_or_else(|| anyhow!("expected MIN-MAX, got {}", s))?;
+    let (min, max): (u16, u16) = (a.trim().parse()?, b.trim().parse()?);
+    if min == 0 || min > max {
+        return Err(anyhow!("invalid port range {}", s));
+    }
+    Ok((min, max))
+}
+
+/// Refuse a rotation range that covers a UDP port the daemon or WireGuard
+/// already uses; `taken` pairs each port with the flag that set it.
+pub fn check_port_range(range: (u16, u16), taken: &[(u16, &str)]) -> Result<()> {
+    let clash: Vec<String> = taken
+        .iter()
+        .filter(|(port, _)| (range.0..=range.1).contains(port))
+        .map(|(port, flag)| format!("{} {}", flag, port))
+        .collect();
+    if clash.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "--rotate-ports {}-{} covers {}",
+        range.0,
+        range.1,
+        clash.join(", ")
+    ))
+}
+
+/// The knock sockets: one per listen address and interface, or, with a
+/// rotation, one per listen address, interface and active port.
+pub struct Listeners {
+    addrs: Vec<SocketAddr>,
+    interfaces: Vec<String>,
+    rotation: Option<PortRotation>,
+    socks: Vec<(u16, UdpSocket)>,
+    next: usize,
+}
+
+/// Where to send the reply to a knock: back to its sender, from the local
+/// address and interface it arrived on (IP_PKTINFO / IPV6_PKTINFO).
+pub struct Reply<'a> {
+    sock: &'a UdpSocket,
+    dst: SocketAddr,
+    info: Option<PktInfo>,
+}
+
+#[derive(Clone, Copy)]
+enum PktInfo {
+    V4(libc::in_pktinfo),
+    V6(libc::in6_pktinfo),
+}
+
+impl Listeners {
+    pub fn new(
+        listens: &[String],
+        interfaces: &[String],
+        rotation: Option<PortRotation>,
+    ) -> Result<Self> {
+        let addrs = listens
+            .iter()
+            .map(|l| {
+                l.parse()
+                    .with_context(|| format!("parse listen address {}", l))
+            })
+            .collect::<Result<Vec<SocketAddr>>>()?;
+        let mut me = Self {
+            addrs,
+            interfaces: interfaces.to_vec(),
+            rotation,
+            socks: Vec::new(),
+            next: 0,
+        };
+        if me.rotation.is_none() {
+            for addr in me.addrs.clone() {
+                for sock in me.bind_all(addr)? {
+                    me.socks.push((addr.port(), sock));
+                }
+            }
+        }
+        Ok(me)
+    }
+
+    /// The bound sockets, for waiting on them.
+    pub fn fds(&self) -> impl Iterator<Item = BorrowedFd<'_>> {
+        self.socks.iter().map(|(_, s)| s.as_fd())
+    }
+
+    /// When the set of active rotating ports next changes, in unix seconds.
+    pub fn next_sync(&self, now_unix: i64) -> Option<i64> {
+        let slot = self.rotation.as_ref()?.slot_secs() as i64;
+        Some((now_unix.max(0) / slot + 1) * slot)
+    }
+
+    /// Open sockets for newly active rotating ports and close stale ones.
+    /// Returns the ports that were opened; a port that fails to bind is
+    /// logged and retried on the next sync.
+    pub fn sync(&mut self, now_unix: i64) -> Vec<u16> {
+        let Some(rot) = &self.rotation else {
+            return Vec::new();
+        };
+        let want = rot.active_ports(now_unix);
+        self.socks.retain(|(p, _)| want.contains(p));
+        let mut opened = Vec::new();
+        for port in want {
+            if self.socks.iter().any(|(p, _)| *p == port) {
+                continue;
+            }
+            let binds: Result<Vec<Vec<UdpSocket>>> = self
+                .addrs
+                .iter()
+                .map(|a| self.bind_all(SocketAddr::new(a.ip(), port)))
+                .collect();
+            match binds {
+                Ok(socks) => {
+                    self.socks
+                        .extend(socks.into_iter().flatten().map(|s| (port, s)));
+                    opened.push(port);
+                }
+                Err(e) => eprintln!("rotating port {}: {:#}", port, e),
+            }
+        }
+        opened
+    }
+
+    /// Non-blocking receive across all sockets, round-robin.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> Result<Option<(usize, SocketAddr, Reply<'_>)>> {
+        let count = self.socks.len();
+        for i in 0..count {
+            let idx = (self.next + i) % count;
+            match recv_with_info(&self.socks[idx].1, buf) {
+                Ok((n, src, info)) => {
+                    self.next = (idx + 1) % count;
+                    let reply = Reply {
+                        sock: &self.socks[idx].1,
+                        dst: src,
+                        info,
+                    };
+                    return Ok(Some((n, src, reply)));
+                }
+                Err(Errno::EAGAIN) => continue,
+                Err(e) => return Err(anyhow!("socket error: {}", e)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// One socket for `addr` per configured interface (or one unbound).
+    fn bind_all(&self, addr: SocketAddr) -> Result<Vec<UdpSocket>> {
+        if self.interfaces.is_empty() {
+            return Ok(vec![bind(addr, None)?]);
+        }
+        self.interfaces
+            .iter()
+            .map(|i| bind(addr, Some(i)))
+            .collect()
+    }
+}
+
+impl Reply<'_> {
+    pub fn send(&self, data: &[u8]) -> Result<()> {
+        let iov = [IoSlice::new(data)];
+        let fd = self.sock.as_raw_fd();
+        let res = match (self.dst, self.info) {
+            (SocketAddr::V4(dst), Some(PktInfo::V4(info))) => sendmsg(
+                fd,
+                &iov,
+                &[ControlMessage::Ipv4PacketInfo(&info)],
+                MsgFlags::empty(),
+                Some(&SockaddrIn::from(dst)),
+            ),
+            (SocketAddr::V6(dst), Some(PktInfo::V6(info))) => sendmsg(
+                fd,
+                &iov,
+                &[ControlMessage::Ipv6PacketInfo(&info)],
+                MsgFlags::empty(),
+                Some(&SockaddrIn6::from(dst)),
+            ),
+            _ => return Ok(self.sock.send_to(data, self.dst).map(|_| ())?),
+        };
+        res.map(|_| ())
+            .map_err(|e| anyhow!("reply to {}: {}", self.dst, e))
+    }
+}
+
+fn recv_with_info(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+) -> nix::Result<(usize, SocketAddr, Option<PktInfo>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg = nix::cmsg_space!(libc::in_pktinfo, libc::in6_pktinfo);
+    let msg = recvmsg::<SockaddrStorage>(
+        sock.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::empty(),
+    )?;
+    let src = msg
+        .address
+        .and_then(|a| {
+            a.as_sockaddr_in()
+                .map(|v4| SocketAddr::V4((*v4).into()))
+                .or_else(|| a.as_sockaddr_in6().map(|v6| SocketAddr::V6((*v6).into())))
+        })
+        .ok_or(Errno::EAFNOSUPPORT)?;
+    let info = msg.cmsgs()?.find_map(|c| match c {
+        ControlMessageOwned::Ipv4PacketInfo(i) => Some(PktInfo::V4(i)),
+        ControlMessageOwned::Ipv6PacketInfo(i) => Some(PktInfo::V6(i)),
+        _ => None,
+    });
+    Ok((msg.bytes, src, info))
+}
+
+fn bind(addr: SocketAddr, iface: Option<&str>) -> Result<UdpSocket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(iface) = iface {
+        sock.bind_device(Some(iface.as_bytes()))
+            .with_context(|| format!("bind {} to interface {}", addr, iface))?;
+    }
+    match addr {
+        SocketAddr::V4(_) => setsockopt(&sock, sockopt::Ipv4PacketInfo, &true)?,
+        SocketAddr::V6(_) => setsockopt(&sock, sockopt::Ipv6RecvPacketInfo, &true)?,
+    }
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())
+        .with_context(|| format!("bind {}", addr))?;
+    Ok(sock.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn rotation_is_deterministic_and_in_range() {
+        let a = PortRotation::new(&[1u8; 32], (40000, 40999), 30);
+        let b = PortRotation::new(&[1u8; 32], (40000, 40999), 30);
+        for slot in 0..100 {
+            let p = a.port_for_slot(slot);
+            assert!((40000..=40999).contains(&p));
+            assert_eq!(p, b.port_for_slot(slot));
+        }
+        // overlap: the current port stays active through the next slot
+        let now = 1_700_000_000i64;
+        let cur = a.port_for_slot(now as u64 / 30);
+        assert!(a.active_ports(now).contains(&cur));
+        assert!(a.active_ports(now + 30).contains(&cur));
+    }
+
+    #[test]
+    fn rotates_the_client_vector() {
+        // the client's rotate::tests::port_matches_the_daemon_schedule
+        // expects this port for secret [9; 32] at 1_700_000_000
+        let r = PortRotation::new(&[9u8; 32], (40000, 40999), 30);
+        assert_eq!(r.port_for_slot(1_700_000_000 / 30), 40942);
+    }
+
+    #[test]
+    fn reply_leaves_from_the_knocked_address() {
+        let mut l = Listeners::new(&["0.0.0.0:0".to_string()], &[], None).unwrap();
+        let port = l.socks[0].1.local_addr().unwrap().port();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        // 127.0.0.2 is local but not the address the kernel would pick
+        client.send_to(b"knock", ("127.0.0.2", port)).unwrap();
+        let mut buf = [0u8; 16];
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let got = loop {
+            if let Some((n, src, reply)) = l.recv_from(&mut buf).unwrap() {
+                reply.send(b"OK").unwrap();
+                break (n, src);
+            }
+            assert!(
+                Instant::now() < deadline,
+                "knock never reached the listener"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(got, (5, client.local_addr().unwrap()));
+        let (n, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"OK");
+        assert_eq!(from, SocketAddr::from(([127, 0, 0, 2], port)));
+    }
+
+    #[test]
+    fn port_range_parsing() {
+        assert_eq!(parse_port_range("40000-49999").unwrap(), (40000, 49999));
+        assert!(parse_port_range("5-1").is_err());
+        assert!(parse_port_range("0-10").is_err());
+        assert!(parse_port_range("40000").is_err());
+        let taken = [(51820, "--wg-port"), (5354, "--dns-listen")];
+        assert!(check_port_range((40000, 49999), &taken).is_ok());
+        let err = check_port_range((50000, 59999), &taken).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--rotate-ports 50000-59999 covers --wg-port 51820"
+        );
+        assert!(check_port_range((5354, 5354), &taken).is_err());
+    }
+}
@@ -1,15 +1,22 @@
 #![forbid(unsafe_code)]
 
 use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use clap::{Parser, Subcommand};
 use hmac::{Hmac, Mac};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use sha2::Sha256;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
 use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-use std::path::PathBuf;
-use std::thread;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use pqcrypto_mlkem::mlkem768 as kem;
@@ -18,14 +25,58 @@ use pqcrypto_traits::kem::{
 };
 use thiserror::Error;
 
+mod capture;
+mod chunks;
+mod clients;
+mod dns;
+mod doctor;
+mod fingerprint;
+mod firewall;
+mod icmp;
+mod keepalive;
+mod listen;
+mod nftables;
+mod obfs;
+mod payload;
+mod peers;
+mod privsep;
+mod provision;
+mod reply;
+mod sandbox;
+mod secrets;
+mod tcp;
+mod ticket;
+mod wgnl;
+
+use capture::Capture;
+use clients::{ClientPolicy, Clients, TargetPolicy};
+use dns::DnsKnocks;
+use fingerprint::Fingerprint;
+use firewall::{Backend, Firewall, Held, Policy, Service};
+use icmp::IcmpKnocks;
+use keepalive::GrantTable;
+use listen::{Listeners, PortRotation};
+use obfs::{Encoding, MaskKey};
+use peers::PeerGate;
+use privsep::Helper;
+use reply::ReplyMode;
+use secrets::{Secret, SecretBytes, Unseal};
+use tcp::TcpKnocks;
+use ticket::{Terms, TicketStore};
+
 type HmacSha256 = Hmac<Sha256>;
 
 // Protocol constants (Kyber/ML-KEM-768)
 const PROTO_VER: u8 = 1;
+// v2 appends an AEAD-sealed payload after client_ip
+const PROTO_VER_PAYLOAD: u8 = 2;
 const NONCE_LEN: usize = 16;
 const TAG_LEN: usize = 32;
 // Kyber768 ciphertext size in bytes (ML-KEM-768)
 const CT_LEN_KYBER768: usize = 1088;
+// ML-KEM-768 decapsulation key layout (FIPS 203): dk_pke(1152) | ek(1184) | H(ek) | z
+const MLKEM768_EK_OFFSET: usize = 1152;
+const MLKEM768_EK_LEN: usize = 1184;
 
 // Replay cache with O(1) membership and TTL-based purge
 struct ReplayCache {
@@ -98,38 +149,250 @@ enum Command {
     },
 
     /// Run SPA daemon
-    Run {
-        /// Listen address, e.g. 0.0.0.0:62201
-        #[arg(long, default_value = "0.0.0.0:62201")]
-        listen: String,
-        /// WireGuard UDP port to open
+    Run(Box<RunArgs>),
+
+    /// Print fingerprints of the KEM public key and PSK for out-of-band checks
+    Fingerprint {
+        /// KEM public key path (raw bytes)
+        #[arg(long, default_value = "/etc/spa/kem_pub.bin")]
+        kem_pub: PathBuf,
+        /// Path to 32-byte PSK file
         #[arg(long)]
-        wg_port: u16,
-        /// KEM private key path
+        psk_file: Option<PathBuf>,
+    },
+
+    /// Seal a KEM private key or PSK with a passphrase or key file
+    SealKey {
+        /// What is sealed
+        #[arg(long, value_enum)]
+        kind: SecretKind,
+        /// Secret to seal (raw, base64 or hex; `cred:NAME` allowed)
+        #[arg(long = "in")]
+        input: PathBuf,
+        /// Sealed output path
         #[arg(long)]
-        kem_priv: PathBuf,
-        /// Path to 32-byte PSK file
+        out: PathBuf,
+        /// File holding the passphrase (Argon2id)
+        #[arg(
+            long,
+            required_unless_present = "key_file",
+            conflicts_with = "key_file"
+        )]
+        passphrase_file: Option<PathBuf>,
+        /// File holding at least 32 random bytes
         #[arg(long)]
-        psk_file: PathBuf,
-        /// Allow window for port opening (seconds)
-        #[arg(long, default_value_t = 45)]
-        open_secs: u64,
-        /// Acceptable time skew for knocks (seconds)
-        #[arg(long, default_value_t = 30)]
-        window_secs: i64,
-        /// nftables family (e.g., inet)
-        #[arg(long, default_value = "inet")]
-        nft_family: String,
-        /// nftables table (e.g., fw4 on OpenWRT)
-        #[arg(long, default_value = "fw4")]
-        nft_table: String,
-        /// nftables set name to receive allowed source IPs
-        #[arg(long, default_value = "wg_spa_allow")]
-        nft_set: String,
-        /// Deprecated: nft chain (old model added elements to <chain>_set)
-        #[arg(long, default_value = "wg_spa_allow")]
-        nft_chain: String,
+        key_file: Option<PathBuf>,
     },
+
+    /// Onboard a device: SPA credential, WireGuard peer and client bundle
+    Provision(Box<provision::ProvisionArgs>),
+
+    /// Check nft objects, keys, clock and ports against the daemon's flags
+    Doctor(Box<doctor::DoctorArgs>),
+
+    /// Firewall half of --privsep; started by `run`, not by hand
+    #[command(hide = true)]
+    PrivsepHelper,
+
+    /// Remove a gated WireGuard peer now (see --peer-gating)
+    RevokePeer {
+        /// WireGuard interface, e.g. wg0
+        #[arg(long)]
+        wg_interface: String,
+        /// Peer public key (base64)
+        #[arg(long)]
+        public_key: String,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum SecretKind {
+    /// ML-KEM-768 private key (--kem-priv)
+    KemPriv,
+    /// 32-byte PSK (--psk-file, `psk_file` in --clients)
+    Psk,
+    /// 32-byte secret the rotating knock ports derive from (--port-secret-file)
+    PortSecret,
+}
+
+impl SecretKind {
+    fn len(self) -> usize {
+        match self {
+            SecretKind::KemPriv => kem::secret_key_bytes(),
+            SecretKind::Psk | SecretKind::PortSecret => 32,
+        }
+    }
+}
+
+/// Whether a grant covers the whole source address or one address and port.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PortGrants {
+    /// Grant the source address; declared ports are ignored
+    Off,
+    /// Grant address and port when the knock declares a port, else the address
+    Allow,
+    /// Deny knocks that do not declare a port
+    Require,
+}
+
+/// What happens to the daemon's grants when it is stopped.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnExit {
+    /// Leave them to time out; a restarted daemon picks them up again
+    Keep,
+    /// Delete every element the daemon added and remove gated peers
+    Flush,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// Listen address, e.g. 0.0.0.0:62201 (repeatable)
+    #[arg(long, default_value = "0.0.0.0:62201")]
+    listen: Vec<String>,
+    /// Only accept knocks arriving on this interface (SO_BINDTODEVICE; repeatable)
+    #[arg(long = "interface")]
+    interfaces: Vec<String>,
+    /// WireGuard UDP port to open
+    #[arg(long)]
+    wg_port: u16,
+    /// KEM private key path (raw, base64, hex or sealed; `cred:NAME` reads
+    /// $CREDENTIALS_DIRECTORY/NAME)
+    #[arg(long)]
+    kem_priv: PathBuf,
+    /// Path to 32-byte PSK file (same forms as --kem-priv)
+    #[arg(long)]
+    psk_file: PathBuf,
+    /// Passphrase for secrets sealed with `seal-key --passphrase-file`
+    #[arg(long)]
+    unseal_passphrase_file: Option<PathBuf>,
+    /// Key file for secrets sealed with `seal-key --key-file`
+    #[arg(long)]
+    unseal_key_file: Option<PathBuf>,
+    /// Allow window for port opening (seconds)
+    #[arg(long, default_value_t = 45)]
+    open_secs: u64,
+    /// Acceptable time skew for knocks (seconds)
+    #[arg(long, default_value_t = 30)]
+    window_secs: i64,
+    /// nftables family (default: that of the table found to gate --wg-port)
+    #[arg(long)]
+    nft_family: Option<String>,
+    /// nftables table, e.g. fw4 on OpenWRT or filter (default: discovered)
+    #[arg(long)]
+    nft_table: Option<String>,
+    /// nftables set name to receive allowed source IPs (default: discovered,
+    /// else wg_spa_allow)
+    #[arg(long)]
+    nft_set: Option<String>,
+    /// Create missing nft tables, sets and gate rules instead of failing
+    #[arg(long)]
+    manage_objects: bool,
+    /// On SIGTERM or SIGINT, keep the daemon's grants or flush them
+    #[arg(long, value_enum, default_value_t = OnExit::Keep)]
+    on_exit: OnExit,
+    /// Bind grants to the WireGuard source port a knock declares
+    #[arg(long, value_enum, default_value_t = PortGrants::Off)]
+    port_grants: PortGrants,
+    /// nftables set (type ipv4_addr . inet_service) for port-bound grants
+    #[arg(long, default_value = "wg_spa_allow_pair")]
+    nft_pair_set: String,
+    /// Which address a valid knock opens, for clients without a --clients entry
+    #[arg(long, value_enum, default_value_t = TargetPolicy::Observed)]
+    target_policy: TargetPolicy,
+    /// Network a `declared` target may lie in, e.g. 192.168.10.0/24 (repeatable)
+    #[arg(long = "delegate")]
+    delegates: Vec<clients::Cidr>,
+    /// Per-client policy file (JSON object keyed by client id)
+    #[arg(long)]
+    clients: Option<PathBuf>,
+    /// Extend grants while the matching peer of this WireGuard interface
+    /// keeps handshaking (polls `wg show <iface> dump`)
+    #[arg(long)]
+    wg_interface: Option<String>,
+    /// How often to poll WireGuard peer state (seconds)
+    #[arg(long, default_value_t = 10)]
+    wg_poll_secs: u64,
+    /// Longest a grant is kept alive after its knock (seconds)
+    #[arg(long, default_value_t = 28800)]
+    keepalive_max_secs: u64,
+    /// Gate the Hysteria2 wrapper port too: knocks may request `wrapper`
+    #[arg(long)]
+    wrap_gating: bool,
+    /// nftables set (type ipv4_addr) that admits sources to the wrapper port
+    #[arg(long, default_value = "wrap_spa_allow")]
+    nft_wrap_set: String,
+    /// Hysteria2 wrapper UDP port, watched for knocks with --wrap-capture-iface
+    #[arg(long)]
+    wrap_port: Option<u16>,
+    /// Capture QUIC-framed knocks sent to --wrap-port on this interface
+    /// (AF_PACKET, like --capture-iface)
+    #[arg(long)]
+    wrap_capture_iface: Option<String>,
+    /// Keep the `wg_peer`s of --clients removed from --wg-interface except
+    /// while their owner holds a grant
+    #[arg(long)]
+    peer_gating: bool,
+    /// Deprecated: nft chain (old model added elements to <chain>_set)
+    #[arg(long)]
+    nft_chain: Option<String>,
+    /// Accepted knock encoding (obfs frames are masked and padded, optionally QUIC-framed)
+    #[arg(long, value_enum, default_value_t = Encoding::Plain)]
+    encoding: Encoding,
+    /// Reply to valid knocks: plaintext OK, encrypted ack, or nothing
+    #[arg(long, value_enum, default_value_t = ReplyMode::Ok)]
+    reply: ReplyMode,
+    /// Rotate the knock port within MIN-MAX per time slot (binds the --listen IP)
+    #[arg(long, value_parser = listen::parse_port_range)]
+    rotate_ports: Option<(u16, u16)>,
+    /// Length of a port rotation slot (seconds)
+    #[arg(long, default_value_t = 60)]
+    rotate_slot_secs: u64,
+    /// 32-byte secret the rotating ports derive from, handed to clients by
+    /// `provision` (same forms as --kem-priv; read with --rotate-ports)
+    #[arg(long, default_value = "/etc/spa/port_secret.bin")]
+    port_secret_file: PathBuf,
+    /// nftables set (type inet_service) that admits the active rotating ports
+    #[arg(long, default_value = "spa_knock_ports")]
+    nft_port_set: String,
+    /// Issue session tickets for compact knocks (requires --reply encrypted)
+    #[arg(long)]
+    tickets: bool,
+    /// Session ticket lifetime (seconds)
+    #[arg(long, default_value_t = 86400)]
+    ticket_secs: u64,
+    /// Also accept length-prefixed knock frames over TCP, e.g. 0.0.0.0:443
+    #[arg(long)]
+    tcp_listen: Option<String>,
+    /// Maximum TCP knock connections in flight
+    #[arg(long, default_value_t = 32)]
+    tcp_max_conns: usize,
+    /// Deadline for a TCP client to deliver its whole frame (milliseconds)
+    #[arg(long, default_value_t = 3000)]
+    tcp_timeout_ms: u64,
+    /// Answer DNS queries carrying knocks on this address, e.g. 0.0.0.0:5354
+    #[arg(long)]
+    dns_listen: Option<String>,
+    /// Zone delegated to the DNS responder, e.g. k.example.net
+    #[arg(long)]
+    dns_zone: Option<String>,
+    /// Also read knocks from ICMP echo requests (raw socket, needs CAP_NET_RAW)
+    #[arg(long)]
+    icmp: bool,
+    /// Capture knocks to --listen on this interface instead of binding a
+    /// socket (AF_PACKET, needs CAP_NET_RAW; replies are never sent)
+    #[arg(long)]
+    capture_iface: Option<String>,
+    /// Change nft sets and WireGuard peers from a separate helper process and
+    /// drop CAP_NET_ADMIN from the one that reads knocks
+    #[arg(long)]
+    privsep: bool,
+    /// Do not confine the daemon with seccomp and Landlock (needed without
+    /// --privsep, where nft and wg run from the daemon itself)
+    #[arg(long)]
+    no_sandbox: bool,
+    /// The only directory the sandboxed daemon may read
+    #[arg(long, default_value = "/etc/spa")]
+    state_dir: PathBuf,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -139,6 +402,16 @@ struct LogLine<'a> {
     decision: &'a str,
     reason: &'a str,
     opens_for_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wg_src_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wg_peer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    services: Vec<&'a str>,
 }
 
 fn now_unix() -> i64 {
@@ -155,21 +428,31 @@ fn read_file(path: &PathBuf) -> Result<Vec<u8>> {
     Ok(b)
 }
 
+/// Writes through a sibling temp file and a rename, so a crash never leaves
+/// a half-written key or config behind.
 fn write_file(path: &PathBuf, data: &[u8], mode: Option<u32>) -> Result<()> {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
     if let Some(m) = mode {
-        // best-effort set umask'd perms after write
-        let mut f = fs::File::create(path).with_context(|| format!("create {}", path.display()))?;
-        f.write_all(data)?;
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            fs::set_permissions(path, fs::Permissions::from_mode(m))?;
-        }
-        Ok(())
-    } else {
-        fs::write(path, data)?;
-        Ok(())
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(m);
     }
+    let mut f = opts
+        .open(&tmp)
+        .with_context(|| format!("create {}", tmp.display()))?;
+    f.write_all(data)?;
+    #[cfg(unix)]
+    if let Some(m) = mode {
+        // the creation mode is umask'd; an existing temp file keeps its own
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(m))?;
+    }
+    f.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("rename onto {}", path.display()))
 }
 
 fn gen_keys(priv_out: PathBuf, pub_out: PathBuf) -> Result<()> {
@@ -184,280 +467,1062 @@ fn gen_keys(priv_out: PathBuf, pub_out: PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn ensure_nft_set(family: &str, table: &str, set_name: &str) -> Result<()> {
-    // Fail-fast verification that the fw4 table and target set exist.
-    let ok_table = std::process::Command::new("nft")
-        .args(["list", "table", family, table])
-        .status()
-        .map(|s| s.success())
-        .unwrap_or(false);
-    let ok_set = std::process::Command::new("nft")
-        .args(["list", "set", family, table, set_name])
-        .status()
-        .map(|s| s.success())
-        .unwrap_or(false);
-    if !(ok_table && ok_set) {
-        return Err(SpaError::NftMissing.into());
+fn print_fingerprints(kem_pub: PathBuf, psk_file: Option<PathBuf>) -> Result<()> {
+    let pk = read_file(&kem_pub)?;
+    if pk.len() != MLKEM768_EK_LEN {
+        return Err(anyhow!(
+            "{} is not an ML-KEM-768 public key",
+            kem_pub.display()
+        ));
+    }
+    let fp = Fingerprint::kem_pub(&pk);
+    println!(
+        "KEM public key  {}\n                {}",
+        fp.hex(),
+        fp.words()
+    );
+    if let Some(path) = psk_file {
+        let psk = secrets::load(&path, 32, &Unseal::default())?;
+        let fp = Fingerprint::psk(psk.expose());
+        println!(
+            "PSK commitment  {}\n                {}",
+            fp.hex(),
+            fp.words()
+        );
     }
     Ok(())
 }
 
-fn add_allow_set_entry(
-    family: &str,
-    table: &str,
-    set_name: &str,
-    client_ip: Ipv4Addr,
-    open_secs: u64,
-) -> Result<()> {
-    // add element to set with timeout
-    let elem = format!("{{ {} timeout {}s }}", client_ip, open_secs);
-    let status = std::process::Command::new("nft")
-        .args(["add", "element", family, table, set_name, &elem])
-        .status()
-        .context("nft add element")?;
-    if !status.success() {
-        return Err(anyhow!("nft add element failed"));
+fn seal_key(kind: SecretKind, input: &Path, out: &PathBuf, unseal: &Unseal) -> Result<()> {
+    let secret = secrets::load(input, kind.len(), &Unseal::default())?;
+    let sealed = secrets::seal(secret.expose(), unseal)?;
+    write_file(out, &sealed, Some(0o600))?;
+    // prove the envelope opens before the plaintext is deleted
+    if secrets::load(out, kind.len(), unseal)?.expose() != secret.expose() {
+        return Err(anyhow!("{} does not unseal to the input", out.display()));
     }
+    eprintln!("sealed {} into {}", input.display(), out.display());
     Ok(())
 }
 
-// delete_rule_by_comment: removed; daemon does not mutate nft rules beyond adding elements
+/// The ML-KEM-768 secret key embeds the public key; the obfuscation mask key
+/// is derived from it.
+fn kem_pub_of_secret(kem_priv: &[u8]) -> Option<&[u8]> {
+    kem_priv.get(MLKEM768_EK_OFFSET..MLKEM768_EK_OFFSET + MLKEM768_EK_LEN)
+}
 
-#[allow(clippy::too_many_arguments)]
-fn run_daemon(
-    listen: String,
-    wg_port: u16,
-    kem_priv: PathBuf,
-    psk_file: PathBuf,
-    open_secs: u64,
+// Per-source token bucket plus a global per-second cap
+struct RateLimiter {
+    buckets: HashMap<Ipv4Addr, (u32, Instant)>,
+    per_src_capacity: u32,
+    global_capacity: u32,
+    global_tokens: u32,
+    last_global_refill: Instant,
+}
+
+impl RateLimiter {
+    const MAX_BUCKETS: usize = 8192;
+
+    fn new(per_src_capacity: u32, global_capacity: u32) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            per_src_capacity,
+            global_capacity,
+            global_tokens: global_capacity,
+            last_global_refill: Instant::now(),
+        }
+    }
+
+    fn allow(&mut self, ip: Ipv4Addr) -> bool {
+        // Refill global tokens every second
+        if self.last_global_refill.elapsed() >= Duration::from_secs(1) {
+            self.global_tokens = self.global_capacity;
+            self.last_global_refill = Instant::now();
+            // Opportunistic prune to bound memory
+            if self.buckets.len() > Self::MAX_BUCKETS {
+                let cutoff = Instant::now() - Duration::from_secs(10);
+                self.buckets.retain(|_, v| v.1 >= cutoff);
+            }
+        }
+        if self.global_tokens == 0 {
+            return false;
+        }
+        self.global_tokens -= 1;
+        let entry = self
+            .buckets
+            .entry(ip)
+            .or_insert((self.per_src_capacity, Instant::now()));
+        if entry.1.elapsed() >= Duration::from_secs(1) {
+            entry.0 = self.per_src_capacity;
+            entry.1 = Instant::now();
+        }
+        if entry.0 == 0 {
+            return false;
+        }
+        entry.0 -= 1;
+        true
+    }
+}
+
+/// Keys, policy and state shared by every knock transport.
+struct Daemon {
+    kem_priv: SecretBytes,
+    psk: SecretBytes,
+    mask_key: MaskKey,
+    encoding: Encoding,
+    reply_mode: ReplyMode,
     window_secs: i64,
-    nft_family: String,
-    nft_table: String,
-    nft_set: String,
-) -> Result<()> {
-    let sock = UdpSocket::bind(&listen).with_context(|| format!("bind {}", listen))?;
-    sock.set_read_timeout(Some(Duration::from_millis(500)))?;
+    open_secs: u64,
+    fw: Firewall,
+    port_grants: PortGrants,
+    replay_cache: ReplayCache,
+    limiter: RateLimiter,
+    tickets: Option<TicketStore>,
+    clients: Clients,
+    wg_interface: Option<String>,
+    keepalive_max_secs: u64,
+    grants: GrantTable,
+    peer_gate: Option<PeerGate>,
+    wrap_gating: bool,
+    wrap_grants: HashMap<Ipv4Addr, i64>,
+}
 
-    let kem_priv_bytes = read_file(&kem_priv)?;
-    let psk = read_file(&psk_file)?;
-    if psk.len() != 32 {
-        return Err(anyhow!("PSK must be 32 bytes"));
+/// How a knock reached the daemon.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Via {
+    /// Straight from the client (UDP or TCP); `src` is the client
+    Direct,
+    /// Relayed by a DNS resolver; `src` is the resolver's egress address
+    Resolver,
+    /// Captured on the Hysteria2 wrapper port; always QUIC-framed
+    Wrapper,
+}
+
+/// Outcome of a valid knock, used by the caller to build the reply.
+struct Accepted {
+    ack_key: Secret<[u8; 32]>,
+    ticket_secret: Option<Secret<[u8; 32]>>,
+    terms: Terms,
+}
+
+impl Daemon {
+    /// Rate-limit, decode, verify and grant one datagram. Returns the reply
+    /// to send, if any.
+    fn process(&mut self, dgram: &[u8], src: SocketAddrV4, via: Via) -> Option<Vec<u8>> {
+        if !self.limiter.allow(*src.ip()) {
+            return None;
+        }
+        // a plain knock would stand out among QUIC on the wrapper port
+        let encoding = match via {
+            Via::Wrapper => Encoding::Obfs,
+            _ => self.encoding,
+        };
+        let res = obfs::decode_for(dgram, encoding, &self.mask_key)
+            .map_err(anyhow::Error::from)
+            .and_then(|knock| self.handle_packet(&knock, src, via));
+        match res {
+            Err(e) => {
+                // best-effort deny log
+                let line = LogLine {
+                    ts: now_unix(),
+                    client_ip: &src.ip().to_string(),
+                    decision: "deny",
+                    reason: reason_of(&e),
+                    opens_for_secs: 0,
+                    client_id: None,
+                    target: None,
+                    wg_src_port: None,
+                    wg_peer: None,
+                    services: Vec::new(),
+                };
+                println!("{}", serde_json::to_string(&line).unwrap_or_default());
+                None
+            }
+            Ok(accepted) => self.reply_for(accepted),
+        }
     }
 
-    // reconstruct secret key
-    let sk = <kem::SecretKey as SkTrait>::from_bytes(&kem_priv_bytes)
-        .map_err(|_| anyhow!("invalid KEM private key"))?;
+    fn reply_for(&mut self, accepted: Accepted) -> Option<Vec<u8>> {
+        let mut ack = reply::Ack {
+            grant_secs: accepted.terms.grant_secs,
+            ..Default::default()
+        };
+        // Tickets only make sense when the client can read them
+        if let (Some(store), Some(secret), ReplyMode::Encrypted) = (
+            self.tickets.as_mut(),
+            accepted.ticket_secret,
+            self.reply_mode,
+        ) {
+            let id = store.issue(secret.expose(), accepted.terms, Instant::now());
+            if let Some(id) = id {
+                ack.ticket = Some(STANDARD.encode(id));
+                ack.ticket_secs = Some(store.lifetime().as_secs());
+            }
+        }
+        reply::build(self.reply_mode, accepted.ack_key.expose(), &ack)
+    }
+
+    fn handle_packet(&mut self, pkt: &[u8], src: SocketAddrV4, via: Via) -> Result<Accepted> {
+        match pkt.first() {
+            Some(&ticket::PROTO_VER_COMPACT) => self.handle_compact(pkt, src),
+            _ => self.handle_full(pkt, src, via),
+        }
+    }
+
+    fn check_fresh(&mut self, src_ip: Ipv4Addr, nonce: &[u8], ts: i64) -> Result<()> {
+        // time window check
+        if (now_unix() - ts).abs() > self.window_secs {
+            return Err(SpaError::StaleTs.into());
+        }
+        // Replay protection: reject duplicate (src_ip, nonce, ts) within window
+        let now_instant = Instant::now();
+        self.replay_cache.purge_expired(now_instant);
+        let mut nonce_arr = [0u8; NONCE_LEN];
+        nonce_arr.copy_from_slice(nonce);
+        if self
+            .replay_cache
+            .seen_or_insert(src_ip, nonce_arr, ts, now_instant)
+        {
+            return Err(SpaError::Replay.into());
+        }
+        Ok(())
+    }
 
-    ensure_nft_set(&nft_family, &nft_table, &nft_set)?;
+    fn handle_full(&mut self, pkt: &[u8], src: SocketAddrV4, via: Via) -> Result<Accepted> {
+        // Packet v1: u8 ver | u16 ct_len | ct | 16 nonce | i64 ts | u32 client_ip | 32 tag
+        // Packet v2: ... | u32 client_ip | u16 sealed_len | sealed payload | 32 tag
+        if pkt.len() < 1 + 2 + NONCE_LEN + 8 + 4 + TAG_LEN {
+            return Err(SpaError::PacketTooShort.into());
+        }
+        let ver = pkt[0];
+        if ver != PROTO_VER && ver != PROTO_VER_PAYLOAD {
+            return Err(SpaError::BadVer.into());
+        }
+        let ct_len = u16::from_be_bytes([pkt[1], pkt[2]]) as usize;
+        let fixed = 1 + 2 + ct_len + NONCE_LEN + 8 + 4;
+        let sealed_len = if ver == PROTO_VER_PAYLOAD {
+            if pkt.len() < fixed + 2 {
+                return Err(SpaError::LengthMismatch.into());
+            }
+            u16::from_be_bytes([pkt[fixed], pkt[fixed + 1]]) as usize
+        } else {
+            0
+        };
+        let need = if ver == PROTO_VER_PAYLOAD {
+            fixed + 2 + sealed_len + TAG_LEN
+        } else {
+            fixed + TAG_LEN
+        };
+        if pkt.len() != need {
+            return Err(SpaError::LengthMismatch.into());
+        }
+        // Enforce Kyber768 ciphertext length strictly
+        if ct_len != CT_LEN_KYBER768 {
+            return Err(SpaError::BadCtLen.into());
+        }
+        if sealed_len > payload::MAX_SEALED_LEN {
+            return Err(SpaError::PayloadInvalid.into());
+        }
+        let mut off = 3;
+        let ct = &pkt[off..off + ct_len];
+        off += ct_len;
+        let header_start = off;
+        let nonce = &pkt[off..off + NONCE_LEN];
+        off += NONCE_LEN;
+        let ts = i64::from_be_bytes(pkt[off..off + 8].try_into().unwrap());
+        off += 8;
+        let ip_raw = u32::from_be_bytes(pkt[off..off + 4].try_into().unwrap());
+        off += 4;
+        let header_end = off;
+        let sealed = if ver == PROTO_VER_PAYLOAD {
+            off += 2;
+            let s = &pkt[off..off + sealed_len];
+            off += sealed_len;
+            Some(s)
+        } else {
+            None
+        };
+        let tag = &pkt[off..off + TAG_LEN];
 
-    // Maintain a replay cache of (src_ip, nonce, ts) with TTL=window_secs
-    let mut replay_cache = ReplayCache::new(Duration::from_secs(window_secs as u64), 4096);
+        let src_ip = *src.ip();
+        self.check_fresh(src_ip, nonce, ts)?;
 
-    // Simple rate limiter: per-source token bucket + global cap
-    let mut buckets: HashMap<Ipv4Addr, (u32, Instant)> = HashMap::new();
-    let per_src_capacity: u32 = 20;
-    let mut global_tokens: u32 = 200;
-    let mut last_global_refill = Instant::now();
-    const MAX_BUCKETS: usize = 8192;
+        // decapsulate
+        let ct_obj =
+            <kem::Ciphertext as CtTrait>::from_bytes(ct).map_err(|_| SpaError::DecapFailed)?;
+        let shared = self.decapsulate(&ct_obj)?;
+        let key = shared.expose();
 
-    let mut buf = [0u8; 4096];
-    loop {
-        match sock.recv_from(&mut buf) {
-            Ok((n, src)) => {
-                if let SocketAddr::V4(src_v4) = src {
-                    // Refill global tokens every second
-                    if last_global_refill.elapsed() >= Duration::from_secs(1) {
-                        global_tokens = 200;
-                        last_global_refill = Instant::now();
-                        // Opportunistic prune to bound memory
-                        if buckets.len() > MAX_BUCKETS {
-                            let cutoff = Instant::now() - Duration::from_secs(10);
-                            buckets.retain(|_, v| v.1 >= cutoff);
+        // HMAC: constant-time verify over PSK || ver || nonce || ts
+        // (v2 additionally covers client_ip and the sealed payload).
+        // The shared PSK first, then each device's own.
+        let psks = std::iter::once((None, self.psk.expose().as_slice()))
+            .chain(self.clients.psks().map(|(id, k)| (Some(id), k)));
+        let mut matched = None;
+        for (device, psk) in psks {
+            let mut mac = HmacSha256::new_from_slice(key).map_err(|_| SpaError::HmacKey)?;
+            mac.update(psk);
+            mac.update(&[ver]);
+            mac.update(nonce);
+            mac.update(&ts.to_be_bytes());
+            if let Some(sealed) = sealed {
+                mac.update(&ip_raw.to_be_bytes());
+                mac.update(&(sealed.len() as u16).to_be_bytes());
+                mac.update(sealed);
+            }
+            if mac.verify_slice(tag).is_ok() {
+                matched = Some((device.map(str::to_string), Secret::new(psk.to_vec())));
+                break;
+            }
+        }
+        let (device, psk) = matched.ok_or(SpaError::BadHmac)?;
+
+        // v2: decrypt and validate the payload (AAD binds it to the header)
+        let knock = match sealed {
+            Some(sealed) => {
+                let mut aad = Vec::with_capacity(1 + header_end - header_start);
+                aad.push(ver);
+                aad.extend_from_slice(&pkt[header_start..header_end]);
+                Some(payload::open(key, psk.expose(), nonce, &aad, sealed)?)
+            }
+            None => None,
+        };
+        // a device PSK speaks for its own client id only, and only it does
+        match (&device, knock.as_ref().map(|p| p.client_id.as_str())) {
+            (Some(d), Some(id)) if d == id => {}
+            (Some(_), _) => return Err(SpaError::ClientMismatch.into()),
+            (None, Some(id)) if self.clients.has_psk(id) => {
+                return Err(SpaError::ClientMismatch.into())
+            }
+            _ => {}
+        }
+        let grant_secs = knock
+            .as_ref()
+            .map(|p| p.grant_secs(self.open_secs))
+            .unwrap_or(self.open_secs);
+        let client_id = knock.as_ref().map(|p| p.client_id.clone());
+        let target = knock.as_ref().and_then(|p| p.target);
+        // only v2 authenticates client_ip
+        let claimed = knock.as_ref().map(|_| Ipv4Addr::from(ip_raw));
+        let policy = self.clients.policy(client_id.as_deref());
+        let granted = match (via, target) {
+            // a resolver's address is shared; only a delegated target stands in
+            (Via::Resolver, Some(t)) => policy.relayed_addr(t)?,
+            _ => policy.grant_addr(src_ip, target, claimed)?,
+        };
+        let reason = if granted != src_ip {
+            "ok_delegated"
+        } else if ip_raw != u32::from_be_bytes(src_ip.octets()) {
+            "ok_nat_mismatch"
+        } else {
+            "ok"
+        };
+        // no services named: whatever the knock's path leads to
+        let (open_wg, open_wrapper) = match knock.as_ref().map(|p| &p.services) {
+            Some(s) if !s.is_empty() => (
+                s.iter().any(|s| s == "wg"),
+                s.iter().any(|s| s == "wrapper"),
+            ),
+            _ => (via != Via::Wrapper, via == Via::Wrapper),
+        };
+        let terms = Terms {
+            client_id,
+            grant_secs,
+            wg_src_port: self.grant_port(knock.as_ref().and_then(|p| p.wg_src_port))?,
+            target,
+            granted: Some(granted),
+            open_wg,
+            open_wrapper,
+        };
+        self.grant(granted, reason, &terms)?;
+
+        Ok(Accepted {
+            ack_key: reply::ack_key(key, psk.expose()),
+            ticket_secret: Some(ticket::ticket_secret(key, psk.expose())),
+            terms,
+        })
+    }
+
+    /// ML-KEM decapsulation with the stored key. pqcrypto's key and secret
+    /// types cannot be wiped, so they only live for this call.
+    fn decapsulate(&self, ct: &kem::Ciphertext) -> Result<Secret<[u8; 32]>, SpaError> {
+        let sk = <kem::SecretKey as SkTrait>::from_bytes(self.kem_priv.expose())
+            .map_err(|_| SpaError::DecapFailed)?;
+        let mut key = Secret::new([0u8; 32]);
+        key.expose_mut()
+            .copy_from_slice(SsTrait::as_bytes(&kem::decapsulate(ct, &sk)));
+        Ok(key)
+    }
+
+    fn handle_compact(&mut self, pkt: &[u8], src: SocketAddrV4) -> Result<Accepted> {
+        let knock = ticket::parse_compact(pkt)?;
+        let src_ip = *src.ip();
+        self.check_fresh(src_ip, knock.nonce, knock.ts)?;
+        let store = self.tickets.as_ref().ok_or(SpaError::UnknownTicket)?;
+        let ticket = store
+            .get(&knock.ticket_id, Instant::now())
+            .ok_or(SpaError::UnknownTicket)?;
+        knock.verify(ticket)?;
+        let terms = ticket.terms.clone();
+        // no KEM secret here: key the ack from the ticket, salted by the nonce
+        let ack_key = reply::ack_key(ticket.secret(), knock.nonce);
+        // the address granted to the full knock is what a `match` client claimed
+        let granted = self.clients.policy(terms.client_id.as_deref()).grant_addr(
+            src_ip,
+            terms.target,
+            terms.granted,
+        )?;
+        self.grant_port(terms.wg_src_port)?;
+        self.grant(granted, "ok_ticket", &terms)?;
+        Ok(Accepted {
+            ack_key,
+            ticket_secret: None,
+            terms,
+        })
+    }
+
+    /// The source port to bind a grant to under --port-grants, if any.
+    fn grant_port(&self, declared: Option<u16>) -> Result<Option<u16>> {
+        match self.port_grants {
+            PortGrants::Off => Ok(None),
+            PortGrants::Allow => Ok(declared),
+            PortGrants::Require => Ok(Some(declared.ok_or(SpaError::PortRequired)?)),
+        }
+    }
+
+    fn grant(&mut self, ip: Ipv4Addr, reason: &str, terms: &Terms) -> Result<()> {
+        if terms.open_wrapper && !self.wrap_gating {
+            return Err(SpaError::ServiceUnavailable.into());
+        }
+        // the peer goes first: a grant without it would be useless
+        let policy = self.clients.policy(terms.client_id.as_deref());
+        if let (Some(gate), Some(peer)) = (self.peer_gate.as_mut(), &policy.wg_peer) {
+            let until = now_unix() + terms.grant_secs as i64;
+            if gate.enable(&mut self.fw, peer, ip, terms.client_id.as_deref(), until)? {
+                log_peer(
+                    "enable",
+                    ip,
+                    terms.client_id.as_deref(),
+                    &peer.public_key,
+                    terms.grant_secs,
+                );
+            }
+        }
+        // insert allow set element for the ip (and port) with timeout
+        if terms.open_wg {
+            self.fw.grant(
+                Service::Wg,
+                ip,
+                terms.wg_src_port,
+                terms.grant_secs,
+                terms.client_id.as_deref(),
+            )?;
+        }
+        if terms.open_wrapper {
+            self.fw.grant(
+                Service::Wrapper,
+                ip,
+                None,
+                terms.grant_secs,
+                terms.client_id.as_deref(),
+            )?;
+            self.wrap_grants
+                .insert(ip, now_unix() + terms.grant_secs as i64);
+        }
+        let mut services = Vec::new();
+        if terms.open_wg {
+            services.push("wg");
+        }
+        if terms.open_wrapper {
+            services.push("wrapper");
+        }
+
+        // log allow
+        let line = LogLine {
+            ts: now_unix(),
+            client_ip: &ip.to_string(),
+            decision: "allow",
+            reason,
+            opens_for_secs: terms.grant_secs,
+            client_id: terms.client_id.as_deref(),
+            target: terms.target.map(|t| t.to_string()),
+            wg_src_port: terms.wg_src_port,
+            wg_peer: None,
+            services,
+        };
+        println!("{}", serde_json::to_string(&line).unwrap_or_default());
+        // wrapper sessions reach WireGuard from localhost; only wg grants match
+        if self.wg_interface.is_some() && terms.open_wg {
+            let policy = self.clients.policy(terms.client_id.as_deref());
+            let max_secs = policy.keepalive_max_secs.unwrap_or(self.keepalive_max_secs);
+            let wg_peer = policy.wg_peer.as_ref().map(|p| p.public_key.clone());
+            self.grants.record(
+                ip,
+                terms.wg_src_port,
+                terms.client_id.clone(),
+                wg_peer,
+                terms.grant_secs,
+                max_secs,
+                now_unix(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Track grants a previous run left in the sets, as if just granted:
+    /// keepalive, peer gating and wrapper re-knocks carry on where it stopped.
+    fn restore(&mut self, held: Vec<Held>, now: i64) -> Result<()> {
+        for h in held {
+            let policy = self.clients.policy(h.client_id.as_deref());
+            let until = now + h.secs as i64;
+            match h.service {
+                Service::Wg => {
+                    if let (Some(gate), Some(peer)) = (self.peer_gate.as_mut(), &policy.wg_peer) {
+                        if gate.enable(&mut self.fw, peer, h.ip, h.client_id.as_deref(), until)? {
+                            log_peer(
+                                "enable",
+                                h.ip,
+                                h.client_id.as_deref(),
+                                &peer.public_key,
+                                h.secs,
+                            );
                         }
                     }
-                    if global_tokens == 0 {
-                        continue;
-                    }
-                    global_tokens -= 1;
-                    let entry = buckets
-                        .entry(*src_v4.ip())
-                        .or_insert((per_src_capacity, Instant::now()));
-                    if entry.1.elapsed() >= Duration::from_secs(1) {
-                        entry.0 = per_src_capacity;
-                        entry.1 = Instant::now();
-                    }
-                    if entry.0 == 0 {
-                        continue;
-                    }
-                    entry.0 -= 1;
-                    let res = handle_packet(
-                        &buf[..n],
-                        src_v4,
-                        &sk,
-                        &psk,
-                        window_secs,
-                        open_secs,
-                        &nft_family,
-                        &nft_table,
-                        &nft_set,
-                        wg_port,
-                        &mut replay_cache,
-                    );
-                    if let Err(e) = res {
-                        // best-effort deny log
-                        let line = LogLine {
-                            ts: now_unix(),
-                            client_ip: &src_v4.ip().to_string(),
-                            decision: "deny",
-                            reason: reason_of(&e),
-                            opens_for_secs: 0,
-                        };
-                        println!("{}", serde_json::to_string(&line).unwrap_or_default());
-                    } else {
-                        let _ = sock.send_to(b"OK", src);
+                    if self.wg_interface.is_some() {
+                        let max_secs = policy.keepalive_max_secs.unwrap_or(self.keepalive_max_secs);
+                        self.grants.record(
+                            h.ip,
+                            h.port,
+                            h.client_id.clone(),
+                            policy.wg_peer.as_ref().map(|p| p.public_key.clone()),
+                            h.secs,
+                            max_secs,
+                            now,
+                        );
                     }
                 }
+                Service::Wrapper => {
+                    self.wrap_grants.insert(h.ip, until);
+                }
+            }
+            let line = LogLine {
+                ts: now,
+                client_ip: &h.ip.to_string(),
+                decision: "restore",
+                reason: "startup",
+                opens_for_secs: h.secs,
+                client_id: h.client_id.as_deref(),
+                target: None,
+                wg_src_port: h.port,
+                wg_peer: None,
+                services: vec![match h.service {
+                    Service::Wg => "wg",
+                    Service::Wrapper => "wrapper",
+                }],
+            };
+            println!("{}", serde_json::to_string(&line).unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// SIGTERM or SIGINT: leave the grants, or take back what was granted.
+    fn shutdown(&mut self, on_exit: OnExit) -> Result<()> {
+        match on_exit {
+            OnExit::Keep => {
+                eprintln!("exiting; grants stay until they time out");
+                Ok(())
+            }
+            OnExit::Flush => {
+                self.expire_peers(i64::MAX);
+                self.fw.flush().context("flush grants")?;
+                eprintln!("exiting; grants flushed");
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether `ip` holds a live wrapper grant.
+    fn wrap_granted(&mut self, ip: Ipv4Addr, now: i64) -> bool {
+        self.wrap_grants.retain(|_, until| *until > now);
+        self.wrap_grants.contains_key(&ip)
+    }
+
+    /// Remove gated peers whose grant ran out (--peer-gating).
+    fn expire_peers(&mut self, now: i64) {
+        let Some(gate) = self.peer_gate.as_mut() else {
+            return;
+        };
+        for d in gate.expire(&mut self.fw, now) {
+            log_peer("disable", d.ip, d.client_id.as_deref(), &d.public_key, 0);
+        }
+    }
+
+    /// Extend grants whose WireGuard peer has a live session (--wg-interface).
+    fn keepalive(&mut self, now: i64) -> Result<()> {
+        if self.wg_interface.is_none() {
+            return Ok(());
+        }
+        let peers = self.fw.wg_dump()?;
+        for ext in self.grants.extend(&peers, self.open_secs, now) {
+            if let Err(e) = self
+                .fw
+                .extend(ext.ip, ext.port, ext.secs, ext.client_id.as_deref())
+            {
+                eprintln!("extend grant {}: {:#}", ext.ip, e);
+                continue;
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // brief sleep to avoid busy loop
-                thread::sleep(Duration::from_millis(1));
+            if let Some(gate) = self.peer_gate.as_mut() {
+                gate.extend(&ext.peer, now + ext.secs as i64);
             }
-            Err(e) => return Err(anyhow!("socket error: {}", e)),
+            let line = LogLine {
+                ts: now,
+                client_ip: &ext.ip.to_string(),
+                decision: "extend",
+                reason: if ext.new_session {
+                    "wg_session"
+                } else {
+                    "wg_keepalive"
+                },
+                opens_for_secs: ext.secs,
+                client_id: ext.client_id.as_deref(),
+                target: None,
+                wg_src_port: ext.port,
+                wg_peer: Some(&ext.peer),
+                services: Vec::new(),
+            };
+            println!("{}", serde_json::to_string(&line).unwrap_or_default());
         }
+        Ok(())
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-fn handle_packet(
-    pkt: &[u8],
-    src: SocketAddrV4,
-    sk: &kem::SecretKey,
-    psk: &[u8],
-    window_secs: i64,
-    open_secs: u64,
-    nft_family: &str,
-    nft_table: &str,
-    nft_set: &str,
-    _wg_port: u16,
-    // replay cache shared from caller
-    replay_cache: &mut ReplayCache,
-) -> Result<()> {
-    // Packet v1: u8 ver | u16 ct_len | ct | 16 nonce | i64 ts | u32 client_ip | 32 tag
-    if pkt.len() < 1 + 2 + NONCE_LEN + 8 + 4 + TAG_LEN {
-        return Err(SpaError::PacketTooShort.into());
-    }
-    let ver = pkt[0];
-    if ver != PROTO_VER {
-        return Err(SpaError::BadVer.into());
-    }
-    let ct_len = u16::from_be_bytes([pkt[1], pkt[2]]) as usize;
-    let need = 1 + 2 + ct_len + 16 + 8 + 4 + 32;
-    if pkt.len() != need {
-        return Err(SpaError::LengthMismatch.into());
-    }
-    // Enforce Kyber768 ciphertext length strictly
-    if ct_len != CT_LEN_KYBER768 {
-        return Err(SpaError::BadCtLen.into());
-    }
-    let mut off = 3;
-    let ct = &pkt[off..off + ct_len];
-    off += ct_len;
-    let nonce = &pkt[off..off + NONCE_LEN];
-    off += NONCE_LEN;
-    let ts = i64::from_be_bytes(pkt[off..off + 8].try_into().unwrap());
-    off += 8;
-    let ip_raw = u32::from_be_bytes(pkt[off..off + 4].try_into().unwrap());
-    off += 4;
-    let tag = &pkt[off..off + TAG_LEN];
-
-    // time window check
-    let now = now_unix();
-    if (now - ts).abs() > window_secs {
-        return Err(SpaError::StaleTs.into());
-    }
-    // Replay protection: reject duplicate (src_ip, nonce, ts) within window
-    let src_ip = *src.ip();
-    let now_instant = Instant::now();
-    replay_cache.purge_expired(now_instant);
-    let mut nonce_arr = [0u8; NONCE_LEN];
-    nonce_arr.copy_from_slice(nonce);
-    if replay_cache.seen_or_insert(src_ip, nonce_arr, ts, now_instant) {
-        return Err(SpaError::Replay.into());
-    }
-
-    // decapsulate
-    let ct_obj = <kem::Ciphertext as CtTrait>::from_bytes(ct).map_err(|_| SpaError::DecapFailed)?;
-    let shared = kem::decapsulate(&ct_obj, sk);
-    let key = SsTrait::as_bytes(&shared);
-
-    // HMAC: constant-time verify over PSK || nonce || ts
-    let mut mac = HmacSha256::new_from_slice(key).map_err(|_| SpaError::HmacKey)?;
-    mac.update(psk);
-    mac.update(&[PROTO_VER]);
-    mac.update(nonce);
-    mac.update(&ts.to_be_bytes());
-    mac.verify_slice(tag).map_err(|_| SpaError::BadHmac)?;
-
-    // insert allow set element for src ip with timeout
-    add_allow_set_entry(nft_family, nft_table, nft_set, src_ip, open_secs)?;
-
-    // log allow
+fn log_peer(decision: &str, ip: Ipv4Addr, client_id: Option<&str>, peer: &str, secs: u64) {
     let line = LogLine {
-        ts: now,
-        client_ip: &src_ip.to_string(),
-        decision: "allow",
-        reason: if ip_raw != u32::from_be_bytes(src_ip.octets()) {
-            "ok_nat_mismatch"
+        ts: now_unix(),
+        client_ip: &ip.to_string(),
+        decision,
+        reason: "peer_gating",
+        opens_for_secs: secs,
+        client_id,
+        target: None,
+        wg_src_port: None,
+        wg_peer: Some(peer),
+        services: Vec::new(),
+    };
+    println!("{}", serde_json::to_string(&line).unwrap_or_default());
+}
+
+/// UDP ports a rotating knock port must not land on, with their flags.
+fn udp_ports_taken(args: &RunArgs) -> Vec<(u16, &'static str)> {
+    let port_of = |addr: &Option<String>| {
+        addr.as_deref()
+            .and_then(|a| a.parse::<SocketAddr>().ok())
+            .map(|a| a.port())
+    };
+    let mut taken = vec![(args.wg_port, "--wg-port")];
+    taken.extend(args.wrap_port.map(|p| (p, "--wrap-port")));
+    taken.extend(port_of(&args.dns_listen).map(|p| (p, "--dns-listen")));
+    taken
+}
+
+fn run_daemon(args: RunArgs) -> Result<()> {
+    if !args.privsep && !args.no_sandbox {
+        return Err(anyhow!(
+            "the sandbox needs --privsep (nft and wg cannot run under it); pass --no-sandbox to run without"
+        ));
+    }
+    let unseal = Unseal {
+        passphrase_file: args.unseal_passphrase_file.clone(),
+        key_file: args.unseal_key_file.clone(),
+    };
+    let kem_priv = secrets::load(&args.kem_priv, SecretKind::KemPriv.len(), &unseal)?;
+    let psk = secrets::load(&args.psk_file, SecretKind::Psk.len(), &unseal)?;
+    // after loading: unsealing may need far more than RLIMIT_MEMLOCK
+    if let Err(e) = secrets::lock_memory() {
+        eprintln!(
+            "memory not locked ({}); grant CAP_IPC_LOCK or raise LimitMEMLOCK=",
+            e
+        );
+    }
+
+    // check the secret key once; it is rebuilt per knock (Daemon::decapsulate)
+    <kem::SecretKey as SkTrait>::from_bytes(kem_priv.expose())
+        .map_err(|_| anyhow!("invalid KEM private key"))?;
+    let kem_pub = kem_pub_of_secret(kem_priv.expose())
+        .ok_or_else(|| anyhow!("invalid KEM private key"))?
+        .to_vec();
+    let rotation = match args.rotate_ports {
+        Some(range) => {
+            listen::check_port_range(range, &udp_ports_taken(&args))?;
+            let secret = secrets::load(
+                &args.port_secret_file,
+                SecretKind::PortSecret.len(),
+                &unseal,
+            )?;
+            Some(PortRotation::new(
+                secret.expose(),
+                range,
+                args.rotate_slot_secs,
+            ))
+        }
+        None => None,
+    };
+
+    let chain_set = args.nft_chain.as_ref().map(|chain| {
+        eprintln!("--nft-chain is deprecated; pass --nft-set {}_set", chain);
+        format!("{}_set", chain)
+    });
+    let ruleset = nftables::list_ruleset()?;
+    let layout = nftables::locate(
+        &ruleset,
+        &nftables::Want {
+            family: args.nft_family.as_deref(),
+            table: args.nft_table.as_deref(),
+            set: args.nft_set.as_deref().or(chain_set.as_deref()),
+            wg_port: args.wg_port,
+        },
+        args.manage_objects,
+    )?;
+    eprintln!(
+        "nft: grants go to {} {} set {} ({})",
+        layout.family, layout.table, layout.set, layout.reason
+    );
+    let default_policy = ClientPolicy {
+        target: args.target_policy,
+        delegate: args.delegates.clone(),
+        ..Default::default()
+    };
+    let clients = match &args.clients {
+        Some(path) => Clients::load(path, default_policy, &unseal)?,
+        None => Clients::new(default_policy),
+    };
+    if args.peer_gating && args.wg_interface.is_none() {
+        return Err(anyhow!("--peer-gating requires --wg-interface"));
+    }
+    // everything the firewall may be asked for is known now, before any knock
+    let port_ttl = rotation.as_ref().map(|r| r.slot_secs() * 4);
+    let policy = Policy {
+        family: layout.family,
+        table: layout.table,
+        wg_set: layout.set,
+        pair_set: (args.port_grants != PortGrants::Off).then(|| args.nft_pair_set.clone()),
+        wrap_set: args.wrap_gating.then(|| args.nft_wrap_set.clone()),
+        // captured knock ports stay closed, so only bound rotating ports need the set
+        port_set: port_ttl
+            .filter(|_| args.capture_iface.is_none())
+            .map(|_| args.nft_port_set.clone()),
+        wg_interface: args.wg_interface.clone(),
+        peers: if args.peer_gating {
+            clients.peers().cloned().collect()
         } else {
-            "ok"
+            Vec::new()
         },
-        opens_for_secs: open_secs,
+        max_ttl: args.open_secs.max(port_ttl.unwrap_or(0)),
     };
-    println!("{}", serde_json::to_string(&line).unwrap_or_default());
+    nftables::ensure(
+        &ruleset,
+        &policy,
+        args.wg_port,
+        args.wrap_port,
+        args.manage_objects,
+    )?;
+    let (held, foreign) = policy.held(&nftables::Ruleset::parse(
+        &ruleset,
+        &policy.family,
+        &policy.table,
+    )?);
+    if foreign > 0 {
+        eprintln!(
+            "nft: {} element(s) without a \"{}\" comment; left alone",
+            foreign,
+            firewall::COMMENT
+        );
+    }
+    let mut fw = if args.privsep {
+        Firewall::Privsep(Helper::spawn(&policy)?)
+    } else {
+        Firewall::Direct(Backend::new(policy))
+    };
+    let peer_gate = if args.peer_gating {
+        let mut gate = PeerGate::default();
+        gate.disable_all(&mut fw, clients.peers())?;
+        Some(gate)
+    } else {
+        None
+    };
+    if args.tickets && args.reply != ReplyMode::Encrypted {
+        return Err(anyhow!("--tickets requires --reply encrypted"));
+    }
+    let (mut listeners, mut capture) = match &args.capture_iface {
+        Some(iface) => {
+            let [listen] = args.listen.as_slice() else {
+                return Err(anyhow!("--capture-iface takes a single --listen"));
+            };
+            let listen: SocketAddr = listen
+                .parse()
+                .with_context(|| format!("parse listen address {}", listen))?;
+            (None, Some(Capture::open(iface, listen, rotation)?))
+        }
+        None => (
+            Some(Listeners::new(&args.listen, &args.interfaces, rotation)?),
+            None,
+        ),
+    };
+    let mut wrap_capture = match (&args.wrap_capture_iface, args.wrap_port) {
+        (None, _) => None,
+        (Some(_), _) if !args.wrap_gating => {
+            return Err(anyhow!("--wrap-capture-iface requires --wrap-gating"))
+        }
+        (Some(iface), Some(port)) => Some(Capture::open(
+            iface,
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+            None,
+        )?),
+        (Some(_), None) => return Err(anyhow!("--wrap-capture-iface needs --wrap-port")),
+    };
+    let mut dns = match (&args.dns_listen, &args.dns_zone) {
+        (Some(addr), Some(zone)) => Some(DnsKnocks::bind(addr, zone)?),
+        (None, None) => None,
+        _ => return Err(anyhow!("--dns-listen and --dns-zone go together")),
+    };
+    let mut icmp = if args.icmp {
+        Some(IcmpKnocks::open()?)
+    } else {
+        None
+    };
+    let mut tcp = match &args.tcp_listen {
+        Some(addr) => Some(TcpKnocks::bind(
+            addr,
+            args.tcp_max_conns,
+            Duration::from_millis(args.tcp_timeout_ms),
+        )?),
+        None => None,
+    };
+    if args.privsep {
+        // re-binding rotating ports to a device needs CAP_NET_RAW
+        let rebinds = args.rotate_ports.is_some() && !args.interfaces.is_empty();
+        privsep::drop_privileges(rebinds).context("drop capabilities")?;
+    }
+    // the sandbox has no rt_sigaction, so handlers go in before it; each
+    // also writes to a socketpair to wake the loop from poll(2)
+    let stop = Arc::new(AtomicBool::new(false));
+    let (signalled, wake) = UnixStream::pair().context("signal socketpair")?;
+    signalled.set_nonblocking(true)?;
+    for sig in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(sig, Arc::clone(&stop)).context("signal handler")?;
+        signal_hook::low_level::pipe::register(sig, wake.try_clone()?).context("signal handler")?;
+    }
+    if !args.no_sandbox {
+        sandbox::enter(&args.state_dir).context("enter sandbox")?;
+    }
 
-    Ok(())
+    let mut daemon = Daemon {
+        kem_priv,
+        psk,
+        mask_key: MaskKey::from_kem_pub(&kem_pub),
+        encoding: args.encoding,
+        reply_mode: args.reply,
+        window_secs: args.window_secs,
+        open_secs: args.open_secs,
+        fw,
+        port_grants: args.port_grants,
+        // Maintain a replay cache of (src_ip, nonce, ts) with TTL=window_secs
+        replay_cache: ReplayCache::new(Duration::from_secs(args.window_secs as u64), 4096),
+        limiter: RateLimiter::new(20, 200),
+        tickets: args
+            .tickets
+            .then(|| TicketStore::new(Duration::from_secs(args.ticket_secs), 1024)),
+        clients,
+        wg_interface: args.wg_interface.clone(),
+        keepalive_max_secs: args.keepalive_max_secs,
+        grants: GrantTable::default(),
+        peer_gate,
+        wrap_gating: args.wrap_gating,
+        wrap_grants: HashMap::new(),
+    };
+    daemon.restore(held, now_unix())?;
+
+    let mut buf = [0u8; 4096];
+    let mut last_sync: Option<i64> = None;
+    let mut last_keepalive = now_unix();
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return daemon.shutdown(args.on_exit);
+        }
+        let now = now_unix();
+        let mut busy = false;
+        if let Firewall::Privsep(helper) = &mut daemon.fw {
+            helper.check()?;
+        }
+        daemon.expire_peers(now);
+        if now - last_keepalive >= args.wg_poll_secs.max(1) as i64 {
+            if let Err(e) = daemon.keepalive(now) {
+                eprintln!("keepalive: {:#}", e);
+            }
+            last_keepalive = now;
+        }
+        // Rotating ports: (re)bind sockets and admit them in nft once per second
+        if let Some(listeners) = listeners.as_mut() {
+            if let Some(port_ttl) = port_ttl {
+                // a port stays active for three slots; one more covers late syncs
+                if last_sync != Some(now) {
+                    for port in listeners.sync(now) {
+                        if let Err(e) = daemon.fw.open_port(port, port_ttl) {
+                            eprintln!("rotating port {}: {}", port, e);
+                        }
+                    }
+                    last_sync = Some(now);
+                }
+            }
+        }
+        // Knocks seen on the wire are never answered
+        if let Some(c) = capture.as_mut() {
+            if let Some((frame, src)) = c.poll(&mut buf, now)? {
+                busy = true;
+                if let SocketAddr::V4(src_v4) = src {
+                    let _ = daemon.process(&frame, src_v4, Via::Direct);
+                }
+            }
+        }
+        // Knocks on the wrapper port. Granted sources are Hysteria clients:
+        // only their long-header packets can be re-knocks, the rest is 1-RTT.
+        if let Some(c) = wrap_capture.as_mut() {
+            if let Some((frame, src)) = c.poll(&mut buf, now)? {
+                busy = true;
+                if let SocketAddr::V4(src_v4) = src {
+                    let long_header = frame.first().is_some_and(|b| b & 0x80 != 0);
+                    if long_header || !daemon.wrap_granted(*src_v4.ip(), now) {
+                        let _ = daemon.process(&frame, src_v4, Via::Wrapper);
+                    }
+                }
+            }
+        }
+        // DNS knocks are answered by the responder itself, never with a reply
+        if let Some(d) = dns.as_mut() {
+            if let Some((frame, src)) = d.poll(&mut buf)? {
+                busy = true;
+                if let SocketAddr::V4(src_v4) = src {
+                    let _ = daemon.process(&frame, src_v4, Via::Resolver);
+                }
+            }
+        }
+        // ICMP knocks get only the kernel's echo reply
+        if let Some(i) = icmp.as_mut() {
+            if let Some((frame, src)) = i.poll(&mut buf)? {
+                busy = true;
+                if let SocketAddr::V4(src_v4) = src {
+                    let _ = daemon.process(&frame, src_v4, Via::Direct);
+                }
+            }
+        }
+        // TCP knocks never get a reply; the connection is already closed
+        if let Some((frame, src)) = tcp.as_mut().and_then(|t| t.poll()) {
+            busy = true;
+            if let SocketAddr::V4(src_v4) = src {
+                let _ = daemon.process(&frame, src_v4, Via::Direct);
+            }
+        }
+        if let Some(listeners) = listeners.as_mut() {
+            if let Some((n, src, reply)) = listeners.recv_from(&mut buf)? {
+                busy = true;
+                if let SocketAddr::V4(src_v4) = src {
+                    if let Some(r) = daemon.process(&buf[..n], src_v4, Via::Direct) {
+                        if let Err(e) = reply.send(&r) {
+                            eprintln!("{:#}", e);
+                        }
+                    }
+                }
+            }
+        }
+        if busy {
+            continue;
+        }
+        // idle: sleep until a source is readable or the next timed job
+        let mut deadlines = Vec::new();
+        if daemon.wg_interface.is_some() {
+            deadlines.push(last_keepalive + args.wg_poll_secs.max(1) as i64);
+        }
+        if port_ttl.is_some() {
+            deadlines.extend(listeners.as_ref().and_then(|l| l.next_sync(now)));
+        }
+        deadlines.extend(daemon.peer_gate.as_ref().and_then(|g| g.next_expiry()));
+        let mut fds = vec![signalled.as_fd()];
+        if let Firewall::Privsep(helper) = &daemon.fw {
+            fds.push(helper.fd());
+        }
+        fds.extend(listeners.iter().flat_map(|l| l.fds()));
+        fds.extend(capture.iter().map(|c| c.as_fd()));
+        fds.extend(wrap_capture.iter().map(|c| c.as_fd()));
+        fds.extend(dns.iter().map(|d| d.as_fd()));
+        fds.extend(icmp.iter().map(|i| i.as_fd()));
+        fds.extend(tcp.iter().flat_map(|t| t.fds()));
+        wait_readable(fds, deadlines.into_iter().min())?;
+        while (&signalled).read(&mut [0u8; 16]).is_ok_and(|n| n > 0) {}
+    }
+}
+
+/// Block until one of `fds` is readable, a signal arrives or `deadline`
+/// (unix seconds) passes.
+fn wait_readable(fds: Vec<BorrowedFd<'_>>, deadline: Option<i64>) -> Result<()> {
+    let timeout = match deadline {
+        Some(at) => {
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::from_secs(0))
+                .as_millis() as i64;
+            PollTimeout::try_from((at * 1000 - now_ms).clamp(0, i32::MAX as i64))
+                .map_err(|e| anyhow!("poll timeout: {}", e))?
+        }
+        None => PollTimeout::NONE,
+    };
+    let mut pfds: Vec<PollFd> = fds
+        .into_iter()
+        .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
+        .collect();
+    match poll(&mut pfds, timeout) {
+        // EINTR: a signal; the loop checks `stop` next
+        Ok(_) | Err(Errno::EINTR) => Ok(()),
+        Err(e) => Err(anyhow!("poll: {}", e)),
+    }
 }
 
 fn main() -> Result<()> {
+    // every subcommand but fingerprint handles key material
+    secrets::harden().context("disable core dumps")?;
     let cli = Cli::parse();
     match cli.cmd {
         Command::GenKeys { priv_out, pub_out } => gen_keys(priv_out, pub_out),
-        Command::Run {
-            listen,
-            wg_port,
-            kem_priv,
-            psk_file,
-            open_secs,
-            window_secs,
-            nft_family,
-            nft_table,
-            nft_set,
-            nft_chain,
-        } => {
-            let effective_set = if nft_set.is_empty() {
-                format!("{}{}_set", "", nft_chain)
-            } else {
-                nft_set
-            };
-            run_daemon(
-                listen,
-                wg_port,
-                kem_priv,
-                psk_file,
-                open_secs,
-                window_secs,
-                nft_family,
-                nft_table,
-                effective_set,
-            )
-        },
+        Command::Run(args) => run_daemon(*args),
+        Command::Fingerprint { kem_pub, psk_file } => print_fingerprints(kem_pub, psk_file),
+        Command::SealKey {
+            kind,
+            input,
+            out,
+            passphrase_file,
+            key_file,
+        } => seal_key(
+            kind,
+            &input,
+            &out,
+            &Unseal {
+                passphrase_file,
+                key_file,
+            },
+        ),
+        Command::Provision(args) => provision::run(&args),
+        Command::Doctor(args) => doctor::run(&args),
+        Command::PrivsepHelper => privsep::run_helper(),
+        Command::RevokePeer {
+            wg_interface,
+            public_key,
+        } => peers::remove_peer(&wg_interface, &public_key),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    // no external RNG used in current tests
+    use payload::KnockPayload;
 
     #[test]
     fn hmac_message_format() {
@@ -514,8 +1579,147 @@ mod tests {
         }
         assert_eq!(entry.0, 2);
     }
+
+    fn test_daemon(default: ClientPolicy) -> (Daemon, kem::PublicKey) {
+        let (pk, sk) = kem::keypair();
+        let kem_pub = PkTrait::as_bytes(&pk).to_vec();
+        let daemon = Daemon {
+            kem_priv: Secret::new(SkTrait::as_bytes(&sk).to_vec()),
+            psk: Secret::new(vec![1u8; 32]),
+            mask_key: MaskKey::from_kem_pub(&kem_pub),
+            encoding: Encoding::Plain,
+            reply_mode: ReplyMode::Encrypted,
+            window_secs: 30,
+            open_secs: 45,
+            fw: Firewall::Direct(Backend::new(Policy::default())),
+            port_grants: PortGrants::Off,
+            replay_cache: ReplayCache::new(Duration::from_secs(30), 64),
+            limiter: RateLimiter::new(20, 200),
+            tickets: None,
+            clients: Clients::new(default),
+            wg_interface: None,
+            keepalive_max_secs: 0,
+            grants: GrantTable::default(),
+            peer_gate: None,
+            wrap_gating: false,
+            wrap_grants: HashMap::new(),
+        };
+        (daemon, pk)
+    }
+
+    /// A v2 knock as the client builds it.
+    fn v2_knock(pk: &kem::PublicKey, psk: &[u8], client_ip: Ipv4Addr, p: &KnockPayload) -> Vec<u8> {
+        let (ss, ct) = kem::encapsulate(pk);
+        let key = SsTrait::as_bytes(&ss);
+        let ct = CtTrait::as_bytes(&ct);
+        let nonce = [9u8; NONCE_LEN];
+        let mut header = nonce.to_vec();
+        header.extend_from_slice(&now_unix().to_be_bytes());
+        header.extend_from_slice(&client_ip.octets());
+        let mut aad = vec![PROTO_VER_PAYLOAD];
+        aad.extend_from_slice(&header);
+        let sealed = payload::seal(key, psk, &nonce, &aad, p);
+        let mut pkt = vec![PROTO_VER_PAYLOAD];
+        pkt.extend_from_slice(&(ct.len() as u16).to_be_bytes());
+        pkt.extend_from_slice(ct);
+        pkt.extend_from_slice(&header);
+        pkt.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+        pkt.extend_from_slice(&sealed);
+        let mut mac = HmacSha256::new_from_slice(key).unwrap();
+        mac.update(psk);
+        mac.update(&[PROTO_VER_PAYLOAD]);
+        mac.update(&header);
+        mac.update(&(sealed.len() as u16).to_be_bytes());
+        mac.update(&sealed);
+        pkt.extend_from_slice(&mac.finalize().into_bytes());
+        pkt
+    }
+
+    #[test]
+    fn resolver_knocks_only_open_delegated_targets() {
+        let resolver = SocketAddrV4::new(Ipv4Addr::new(9, 9, 9, 9), 53);
+        let knock = KnockPayload {
+            client_id: "laptop".into(),
+            target: Some(Ipv4Addr::new(203, 0, 113, 7)),
+            ..Default::default()
+        };
+        let refused = |policy: ClientPolicy| {
+            let (mut daemon, pk) = test_daemon(policy);
+            let pkt = v2_knock(&pk, &[1u8; 32], *resolver.ip(), &knock);
+            let err = daemon
+                .handle_packet(&pkt, resolver, Via::Resolver)
+                .err()
+                .expect("granted");
+            reason_of(&err)
+        };
+        // outside the delegate networks
+        let declared = ClientPolicy {
+            target: TargetPolicy::Declared,
+            delegate: vec!["192.168.10.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(refused(declared), "target_denied");
+        // the resolver's address is not the client's to match or stand for
+        for target in [TargetPolicy::Observed, TargetPolicy::Match] {
+            let policy = ClientPolicy {
+                target,
+                ..Default::default()
+            };
+            assert_eq!(refused(policy), "target_mismatch");
+        }
+    }
+
+    #[test]
+    fn icmp_knock_completes_after_a_flood() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 2), 0);
+        // the target is refused only once the knock has been verified
+        let (mut daemon, pk) = test_daemon(ClientPolicy {
+            target: TargetPolicy::Declared,
+            ..Default::default()
+        });
+        let knock = KnockPayload {
+            client_id: "laptop".into(),
+            target: Some(Ipv4Addr::new(203, 0, 113, 7)),
+            ..Default::default()
+        };
+        let frame = v2_knock(&pk, &[1u8; 32], *src.ip(), &knock);
+        let echo = |session: [u8; 8], idx: u8, total: u8, data: &[u8]| {
+            let mut pkt = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0];
+            pkt.extend_from_slice(&[198, 51, 100, 2, 10, 0, 0, 1]);
+            pkt.extend_from_slice(&[8, 0, 0, 0, 0, 1, 0, 1]);
+            pkt.extend_from_slice(&session);
+            pkt.extend_from_slice(&[idx, total]);
+            pkt.extend_from_slice(data);
+            icmp::parse_echo(&pkt).unwrap()
+        };
+        let parts: Vec<&[u8]> = frame.chunks(icmp::CHUNK_LEN).collect();
+        let (last, total) = (parts.len() - 1, parts.len() as u8);
+
+        // spoofed first chunks fill the table before and during the knock
+        let mut r = chunks::Reassembly::new();
+        let now = Instant::now();
+        for s in 0..300u64 {
+            assert!(r.add(echo(s.to_be_bytes(), 0, 2, b"junk"), now).is_none());
+        }
+        let later = now + Duration::from_millis(50);
+        for (i, part) in parts[..last].iter().enumerate() {
+            assert!(r.add(echo([3; 8], i as u8, total, part), later).is_none());
+        }
+        for s in 300..500u64 {
+            assert!(r.add(echo(s.to_be_bytes(), 0, 2, b"junk"), later).is_none());
+        }
+        let got = r
+            .add(echo([3; 8], last as u8, total, parts[last]), later)
+            .unwrap();
+        assert_eq!(got, frame);
+        let err = daemon
+            .handle_packet(&got, src, Via::Direct)
+            .err()
+            .expect("granted");
+        assert_eq!(reason_of(&err), "target_denied");
+    }
 }
-#[derive(Error, Debug)]
+#[derive(Error, Debug, PartialEq, Eq)]
 enum SpaError {
     #[error("packet too short")]
     PacketTooShort,
@@ -537,6 +1741,24 @@ enum SpaError {
     BadHmac,
     #[error("nft_missing")]
     NftMissing,
+    #[error("payload_decrypt")]
+    PayloadDecrypt,
+    #[error("payload_invalid")]
+    PayloadInvalid,
+    #[error("bad_frame")]
+    BadFrame,
+    #[error("unknown_ticket")]
+    UnknownTicket,
+    #[error("port_required")]
+    PortRequired,
+    #[error("target_mismatch")]
+    TargetMismatch,
+    #[error("target_denied")]
+    TargetDenied,
+    #[error("service_unavailable")]
+    ServiceUnavailable,
+    #[error("client_mismatch")]
+    ClientMismatch,
 }
 
 fn reason_of(e: &anyhow::Error) -> &'static str {
@@ -552,6 +1774,15 @@ fn reason_of(e: &anyhow::Error) -> &'static str {
             SpaError::HmacKey => "hmac_key",
             SpaError::BadHmac => "bad_hmac",
             SpaError::NftMissing => "nft_missing",
+            SpaError::PayloadDecrypt => "payload_decrypt",
+            SpaError::PayloadInvalid => "payload_invalid",
+            SpaError::BadFrame => "bad_frame",
+            SpaError::UnknownTicket => "unknown_ticket",
+            SpaError::PortRequired => "port_required",
+            SpaError::TargetMismatch => "target_mismatch",
+            SpaError::TargetDenied => "target_denied",
+            SpaError::ServiceUnavailable => "service_unavailable",
+            SpaError::ClientMismatch => "client_mismatch",
         }
     } else {
         "error"
@@ -0,0 +1,711 @@
+// nftables layouts: where the WireGuard gate lives.
+//
+// Two layouts ship with open-winder: the OpenWRT include (99-wg-spa.nft),
+// whose sets and rules sit in fw4's `inet fw4` table, and the standalone
+// nftables.spa.conf, whose `inet filter` input chain jumps to a chain that
+// matches the allow set. Rather than trust the flags to name the right one,
+// `locate` reads `nft -j list ruleset` and follows the input hook, through
+// jumps, to the rule that accepts WG_PORT from an address set; that set and
+// its table are where grants go. `ensure` then checks the other sets the
+// flags need and, with --manage-objects, creates what is missing: sets, and
+// rules at the top of the input chain (or a chain of its own, hooked on
+// input, in a table it created). The doctor reads the ruleset the same way.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::net::Ipv4Addr;
+
+use crate::firewall::Policy;
+use crate::SpaError;
+
+/// Tables tried first, in this order: the fw4 include, then nftables.spa.conf.
+const KNOWN_TABLES: [(&str, &str); 2] = [("inet", "fw4"), ("inet", "filter")];
+/// The allow set when neither the flags nor the ruleset name one.
+const DEFAULT_SET: &str = "wg_spa_allow";
+/// Input chain created by --manage-objects in a table without one.
+const MANAGED_CHAIN: &str = "wg_spa_input";
+
+pub const IPV4: &[&str] = &["ipv4_addr"];
+pub const PAIR: &[&str] = &["ipv4_addr", "inet_service"];
+pub const SERVICE: &[&str] = &["inet_service"];
+
+pub fn list_ruleset() -> Result<String> {
+    list(&["ruleset"])
+}
+
+/// `nft -j list set`: the set and its elements.
+pub fn list_set(family: &str, table: &str, set: &str) -> Result<String> {
+    list(&["set", family, table, set])
+}
+
+fn list(what: &[&str]) -> Result<String> {
+    let out = std::process::Command::new("nft")
+        .args(["-j", "list"])
+        .args(what)
+        .output()
+        .context("nft -j list")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "nft -j list {}: {}",
+            what.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// The objects of one table in `nft -j list ruleset` output.
+#[derive(Debug, Default)]
+pub struct Ruleset {
+    pub family: String,
+    pub table: String,
+    /// Every table, as "family name"
+    pub tables: Vec<String>,
+    pub sets: Vec<Set>,
+    pub chains: Vec<Chain>,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+pub struct Set {
+    pub name: String,
+    /// One entry per concatenated field
+    pub kind: Vec<String>,
+    pub flags: Vec<String>,
+    pub elements: Vec<Element>,
+}
+
+/// An element of an address, address . port or port set.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Element {
+    pub ip: Option<Ipv4Addr>,
+    pub port: Option<u16>,
+    /// Seconds left, for elements with a timeout
+    pub expires: Option<u64>,
+    pub comment: Option<String>,
+}
+
+impl Element {
+    /// `{ 1.2.3.4 timeout 45s comment "x" }` in JSON: the value alone, or
+    /// under "elem" with its timeout, expiry and comment.
+    fn parse(v: &Value) -> Option<Self> {
+        let (val, meta) = match v.get("elem") {
+            Some(e) => (e.get("val")?, Some(e)),
+            None => (v, None),
+        };
+        let ip = |v: &Value| v.as_str()?.parse().ok();
+        let port = |v: &Value| u16::try_from(v.as_u64()?).ok();
+        let (ip, port) = match val.get("concat").and_then(Value::as_array) {
+            Some(c) => (ip(c.first()?), port(c.get(1)?)),
+            None => (ip(val), port(val)),
+        };
+        if ip.is_none() && port.is_none() {
+            return None;
+        }
+        Some(Element {
+            ip,
+            port,
+            expires: meta.and_then(|m| m.get("expires")?.as_u64()),
+            comment: meta
+                .and_then(|m| m.get("comment")?.as_str())
+                .map(str::to_string),
+        })
+    }
+
+    /// The element in nft syntax, without timeout or comment.
+    pub fn key(&self) -> String {
+        match (self.ip, self.port) {
+            (Some(ip), Some(port)) => format!("{} . {}", ip, port),
+            (Some(ip), None) => ip.to_string(),
+            (None, Some(port)) => port.to_string(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Chain {
+    pub name: String,
+    pub hook: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    pub chain: String,
+    pub handle: u64,
+    pub expr: Vec<Value>,
+}
+
+/// A string or a list of strings (nft writes both).
+fn strings(v: Option<&Value>) -> Vec<String> {
+    match v {
+        Some(Value::String(s)) => vec![s.clone()],
+        Some(Value::Array(a)) => a
+            .iter()
+            .filter_map(|s| s.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl Ruleset {
+    pub fn parse(json: &str, family: &str, table: &str) -> Result<Self> {
+        let root: Value = serde_json::from_str(json)?;
+        let objects = root
+            .get("nftables")
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("no \"nftables\" array"))?;
+        let mut rs = Ruleset {
+            family: family.to_string(),
+            table: table.to_string(),
+            ..Default::default()
+        };
+        for obj in objects {
+            let Some((kind, o)) = obj.as_object().and_then(|m| m.iter().next()) else {
+                continue;
+            };
+            let field = |k: &str| o.get(k).and_then(Value::as_str).unwrap_or_default();
+            if kind == "table" {
+                rs.tables
+                    .push(format!("{} {}", field("family"), field("name")));
+                continue;
+            }
+            if field("family") != family || field("table") != table {
+                continue;
+            }
+            match kind.as_str() {
+                "set" => rs.sets.push(Set {
+                    name: field("name").to_string(),
+                    kind: strings(o.get("type")),
+                    flags: strings(o.get("flags")),
+                    elements: o
+                        .get("elem")
+                        .and_then(Value::as_array)
+                        .map(|a| a.iter().filter_map(Element::parse).collect())
+                        .unwrap_or_default(),
+                }),
+                "chain" => rs.chains.push(Chain {
+                    name: field("name").to_string(),
+                    hook: o.get("hook").and_then(Value::as_str).map(str::to_string),
+                }),
+                "rule" => rs.rules.push(Rule {
+                    chain: field("chain").to_string(),
+                    handle: o.get("handle").and_then(Value::as_u64).unwrap_or(0),
+                    expr: o
+                        .get("expr")
+                        .and_then(Value::as_array)
+                        .cloned()
+                        .unwrap_or_default(),
+                }),
+                _ => {}
+            }
+        }
+        Ok(rs)
+    }
+
+    pub fn has_table(&self) -> bool {
+        self.tables
+            .contains(&format!("{} {}", self.family, self.table))
+    }
+
+    pub fn set(&self, name: &str) -> Option<&Set> {
+        self.sets.iter().find(|s| s.name == name)
+    }
+
+    /// Follow every input-hooked chain, and the chains it jumps to, looking
+    /// for rules that accept traffic to `port`.
+    pub fn gate(&self, port: Port) -> Gate {
+        let mut gate = Gate::default();
+        let mut seen = BTreeSet::new();
+        for chain in &self.chains {
+            if chain.hook.as_deref() == Some("input") {
+                self.walk(&chain.name, false, port, &mut seen, &mut gate);
+            }
+        }
+        gate
+    }
+
+    fn walk<'a>(
+        &'a self,
+        chain: &'a str,
+        on_port: bool,
+        port: Port,
+        seen: &mut BTreeSet<(&'a str, bool)>,
+        gate: &mut Gate,
+    ) {
+        if !seen.insert((chain, on_port)) {
+            return;
+        }
+        for rule in self.rules.iter().filter(|r| r.chain == chain) {
+            let on_port = on_port || rule.matches_port(port);
+            match rule.verdict() {
+                Some(Verdict::Accept) if on_port => {
+                    let sets = rule.saddr_sets();
+                    if !sets.is_empty() {
+                        gate.sets.extend(sets.iter().map(|s| s.to_string()));
+                    } else if rule.other_matches() == 0 {
+                        gate.open
+                            .push(format!("{} handle {}", rule.chain, rule.handle));
+                    }
+                }
+                Some(Verdict::Jump(target)) => {
+                    self.walk(target, on_port, port, seen, gate);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// What an input path does with a port.
+#[derive(Debug, Default)]
+pub struct Gate {
+    /// Sets the rules accepting it take source addresses from
+    pub sets: BTreeSet<String>,
+    /// Rules accepting it from anyone, as "chain handle N"
+    pub open: Vec<String>,
+}
+
+impl Gate {
+    /// Accepted from `set`, or with no set, from anyone.
+    pub fn admits(&self, set: Option<&str>) -> bool {
+        match set {
+            Some(set) => self.sets.contains(set),
+            None => !self.open.is_empty(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Port<'a> {
+    Num(u16),
+    /// `udp dport @set`
+    Set(&'a str),
+}
+
+enum Verdict<'a> {
+    Accept,
+    /// jump or goto
+    Jump(&'a str),
+}
+
+impl Rule {
+    fn matches(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.expr.iter().filter_map(|e| {
+            let m = e.get("match")?;
+            Some((m.get("left")?, m.get("right")?))
+        })
+    }
+
+    fn matches_port(&self, port: Port) -> bool {
+        self.matches().any(|(left, right)| {
+            is_dport(left)
+                && match port {
+                    Port::Num(n) => contains_port(right, n),
+                    Port::Set(s) => right.as_str() == Some(&format!("@{}", s)),
+                }
+        })
+    }
+
+    /// Sets matched against the source address (alone or first in a
+    /// concatenation).
+    fn saddr_sets(&self) -> Vec<&str> {
+        self.matches()
+            .filter(|(left, _)| {
+                is_saddr(left)
+                    || left
+                        .get("concat")
+                        .and_then(|c| c.get(0))
+                        .is_some_and(is_saddr)
+            })
+            .filter_map(|(_, right)| right.as_str()?.strip_prefix('@'))
+            .collect()
+    }
+
+    /// Matches other than on the destination port or the protocol.
+    fn other_matches(&self) -> usize {
+        self.matches()
+            .filter(|(left, _)| {
+                !is_dport(left) && left.pointer("/meta/key") != Some(&"l4proto".into())
+            })
+            .count()
+    }
+
+    fn verdict(&self) -> Option<Verdict<'_>> {
+        self.expr.iter().find_map(|e| {
+            if e.get("accept").is_some() {
+                return Some(Verdict::Accept);
+            }
+            let target = e.get("jump").or_else(|| e.get("goto"))?;
+            target.get("target")?.as_str().map(Verdict::Jump)
+        })
+    }
+}
+
+fn is_payload(v: &Value, protocols: &[&str], field: &str) -> bool {
+    v.get("payload").is_some_and(|p| {
+        p.get("field").and_then(Value::as_str) == Some(field)
+            && p.get("protocol")
+                .and_then(Value::as_str)
+                .is_some_and(|proto| protocols.contains(&proto))
+    })
+}
+
+fn is_dport(v: &Value) -> bool {
+    is_payload(v, &["udp", "th"], "dport")
+}
+
+fn is_saddr(v: &Value) -> bool {
+    is_payload(v, &["ip"], "saddr")
+}
+
+/// `right` of a dport match: a number, an anonymous set, or a range.
+fn contains_port(right: &Value, port: u16) -> bool {
+    let port = u64::from(port);
+    if let Some(n) = right.as_u64() {
+        return n == port;
+    }
+    if let Some(range) = right.get("range").and_then(Value::as_array) {
+        let (lo, hi) = (
+            range.first().and_then(Value::as_u64),
+            range.get(1).and_then(Value::as_u64),
+        );
+        return matches!((lo, hi), (Some(lo), Some(hi)) if (lo..=hi).contains(&port));
+    }
+    right
+        .get("set")
+        .and_then(Value::as_array)
+        .is_some_and(|items| items.iter().any(|v| contains_port(v, port as u16)))
+}
+
+/// --nft-family, --nft-table and --nft-set as given; None is discovered.
+pub struct Want<'a> {
+    pub family: Option<&'a str>,
+    pub table: Option<&'a str>,
+    pub set: Option<&'a str>,
+    pub wg_port: u16,
+}
+
+/// Where WireGuard grants go, and why.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub family: String,
+    pub table: String,
+    pub set: String,
+    /// For the startup log
+    pub reason: String,
+}
+
+/// Find the table and set that gate WG_PORT. Failing that, a table holding
+/// a set of the wanted name; failing that, with `manage`, where to create
+/// one: the wanted table, fw4 where it runs, else `inet filter`.
+pub fn locate(json: &str, want: &Want, manage: bool) -> Result<Layout> {
+    let root = Ruleset::parse(json, "", "")?;
+    let mut tables: Vec<(&str, &str)> = root
+        .tables
+        .iter()
+        .filter_map(|t| t.split_once(' '))
+        .filter(|(f, t)| want.family.is_none_or(|w| w == *f) && want.table.is_none_or(|w| w == *t))
+        .collect();
+    tables.sort_by_key(|t| {
+        KNOWN_TABLES
+            .iter()
+            .position(|k| k == t)
+            .unwrap_or(KNOWN_TABLES.len())
+    });
+    let layout = |(family, table): (&str, &str), set: &str, reason: String| Layout {
+        family: family.to_string(),
+        table: table.to_string(),
+        set: set.to_string(),
+        reason,
+    };
+    for &t in &tables {
+        let rs = Ruleset::parse(json, t.0, t.1)?;
+        let gate = rs.gate(Port::Num(want.wg_port));
+        let found = gate.sets.iter().find(|s| {
+            want.set.is_none_or(|w| w == s.as_str()) && rs.set(s).is_some_and(|s| s.kind == IPV4)
+        });
+        if let Some(set) = found {
+            let reason = format!("input accepts udp dport {} from @{}", want.wg_port, set);
+            return Ok(layout(t, set, reason));
+        }
+    }
+    let name = want.set.unwrap_or(DEFAULT_SET);
+    for &t in &tables {
+        if Ruleset::parse(json, t.0, t.1)?.set(name).is_some() {
+            let reason = format!(
+                "set found by name; no input rule accepts udp dport {} from it",
+                want.wg_port
+            );
+            return Ok(layout(t, name, reason));
+        }
+    }
+    if !manage {
+        return Err(missing(format!(
+            "no nft table{} gates udp dport {} with an address set or has a set {}",
+            match (want.family, want.table) {
+                (None, None) => String::new(),
+                (f, t) => format!(
+                    " matching {} {}",
+                    f.unwrap_or("<any>"),
+                    t.unwrap_or("<any>")
+                ),
+            },
+            want.wg_port,
+            name
+        )));
+    }
+    let fw4 = root.tables.iter().any(|t| t == "inet fw4");
+    let family = want.family.unwrap_or("inet");
+    let table = want
+        .table
+        .or_else(|| tables.first().map(|t| t.1))
+        .unwrap_or(if fw4 { "fw4" } else { "filter" });
+    Ok(layout(
+        (family, table),
+        name,
+        "not found; --manage-objects creates it".to_string(),
+    ))
+}
+
+fn missing(what: String) -> anyhow::Error {
+    anyhow::Error::from(SpaError::NftMissing).context(format!(
+        "{}; pass --nft-family/--nft-table/--nft-set, load the nftables config, or run with --manage-objects (see `home-secnet-spa-pq doctor`)",
+        what
+    ))
+}
+
+/// Check that every set of `policy` exists with its type. Rules admitting
+/// the sets are looked for too; a missing one is only logged. With
+/// `manage`, create what is missing instead.
+pub fn ensure(
+    json: &str,
+    policy: &Policy,
+    wg_port: u16,
+    wrap_port: Option<u16>,
+    manage: bool,
+) -> Result<()> {
+    let rs = Ruleset::parse(json, &policy.family, &policy.table)?;
+    let (family, table) = (&policy.family, &policy.table);
+    let mut batch = Vec::new();
+    if !rs.has_table() {
+        if !manage {
+            return Err(missing(format!("nft table {} {} missing", family, table)));
+        }
+        batch.push(format!("add table {} {}", family, table));
+    }
+    let sets = [
+        (Some(&policy.wg_set), IPV4),
+        (policy.pair_set.as_ref(), PAIR),
+        (policy.wrap_set.as_ref(), IPV4),
+        (policy.port_set.as_ref(), SERVICE),
+    ];
+    for (name, kind) in sets {
+        let Some(name) = name else {
+            continue;
+        };
+        match rs.set(name) {
+            Some(set) if set.kind == kind => {}
+            Some(set) => {
+                return Err(anyhow!(
+                    "nft set {} {} {} has type {}, expected {}",
+                    family,
+                    table,
+                    name,
+                    set.kind.join(" . "),
+                    kind.join(" . ")
+                ))
+            }
+            None if manage => batch.push(format!(
+                "add set {} {} {} {{ type {}; flags timeout; }}",
+                family,
+                table,
+                name,
+                kind.join(" . ")
+            )),
+            None => {
+                return Err(missing(format!(
+                    "nft set {} {} {} missing",
+                    family, table, name
+                )))
+            }
+        }
+    }
+
+    let mut gates = vec![(
+        Port::Num(wg_port),
+        Some(policy.wg_set.as_str()),
+        format!("udp dport {} ip saddr @{} accept", wg_port, policy.wg_set),
+    )];
+    if let Some(set) = &policy.pair_set {
+        gates.push((
+            Port::Num(wg_port),
+            Some(set),
+            format!("udp dport {} ip saddr . udp sport @{} accept", wg_port, set),
+        ));
+    }
+    if let (Some(set), Some(port)) = (&policy.wrap_set, wrap_port) {
+        gates.push((
+            Port::Num(port),
+            Some(set),
+            format!("udp dport {} ip saddr @{} accept", port, set),
+        ));
+    }
+    if let Some(set) = &policy.port_set {
+        gates.push((Port::Set(set), None, format!("udp dport @{} accept", set)));
+    }
+    let rules: Vec<String> = gates
+        .into_iter()
+        .filter(|(port, set, _)| !rs.gate(*port).admits(*set))
+        .map(|(_, _, rule)| rule)
+        .collect();
+    if !manage {
+        for rule in &rules {
+            eprintln!(
+                "nft: no input rule in {} {} like `{}`; see `home-secnet-spa-pq doctor`",
+                family, table, rule
+            );
+        }
+    } else if !rules.is_empty() {
+        match rs
+            .chains
+            .iter()
+            .find(|c| c.hook.as_deref() == Some("input"))
+        {
+            // inserted in reverse, so they keep their order at the top
+            Some(chain) => {
+                batch.extend(rules.iter().rev().map(|rule| {
+                    format!("insert rule {} {} {} {}", family, table, chain.name, rule)
+                }))
+            }
+            None => {
+                batch.push(format!(
+                    "add chain {} {} {} {{ type filter hook input priority 0; policy accept; }}",
+                    family, table, MANAGED_CHAIN
+                ));
+                batch.extend(rules.iter().map(|rule| {
+                    format!("add rule {} {} {} {}", family, table, MANAGED_CHAIN, rule)
+                }));
+                let mut closed = vec![wg_port];
+                closed.extend(wrap_port.filter(|_| policy.wrap_set.is_some()));
+                batch.extend(closed.into_iter().map(|port| {
+                    format!(
+                        "add rule {} {} {} udp dport {} drop",
+                        family, table, MANAGED_CHAIN, port
+                    )
+                }));
+            }
+        }
+    }
+    if batch.is_empty() {
+        return Ok(());
+    }
+    for line in &batch {
+        eprintln!("nft: {}", line);
+    }
+    apply(&batch.join("\n"))
+}
+
+pub fn apply(batch: &str) -> Result<()> {
+    let mut child = std::process::Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .context("nft -f -")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(format!("{}\n", batch).as_bytes())
+            .context("write nft batch")?;
+    }
+    if !child.wait().context("nft -f -")?.success() {
+        return Err(anyhow!("nft -f - failed"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `nft -j list ruleset` for the OpenWRT include and for
+    // nftables.spa.conf, whose chain matches a set under another name
+    const RULESET: &str = r#"{"nftables": [
+      {"metainfo": {"version": "1.0.9", "json_schema_version": 1}},
+      {"table": {"family": "inet", "name": "fw4", "handle": 1}},
+      {"set": {"family": "inet", "name": "wg_spa_allow", "table": "fw4", "type": "ipv4_addr", "handle": 2, "flags": ["timeout"]}},
+      {"set": {"family": "inet", "name": "wg_spa_allow_pair", "table": "fw4", "type": ["ipv4_addr", "inet_service"], "handle": 3, "flags": ["timeout"]}},
+      {"chain": {"family": "inet", "table": "fw4", "name": "input", "handle": 4, "type": "filter", "hook": "input", "prio": 0, "policy": "drop"}},
+      {"chain": {"family": "inet", "table": "fw4", "name": "input_wan", "handle": 5}},
+      {"rule": {"family": "inet", "table": "fw4", "chain": "input", "handle": 6, "expr": [
+        {"match": {"op": "==", "left": {"meta": {"key": "iifname"}}, "right": "wan"}},
+        {"jump": {"target": "input_wan"}}]}},
+      {"rule": {"family": "inet", "table": "fw4", "chain": "input_wan", "handle": 7, "expr": [
+        {"match": {"op": "==", "left": {"payload": {"protocol": "udp", "field": "dport"}}, "right": 51820}},
+        {"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": "@wg_spa_allow"}},
+        {"accept": null}]}},
+      {"rule": {"family": "inet", "table": "fw4", "chain": "input_wan", "handle": 8, "expr": [
+        {"match": {"op": "==", "left": {"payload": {"protocol": "udp", "field": "dport"}}, "right": {"set": [443, 51820]}}},
+        {"match": {"op": "==", "left": {"concat": [{"payload": {"protocol": "ip", "field": "saddr"}}, {"payload": {"protocol": "udp", "field": "sport"}}]}, "right": "@wg_spa_allow_pair"}},
+        {"accept": null}]}},
+      {"rule": {"family": "inet", "table": "fw4", "chain": "input_wan", "handle": 9, "expr": [
+        {"match": {"op": "==", "left": {"payload": {"protocol": "udp", "field": "dport"}}, "right": 1194}},
+        {"accept": null}]}},
+      {"table": {"family": "inet", "name": "filter", "handle": 10}},
+      {"set": {"family": "inet", "name": "wg_spa_allow", "table": "filter", "type": "ipv4_addr", "handle": 11}},
+      {"set": {"family": "inet", "name": "wg_spa_allow_set", "table": "filter", "type": "ipv4_addr", "handle": 16, "flags": ["timeout"]}},
+      {"chain": {"family": "inet", "table": "filter", "name": "input", "handle": 12, "type": "filter", "hook": "input", "prio": 0, "policy": "drop"}},
+      {"chain": {"family": "inet", "table": "filter", "name": "wg_spa_allow", "handle": 13}},
+      {"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 14, "expr": [
+        {"match": {"op": "==", "left": {"payload": {"protocol": "udp", "field": "dport"}}, "right": 51820}},
+        {"jump": {"target": "wg_spa_allow"}}]}},
+      {"rule": {"family": "inet", "table": "filter", "chain": "wg_spa_allow", "handle": 15, "expr": [
+        {"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": "@wg_spa_allow_set"}},
+        {"accept": null}]}}
+    ]}"#;
+
+    #[test]
+    fn gates_are_found_through_jumps() {
+        let fw4 = Ruleset::parse(RULESET, "inet", "fw4").unwrap();
+        assert_eq!(fw4.tables, ["inet fw4", "inet filter"]);
+        assert_eq!(fw4.set("wg_spa_allow_pair").unwrap().kind, PAIR);
+        let gate = fw4.gate(Port::Num(51820));
+        assert!(gate.admits(Some("wg_spa_allow")) && gate.open.is_empty());
+        assert!(gate.admits(Some("wg_spa_allow_pair")));
+        // a port nothing gates is open to anyone
+        let gate = fw4.gate(Port::Num(1194));
+        assert!(gate.sets.is_empty());
+        assert_eq!(gate.open, ["input_wan handle 9"]);
+
+        let filter = Ruleset::parse(RULESET, "inet", "filter").unwrap();
+        assert!(filter.set("wg_spa_allow").unwrap().flags.is_empty());
+        let gate = filter.gate(Port::Num(51820));
+        assert!(!gate.admits(Some("wg_spa_allow")) && gate.open.is_empty());
+        assert!(gate.admits(Some("wg_spa_allow_set")));
+    }
+
+    #[test]
+    fn layout_is_where_the_gate_is() {
+        let want = |table, set| Want {
+            family: None,
+            table,
+            set,
+            wg_port: 51820,
+        };
+        let at = |l: Layout| (l.table, l.set);
+        // fw4 first; the standalone table's gate uses its own set name
+        let found = locate(RULESET, &want(None, None), false).unwrap();
+        assert_eq!(at(found), ("fw4".into(), "wg_spa_allow".into()));
+        let found = locate(RULESET, &want(Some("filter"), None), false).unwrap();
+        assert_eq!(at(found), ("filter".into(), "wg_spa_allow_set".into()));
+        // a named set nothing gates is still used, by name
+        let found = locate(RULESET, &want(Some("filter"), Some("wg_spa_allow")), false).unwrap();
+        assert!(found.reason.contains("by name"));
+        // nothing to find: an error, or with --manage-objects a place for it
+        let err = locate(RULESET, &want(Some("spa"), None), false).unwrap_err();
+        assert!(err.downcast_ref::<SpaError>().is_some());
+        let new = locate(RULESET, &want(Some("spa"), None), true).unwrap();
+        assert_eq!(at(new), ("spa".into(), "wg_spa_allow".into()));
+    }
+}
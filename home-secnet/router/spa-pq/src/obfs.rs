@@ -0,0 +1,214 @@
+// Obfuscated knock encoding
+//
+// Frame: salt(16) | E(knock_len u16 | check[6]) | E(knock) | padding
+// Optionally wrapped in a QUIC v1 Initial long header so the datagram looks
+// like the start of a QUIC handshake.
+//
+// The mask key is derived from the KEM public key, which clients already hold
+// but on-path observers do not (the daemon reads it out of the secret key).
+// Per frame, stream_key = HMAC(mask_key, salt) drives a ChaCha20 keystream
+// over header and knock; check is HMAC(stream_key, knock_len)[..6], which lets
+// the daemon drop junk with two HMACs and no KEM work.
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use hmac::Mac;
+use sha2::{Digest, Sha256};
+
+use crate::{HmacSha256, SpaError};
+
+pub const SALT_LEN: usize = 16;
+const CHECK_LEN: usize = 6;
+const HDR_LEN: usize = 2 + CHECK_LEN;
+const MASK_DOMAIN: &[u8] = b"open-winder spa-pq obfs v1";
+
+// QUIC v1 long header (Initial): flags | version | dcid_len | dcid | scid_len | token_len | length
+const QUIC_VERSION_1: [u8; 4] = [0, 0, 0, 1];
+const QUIC_DCID_LEN: usize = 8;
+const QUIC_HDR_LEN: usize = 1 + 4 + 1 + QUIC_DCID_LEN + 1 + 1 + 2;
+
+/// Accepted knock encodings.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Raw v1/v2 knocks only
+    Plain,
+    /// Masked frames only (bare or QUIC-framed)
+    Obfs,
+    /// Either of the above
+    Any,
+}
+
+#[derive(Clone)]
+pub struct MaskKey([u8; 32]);
+
+impl MaskKey {
+    pub fn from_kem_pub(kem_pub: &[u8]) -> Self {
+        let mut h = Sha256::new();
+        h.update(MASK_DOMAIN);
+        h.update(kem_pub);
+        Self(h.finalize().into())
+    }
+
+    fn stream(&self, salt: &[u8]) -> ([u8; 32], ChaCha20) {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.0).expect("hmac key");
+        mac.update(salt);
+        let stream_key: [u8; 32] = mac.finalize().into_bytes().into();
+        let cipher = ChaCha20::new(&stream_key.into(), &[0u8; 12].into());
+        (stream_key, cipher)
+    }
+}
+
+fn check_of(stream_key: &[u8; 32], knock_len: u16) -> [u8; CHECK_LEN] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(stream_key).expect("hmac key");
+    mac.update(&knock_len.to_be_bytes());
+    let full = mac.finalize().into_bytes();
+    let mut out = [0u8; CHECK_LEN];
+    out.copy_from_slice(&full[..CHECK_LEN]);
+    out
+}
+
+/// Strip a QUIC Initial long header if present and return the inner frame.
+fn strip_quic(dgram: &[u8]) -> Option<&[u8]> {
+    if dgram.len() < QUIC_HDR_LEN || dgram[0] & 0xf0 != 0xc0 || dgram[1..5] != QUIC_VERSION_1 {
+        return None;
+    }
+    if dgram[5] as usize != QUIC_DCID_LEN {
+        return None;
+    }
+    let mut off = 6 + QUIC_DCID_LEN;
+    // scid_len and token_len are always zero in our framing
+    if dgram[off] != 0 || dgram[off + 1] != 0 {
+        return None;
+    }
+    off += 2;
+    // two-byte varint length
+    if dgram[off] & 0xc0 != 0x40 {
+        return None;
+    }
+    let len = (u16::from_be_bytes([dgram[off], dgram[off + 1]]) & 0x3fff) as usize;
+    off += 2;
+    if dgram.len() != off + len {
+        return None;
+    }
+    Some(&dgram[off..])
+}
+
+/// Decode an obfuscated datagram into the raw knock. Fails without touching
+/// the KEM if the keyed header check does not match.
+pub fn decode(dgram: &[u8], key: &MaskKey) -> Result<Vec<u8>, SpaError> {
+    let frame = strip_quic(dgram).unwrap_or(dgram);
+    if frame.len() < SALT_LEN + HDR_LEN {
+        return Err(SpaError::BadFrame);
+    }
+    let (salt, rest) = frame.split_at(SALT_LEN);
+    let (stream_key, mut cipher) = key.stream(salt);
+    let mut hdr = [0u8; HDR_LEN];
+    hdr.copy_from_slice(&rest[..HDR_LEN]);
+    cipher.apply_keystream(&mut hdr);
+    let knock_len = u16::from_be_bytes([hdr[0], hdr[1]]);
+    if hdr[2..] != check_of(&stream_key, knock_len) {
+        return Err(SpaError::BadFrame);
+    }
+    let body = &rest[HDR_LEN..];
+    if body.len() < knock_len as usize {
+        return Err(SpaError::BadFrame);
+    }
+    let mut knock = body[..knock_len as usize].to_vec();
+    cipher.apply_keystream(&mut knock);
+    Ok(knock)
+}
+
+/// Decode a datagram according to the configured encoding.
+pub fn decode_for(dgram: &[u8], encoding: Encoding, key: &MaskKey) -> Result<Vec<u8>, SpaError> {
+    let looks_plain = matches!(
+        dgram.first(),
+        Some(&(crate::PROTO_VER | crate::PROTO_VER_PAYLOAD))
+    );
+    match encoding {
+        Encoding::Plain => Ok(dgram.to_vec()),
+        Encoding::Obfs => decode(dgram, key),
+        Encoding::Any => decode(dgram, key).or_else(|e| {
+            if looks_plain {
+                Ok(dgram.to_vec())
+            } else {
+                Err(e)
+            }
+        }),
+    }
+}
+
+#[cfg(test)]
+pub fn encode(
+    knock: &[u8],
+    key: &MaskKey,
+    salt: [u8; SALT_LEN],
+    pad: usize,
+    quic: bool,
+) -> Vec<u8> {
+    let (stream_key, mut cipher) = key.stream(&salt);
+    let mut frame = salt.to_vec();
+    let mut enc = (knock.len() as u16).to_be_bytes().to_vec();
+    enc.extend_from_slice(&check_of(&stream_key, knock.len() as u16));
+    enc.extend_from_slice(knock);
+    cipher.apply_keystream(&mut enc);
+    frame.extend_from_slice(&enc);
+    frame.extend(std::iter::repeat_n(0xa5, pad));
+    if !quic {
+        return frame;
+    }
+    let mut out = vec![0xc3];
+    out.extend_from_slice(&QUIC_VERSION_1);
+    out.push(QUIC_DCID_LEN as u8);
+    out.extend_from_slice(&[9u8; QUIC_DCID_LEN]);
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(&(0x4000 | frame.len() as u16).to_be_bytes());
+    out.extend_from_slice(&frame);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MLKEM768_EK_LEN;
+
+    #[test]
+    fn roundtrip_bare_and_quic() {
+        let key = MaskKey::from_kem_pub(&[1u8; MLKEM768_EK_LEN]);
+        let knock = vec![1u8, 0x04, 0x40, 7, 7, 7];
+        for quic in [false, true] {
+            let dgram = encode(&knock, &key, [3u8; SALT_LEN], 40, quic);
+            assert_ne!(dgram[0], 1);
+            assert_eq!(decode(&dgram, &key).unwrap(), knock);
+            assert_eq!(decode_for(&dgram, Encoding::Any, &key).unwrap(), knock);
+        }
+    }
+
+    #[test]
+    fn wrong_key_or_junk_is_rejected_before_kem() {
+        let key = MaskKey::from_kem_pub(&[1u8; MLKEM768_EK_LEN]);
+        let other = MaskKey::from_kem_pub(&[2u8; MLKEM768_EK_LEN]);
+        let dgram = encode(&[1u8; 64], &key, [3u8; SALT_LEN], 0, false);
+        assert!(matches!(decode(&dgram, &other), Err(SpaError::BadFrame)));
+        assert!(matches!(
+            decode_for(&[0x55; 1200], Encoding::Obfs, &key),
+            Err(SpaError::BadFrame)
+        ));
+        // plain knocks only pass in `any` mode
+        let plain = [crate::PROTO_VER; 40];
+        assert!(decode_for(&plain, Encoding::Obfs, &key).is_err());
+        assert!(decode_for(&plain, Encoding::Any, &key).is_ok());
+    }
+    #[test]
+    fn masks_the_client_vector() {
+        // the client's obfs::tests::masked_frame_matches_the_daemon_vector
+        // expects exactly this frame
+        let key = MaskKey::from_kem_pub(b"open-winder test kem_pub");
+        let dgram = encode(b"\x02knock", &key, [0x11; SALT_LEN], 0, false);
+        let hex: String = dgram.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "111111111111111111111111111111110ee1c6e5711a59834070a4ab511d"
+        );
+        assert_eq!(decode(&dgram, &key).unwrap(), b"\x02knock");
+    }
+}
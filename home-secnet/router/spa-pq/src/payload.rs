@@ -0,0 +1,211 @@
+// Encrypted knock payload (packet v2)
+//
+// The payload is sealed with ChaCha20-Poly1305 under a key derived from the
+// ML-KEM shared secret (HKDF-SHA256, PSK as salt). It is carried inside the
+// HMAC-authenticated region of the knock, so tampering fails the outer HMAC
+// before the AEAD is ever opened.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use zeroize::Zeroizing;
+
+use crate::SpaError;
+
+const HKDF_INFO: &[u8] = b"open-winder spa-pq v2 payload";
+pub const AEAD_NONCE_LEN: usize = 12;
+pub const AEAD_TAG_LEN: usize = 16;
+/// Upper bound on plaintext size; keeps a v2 knock below a 1500-byte MTU.
+pub const MAX_PLAIN_LEN: usize = 256;
+pub const MAX_SEALED_LEN: usize = MAX_PLAIN_LEN + AEAD_TAG_LEN;
+
+const MAX_CLIENT_ID_LEN: usize = 32;
+const MAX_SERVICES: usize = 4;
+const MAX_META_ENTRIES: usize = 8;
+const MAX_META_KEY_LEN: usize = 32;
+const MAX_META_VALUE_LEN: usize = 64;
+
+/// Services a knock may request.
+pub const KNOWN_SERVICES: &[&str] = &["wg", "wrapper"];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KnockPayload {
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<String>,
+    /// Requested grant duration in seconds; 0 means the daemon default.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub duration_secs: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<Ipv4Addr>,
+    /// UDP source port of the client's WireGuard endpoint (port-bound grants)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wg_src_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+/// Client ids are short and filename-safe.
+pub fn valid_client_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_CLIENT_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+impl KnockPayload {
+    /// Structural validation of a decrypted payload.
+    pub fn validate(&self) -> Result<(), SpaError> {
+        if !valid_client_id(&self.client_id) {
+            return Err(SpaError::PayloadInvalid);
+        }
+        if self.services.len() > MAX_SERVICES
+            || self
+                .services
+                .iter()
+                .any(|s| !KNOWN_SERVICES.contains(&s.as_str()))
+        {
+            return Err(SpaError::PayloadInvalid);
+        }
+        if self.wg_src_port == Some(0) {
+            return Err(SpaError::PayloadInvalid);
+        }
+        if self.meta.len() > MAX_META_ENTRIES
+            || self.meta.iter().any(|(k, v)| {
+                k.is_empty() || k.len() > MAX_META_KEY_LEN || v.len() > MAX_META_VALUE_LEN
+            })
+        {
+            return Err(SpaError::PayloadInvalid);
+        }
+        Ok(())
+    }
+
+    /// Grant duration after applying the daemon's cap.
+    pub fn grant_secs(&self, cap: u64) -> u64 {
+        if self.duration_secs == 0 {
+            cap
+        } else {
+            self.duration_secs.min(cap)
+        }
+    }
+}
+
+fn payload_cipher(shared: &[u8], psk: &[u8]) -> Result<ChaCha20Poly1305, SpaError> {
+    let hk = Hkdf::<Sha256>::new(Some(psk), shared);
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(HKDF_INFO, okm.as_mut())
+        .map_err(|_| SpaError::HmacKey)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(okm.as_ref())))
+}
+
+/// Decrypt and decode a sealed payload. `nonce` is the 16-byte knock nonce;
+/// its first 12 bytes are used as the AEAD nonce (the key is unique per
+/// encapsulation, so there is no nonce reuse across knocks).
+pub fn open(
+    shared: &[u8],
+    psk: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<KnockPayload, SpaError> {
+    if sealed.len() < AEAD_TAG_LEN || sealed.len() > MAX_SEALED_LEN {
+        return Err(SpaError::PayloadInvalid);
+    }
+    let cipher = payload_cipher(shared, psk)?;
+    let plain = cipher
+        .decrypt(
+            Nonce::from_slice(&nonce[..AEAD_NONCE_LEN]),
+            Payload { msg: sealed, aad },
+        )
+        .map_err(|_| SpaError::PayloadDecrypt)?;
+    let payload: KnockPayload =
+        serde_json::from_slice(&plain).map_err(|_| SpaError::PayloadInvalid)?;
+    payload.validate()?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+pub fn seal(
+    shared: &[u8],
+    psk: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    payload: &KnockPayload,
+) -> Vec<u8> {
+    let plain = serde_json::to_vec(payload).unwrap();
+    payload_cipher(shared, psk)
+        .unwrap()
+        .encrypt(
+            Nonce::from_slice(&nonce[..AEAD_NONCE_LEN]),
+            Payload { msg: &plain, aad },
+        )
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> KnockPayload {
+        let mut meta = BTreeMap::new();
+        meta.insert("client_version".to_string(), "0.1.0".to_string());
+        KnockPayload {
+            client_id: "laptop".into(),
+            services: vec!["wg".into()],
+            duration_secs: 600,
+            target: Some(Ipv4Addr::new(203, 0, 113, 7)),
+            wg_src_port: Some(51820),
+            meta,
+        }
+    }
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let shared = [3u8; 32];
+        let psk = [4u8; 32];
+        let nonce = [5u8; 16];
+        let aad = b"hdr";
+        let sealed = seal(&shared, &psk, &nonce, aad, &sample());
+        let got = open(&shared, &psk, &nonce, aad, &sealed).unwrap();
+        assert_eq!(got, sample());
+        assert_eq!(got.grant_secs(45), 45);
+    }
+
+    #[test]
+    fn open_rejects_wrong_aad_and_bad_fields() {
+        let shared = [3u8; 32];
+        let psk = [4u8; 32];
+        let nonce = [5u8; 16];
+        let sealed = seal(&shared, &psk, &nonce, b"hdr", &sample());
+        assert!(matches!(
+            open(&shared, &psk, &nonce, b"other", &sealed),
+            Err(SpaError::PayloadDecrypt)
+        ));
+
+        let mut bad = sample();
+        bad.services = vec!["ssh".into()];
+        let sealed = seal(&shared, &psk, &nonce, b"hdr", &bad);
+        assert!(matches!(
+            open(&shared, &psk, &nonce, b"hdr", &sealed),
+            Err(SpaError::PayloadInvalid)
+        ));
+
+        let mut bad = sample();
+        bad.wg_src_port = Some(0);
+        let sealed = seal(&shared, &psk, &nonce, b"hdr", &bad);
+        assert!(matches!(
+            open(&shared, &psk, &nonce, b"hdr", &sealed),
+            Err(SpaError::PayloadInvalid)
+        ));
+    }
+}
@@ -0,0 +1,232 @@
+// Peer gating (--peer-gating): WireGuard peers exist only while granted.
+//
+// An open port is not enough to handshake: peers named in --clients
+// (`wg_peer`) are removed from the interface at startup and added back when
+// their owner's knock is granted. They are removed again once the grant,
+// including keepalive extensions, runs out, or on `revoke-peer`. A peer
+// without allowed-ips could still complete a handshake, so disabled peers are
+// removed outright rather than emptied. Changes go through the firewall
+// (firewall.rs), which only knows peers by public key, and reach the kernel
+// over WireGuard's netlink API (wgnl.rs) rather than by running `wg set`.
+
+use crate::firewall::Firewall;
+use crate::secrets::Secret;
+use crate::wgnl::{self, PeerChange};
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+/// How to configure a client's peer while it is enabled.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WgPeer {
+    pub public_key: String,
+    pub allowed_ips: Vec<String>,
+    #[serde(default)]
+    pub preshared_key_file: Option<PathBuf>,
+}
+
+impl WgPeer {
+    pub fn validate(&self) -> Result<()> {
+        decode_key(&self.public_key)?;
+        if self.allowed_ips.is_empty() {
+            return Err(anyhow!("peer {} has no allowed_ips", self.public_key));
+        }
+        self.networks()?;
+        Ok(())
+    }
+
+    /// `allowed_ips` as address and prefix length; a bare address is a host.
+    fn networks(&self) -> Result<Vec<(IpAddr, u8)>> {
+        self.allowed_ips
+            .iter()
+            .map(|net| {
+                let (addr, len) = net.split_once('/').unwrap_or((net, ""));
+                let addr: IpAddr = addr
+                    .parse()
+                    .with_context(|| format!("bad allowed_ips entry {}", net))?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let len = match len {
+                    "" => max,
+                    l => l
+                        .parse()
+                        .ok()
+                        .filter(|l| *l <= max)
+                        .ok_or_else(|| anyhow!("bad allowed_ips entry {}", net))?,
+                };
+                Ok((addr, len))
+            })
+            .collect()
+    }
+}
+
+/// A base64 WireGuard key, as `wg` prints and reads them.
+fn decode_key(key: &str) -> Result<[u8; 32]> {
+    STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|k| k.try_into().ok())
+        .ok_or_else(|| anyhow!("bad WireGuard key {}", key))
+}
+
+struct Enabled {
+    until: i64,
+    ip: Ipv4Addr,
+    client_id: Option<String>,
+}
+
+/// A peer that was just disabled, for logging.
+pub struct Disabled {
+    pub public_key: String,
+    pub ip: Ipv4Addr,
+    pub client_id: Option<String>,
+}
+
+#[derive(Default)]
+pub struct PeerGate {
+    enabled: HashMap<String, Enabled>,
+}
+
+impl PeerGate {
+    /// Remove every gated peer; run once at startup.
+    pub fn disable_all<'a>(
+        &mut self,
+        fw: &mut Firewall,
+        peers: impl Iterator<Item = &'a WgPeer>,
+    ) -> Result<()> {
+        for p in peers {
+            fw.revoke_peer(&p.public_key)?;
+        }
+        self.enabled.clear();
+        Ok(())
+    }
+
+    /// Add `peer` (if not already there) until `until`. Returns true if it
+    /// was disabled before.
+    pub fn enable(
+        &mut self,
+        fw: &mut Firewall,
+        peer: &WgPeer,
+        ip: Ipv4Addr,
+        client_id: Option<&str>,
+        until: i64,
+    ) -> Result<bool> {
+        if let Some(e) = self.enabled.get_mut(&peer.public_key) {
+            e.until = e.until.max(until);
+            e.ip = ip;
+            return Ok(false);
+        }
+        fw.enable_peer(&peer.public_key)?;
+        self.enabled.insert(
+            peer.public_key.clone(),
+            Enabled {
+                until,
+                ip,
+                client_id: client_id.map(str::to_string),
+            },
+        );
+        Ok(true)
+    }
+
+    /// Push an enabled peer's expiry forward (keepalive extension).
+    pub fn extend(&mut self, public_key: &str, until: i64) {
+        if let Some(e) = self.enabled.get_mut(public_key) {
+            e.until = e.until.max(until);
+        }
+    }
+
+    /// When the next enabled peer's grant runs out, in unix seconds.
+    pub fn next_expiry(&self) -> Option<i64> {
+        self.enabled.values().map(|e| e.until).min()
+    }
+
+    /// Remove peers whose grant ran out.
+    pub fn expire(&mut self, fw: &mut Firewall, now: i64) -> Vec<Disabled> {
+        let due: Vec<String> = self
+            .enabled
+            .iter()
+            .filter(|(_, e)| e.until <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut out = Vec::new();
+        for key in due {
+            if let Err(e) = fw.revoke_peer(&key) {
+                // keep it and retry on the next tick
+                eprintln!("disable peer {}: {:#}", key, e);
+                continue;
+            }
+            if let Some(e) = self.enabled.remove(&key) {
+                out.push(Disabled {
+                    public_key: key,
+                    ip: e.ip,
+                    client_id: e.client_id,
+                });
+            }
+        }
+        out
+    }
+}
+
+/// Add `peer` to `iface`, like `wg set IFACE peer K allowed-ips ...`. The
+/// preshared key file holds base64, as for `wg`.
+pub fn add_peer(iface: &str, peer: &WgPeer) -> Result<()> {
+    let preshared_key = match &peer.preshared_key_file {
+        Some(path) => {
+            let text = Secret::new(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("read {}", path.display()))?,
+            );
+            Some(Secret::new(decode_key(text.expose())?))
+        }
+        None => None,
+    };
+    let change = PeerChange::Set {
+        allowed_ips: &peer.networks()?,
+        preshared_key: preshared_key.as_ref().map(Secret::expose),
+    };
+    wgnl::set_peer(iface, &decode_key(&peer.public_key)?, change)
+        .with_context(|| format!("add peer {}", peer.public_key))
+}
+
+/// Remove a peer from `iface`, like `wg set IFACE peer K remove`.
+pub fn remove_peer(iface: &str, public_key: &str) -> Result<()> {
+    wgnl::set_peer(iface, &decode_key(public_key)?, PeerChange::Remove)
+        .with_context(|| format!("remove peer {}", public_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_need_a_key_and_addresses() {
+        let peer: WgPeer = serde_json::from_str(
+            r#"{"public_key": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+                "allowed_ips": ["10.8.0.2/32"]}"#,
+        )
+        .unwrap();
+        assert!(peer.validate().is_ok());
+        let mut bad = peer.clone();
+        bad.public_key = "--help".into();
+        assert!(bad.validate().is_err());
+        let mut bad = peer.clone();
+        bad.allowed_ips.clear();
+        assert!(bad.validate().is_err());
+        let mut bad = peer.clone();
+        bad.allowed_ips = vec!["10.8.0.2/33".into()];
+        assert!(bad.validate().is_err());
+        let mut both = peer;
+        both.allowed_ips = vec!["10.8.0.2".into(), "fd00::/64".into()];
+        assert_eq!(
+            both.networks().unwrap(),
+            [
+                ("10.8.0.2".parse().unwrap(), 32),
+                ("fd00::".parse().unwrap(), 64)
+            ]
+        );
+    }
+}
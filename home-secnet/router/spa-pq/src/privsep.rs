@@ -0,0 +1,288 @@
+// Privilege separation (--privsep).
+//
+// The daemon re-executes itself as `privsep-helper` with one end of a
+// socketpair as its stdin, then drops its own capabilities (CAP_NET_RAW
+// stays only if rotating ports are re-bound to --interface devices). From
+// then on the process that parses knocks and holds the keys cannot touch the
+// firewall except through the helper, which holds no keys and reads no
+// packets.
+//
+// The protocol is JSON lines. The first line is the `Policy`; then the
+// daemon sends `Request`s and the helper answers each with a `Response`
+// carrying the same sequence number. The helper checks every op against
+// the policy (firewall.rs) and the daemon checks every response against its
+// request; a line over the size limit, a sequence mismatch or a dump that
+// was not asked for ends the session.
+
+use crate::firewall::{Backend, Op, Policy};
+use anyhow::{anyhow, Context, Result};
+use caps::{CapSet, Capability, CapsHashSet};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::{AsFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Stdio};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Longest request line; ops are a few dozen bytes.
+const MAX_REQUEST: usize = 1024;
+/// Longest policy or response line (a `wg show dump` of many peers).
+const MAX_LINE: usize = 1 << 20;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Request {
+    seq: u64,
+    cmd: Op,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Response {
+    seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dump: Option<String>,
+}
+
+/// The daemon's end: the helper process and its socket.
+pub struct Helper {
+    child: Child,
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    seq: u64,
+    broken: bool,
+}
+
+impl Helper {
+    /// Start `<this binary> privsep-helper` and hand it `policy`.
+    pub fn spawn(policy: &Policy) -> Result<Self> {
+        let (ours, theirs) = UnixStream::pair().context("socketpair")?;
+        // the helper's stdout goes to our stderr so nft/wg chatter is logged
+        // and cannot interleave with the protocol
+        let log = std::io::stderr().as_fd().try_clone_to_owned()?;
+        let exe = std::env::current_exe().context("locate own binary")?;
+        let child = std::process::Command::new(exe)
+            .arg("privsep-helper")
+            .stdin(Stdio::from(OwnedFd::from(theirs)))
+            .stdout(Stdio::from(log))
+            .spawn()
+            .context("start privsep helper")?;
+        let mut helper = Self {
+            child,
+            reader: BufReader::new(ours.try_clone()?),
+            writer: ours,
+            seq: 0,
+            broken: false,
+        };
+        helper.send(&serde_json::to_string(policy)?)?;
+        Ok(helper)
+    }
+
+    /// Have the helper apply `op`. A refusal or failed command is an error
+    /// for this op only; a broken session fails every later call as well.
+    pub fn call(&mut self, op: &Op) -> Result<Option<String>> {
+        if self.broken {
+            return Err(anyhow!("privsep helper session is broken"));
+        }
+        self.seq += 1;
+        let resp = self.exchange(op).inspect_err(|_| self.broken = true)?;
+        if let Some(e) = resp.error {
+            return Err(anyhow!("privsep helper: {}", e));
+        }
+        Ok(resp.dump)
+    }
+
+    fn exchange(&mut self, op: &Op) -> Result<Response> {
+        let req = Request {
+            seq: self.seq,
+            cmd: op.clone(),
+        };
+        self.send(&serde_json::to_string(&req)?)?;
+        let line = read_line(&mut self.reader, MAX_LINE)?
+            .ok_or_else(|| anyhow!("privsep helper closed the socket"))?;
+        let resp: Response = serde_json::from_str(&line).context("privsep helper response")?;
+        if resp.seq != self.seq {
+            return Err(anyhow!(
+                "privsep helper answered {} to request {}",
+                resp.seq,
+                self.seq
+            ));
+        }
+        if resp.dump.is_some() && !matches!(op, Op::WgDump) {
+            return Err(anyhow!("privsep helper sent output that was not asked for"));
+        }
+        Ok(resp)
+    }
+
+    /// Readable only once the helper has exited: it never writes unasked.
+    pub fn fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.reader.get_ref().as_fd()
+    }
+
+    fn send(&mut self, line: &str) -> Result<()> {
+        self.writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .context("write to privsep helper")
+    }
+
+    /// Fails once the helper has exited or the session broke.
+    pub fn check(&mut self) -> Result<()> {
+        if let Some(status) = self.child.try_wait()? {
+            return Err(anyhow!("privsep helper exited ({})", status));
+        }
+        if self.broken {
+            return Err(anyhow!("privsep helper session is broken"));
+        }
+        Ok(())
+    }
+}
+
+/// The helper side: serve the daemon on stdin until it closes the socket.
+pub fn run_helper() -> Result<()> {
+    // nft and wg need nothing else
+    let keep: CapsHashSet = [Capability::CAP_NET_ADMIN].into_iter().collect();
+    restrict_caps(&keep)?;
+    // a service stop signals the whole group; outlive the daemon's shutdown
+    // (--on-exit flush) and exit when it closes the socket
+    for sig in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(sig, Arc::new(AtomicBool::new(false)))
+            .context("signal handler")?;
+    }
+    let stdin = std::io::stdin().as_fd().try_clone_to_owned()?;
+    serve(UnixStream::from(stdin))
+}
+
+fn serve(stream: UnixStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let Some(line) = read_line(&mut reader, MAX_LINE)? else {
+        return Ok(());
+    };
+    let policy: Policy = serde_json::from_str(&line).context("privsep policy")?;
+    let backend = Backend::new(policy);
+    while let Some(line) = read_line(&mut reader, MAX_REQUEST)? {
+        // a request we cannot parse has no sequence number to answer with
+        let req: Request = serde_json::from_str(&line).context("privsep request")?;
+        let resp = match backend.apply(&req.cmd) {
+            Ok(dump) => Response {
+                seq: req.seq,
+                error: None,
+                dump,
+            },
+            Err(e) => {
+                eprintln!("privsep: {:?} refused: {:#}", req.cmd, e);
+                Response {
+                    seq: req.seq,
+                    error: Some(format!("{:#}", e)),
+                    dump: None,
+                }
+            }
+        };
+        writer.write_all(format!("{}\n", serde_json::to_string(&resp)?).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// One line without its newline, or None at end of stream. Lines longer than
+/// `max` are an error rather than a reason to buffer without bound.
+fn read_line(reader: &mut impl BufRead, max: usize) -> Result<Option<String>> {
+    let mut buf = Vec::new();
+    let n = reader
+        .by_ref()
+        .take(max as u64 + 1)
+        .read_until(b'\n', &mut buf)
+        .context("read privsep socket")?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if buf.pop() != Some(b'\n') {
+        return Err(anyhow!("privsep line too long or truncated"));
+    }
+    Ok(Some(String::from_utf8(buf).context("privsep line")?))
+}
+
+/// Give up every capability; the helper keeps the firewall. Sockets already
+/// open stay usable.
+pub fn drop_privileges(keep_net_raw: bool) -> Result<()> {
+    let mut keep = CapsHashSet::new();
+    if keep_net_raw {
+        keep.insert(Capability::CAP_NET_RAW);
+    }
+    restrict_caps(&keep)
+}
+
+fn restrict_caps(keep: &CapsHashSet) -> Result<()> {
+    caps::clear(None, CapSet::Ambient).context("clear ambient capabilities")?;
+    caps::clear(None, CapSet::Inheritable).context("clear inheritable capabilities")?;
+    let permitted = caps::read(None, CapSet::Permitted)?;
+    let keep: CapsHashSet = keep.intersection(&permitted).copied().collect();
+    caps::set(None, CapSet::Effective, &keep).context("set effective capabilities")?;
+    caps::set(None, CapSet::Permitted, &keep).context("set permitted capabilities")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firewall::Service;
+    use std::net::Ipv4Addr;
+
+    fn start(policy: &Policy) -> (UnixStream, std::thread::JoinHandle<Result<()>>) {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || serve(theirs));
+        let line = serde_json::to_string(policy).unwrap();
+        ours.write_all(format!("{}\n", line).as_bytes()).unwrap();
+        (ours, server)
+    }
+
+    fn ask(conn: &mut BufReader<UnixStream>, seq: u64, cmd: Op) -> Response {
+        let line = serde_json::to_string(&Request { seq, cmd }).unwrap();
+        conn.get_mut()
+            .write_all(format!("{}\n", line).as_bytes())
+            .unwrap();
+        let resp = read_line(conn, MAX_LINE).unwrap().unwrap();
+        serde_json::from_str(&resp).unwrap()
+    }
+
+    #[test]
+    fn helper_refuses_ops_outside_its_policy() {
+        let policy = Policy {
+            family: "inet".into(),
+            table: "fw4".into(),
+            wg_set: "wg_spa_allow".into(),
+            max_ttl: 45,
+            ..Default::default()
+        };
+        let (ours, server) = start(&policy);
+        let mut conn = BufReader::new(ours);
+        let over_ttl = Op::Grant {
+            service: Service::Wg,
+            ip: Ipv4Addr::new(198, 51, 100, 7),
+            port: None,
+            ttl: 3600,
+            client_id: None,
+        };
+        let resp = ask(&mut conn, 7, over_ttl);
+        assert_eq!(resp.seq, 7);
+        assert!(resp.error.unwrap().contains("timeout"));
+        let resp = ask(&mut conn, 8, Op::WgDump);
+        assert_eq!((resp.seq, resp.dump), (8, None));
+        assert!(resp.error.is_some());
+        drop(conn);
+        // closing the socket ends the helper cleanly
+        assert!(server.join().unwrap().is_ok());
+
+        // an op it does not know ends the session
+        let (mut ours, server) = start(&policy);
+        ours.write_all(b"{\"seq\":1,\"cmd\":{\"op\":\"exec\"}}\n")
+            .unwrap();
+        assert!(server.join().unwrap().is_err());
+        // so does an overlong line
+        let (mut ours, server) = start(&policy);
+        ours.write_all(&[b' '; MAX_REQUEST + 8]).unwrap();
+        assert!(server.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,332 @@
+// `provision <name>`: onboard a device in one step.
+//
+// Generates the device's SPA credential (its own 32-byte PSK, see clients.rs)
+// and a WireGuard keypair, allocates the lowest free tunnel address in
+// --wg-net and registers both on the router: the PSK as `psk_file` in
+// --clients, the peer either as a `[Peer]` in --wg-config or, with
+// --peer-gating, as the client's `wg_peer`. The bundle written to --out holds
+// the client JSON, with the KEM fingerprint pinned (and, with --rotate-ports,
+// the port secret the schedule derives from), and a wg-quick config; the
+// latter is also shown as a QR code for mobile WireGuard apps.
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::clients::{Cidr, ClientPolicy};
+use crate::fingerprint::Fingerprint;
+use crate::secrets::{self, Unseal};
+use crate::{listen, payload, write_file, SecretKind};
+
+#[derive(clap::Args, Debug)]
+pub struct ProvisionArgs {
+    /// Device name; becomes its client id
+    name: String,
+    /// Client policy file the device is registered in (daemon --clients)
+    #[arg(long, default_value = "/etc/spa/clients.json")]
+    clients: PathBuf,
+    /// Directory for per-device PSKs
+    #[arg(long, default_value = "/etc/spa/clients")]
+    psk_dir: PathBuf,
+    /// KEM public key path (raw bytes)
+    #[arg(long, default_value = "/etc/spa/kem_pub.bin")]
+    kem_pub: PathBuf,
+    /// Public address or name clients knock and connect to
+    #[arg(long)]
+    router_host: String,
+    /// SPA knock port
+    #[arg(long, default_value_t = 62201)]
+    spa_port: u16,
+    /// WireGuard UDP port
+    #[arg(long, default_value_t = 51820)]
+    wg_port: u16,
+    /// Tunnel network to allocate the device address from, e.g. 10.8.0.0/24
+    #[arg(long)]
+    wg_net: Cidr,
+    /// Router's tunnel address (default: first host of --wg-net)
+    #[arg(long)]
+    wg_server_ip: Option<Ipv4Addr>,
+    /// Router WireGuard interface, used to read its public key
+    #[arg(long, default_value = "wg0")]
+    wg_interface: String,
+    /// Router WireGuard public key (default: `wg show <wg-interface> public-key`)
+    #[arg(long)]
+    wg_server_pub: Option<String>,
+    /// Router wg-quick config; scanned for used addresses and, without
+    /// --peer-gating, given the new [Peer]
+    #[arg(long, default_value = "/etc/wireguard/wg0.conf")]
+    wg_config: PathBuf,
+    /// Register the peer as the client's `wg_peer` (daemon --peer-gating)
+    /// instead of in --wg-config
+    #[arg(long)]
+    peer_gating: bool,
+    /// AllowedIPs routed through the tunnel on the device
+    #[arg(long, default_value = "0.0.0.0/0")]
+    client_allowed_ips: String,
+    /// DNS server for the device's wg-quick config
+    #[arg(long)]
+    dns: Option<String>,
+    /// Pin the device's WireGuard ListenPort and declare it in knocks
+    /// (daemon --port-grants)
+    #[arg(long)]
+    wg_src_port: Option<u16>,
+    /// Knock on the rotating ports (daemon --rotate-ports)
+    #[arg(long, value_parser = listen::parse_port_range)]
+    rotate_ports: Option<(u16, u16)>,
+    /// Rotation slot length (daemon --rotate-slot-secs)
+    #[arg(long, default_value_t = 60)]
+    rotate_slot_secs: u64,
+    /// Secret the rotating ports derive from (daemon --port-secret-file)
+    #[arg(long, default_value = "/etc/spa/port_secret.bin")]
+    port_secret_file: PathBuf,
+    /// Bundle directory (default: ./<name>)
+    #[arg(long)]
+    out: Option<PathBuf>,
+}
+
+pub fn run(args: &ProvisionArgs) -> Result<()> {
+    let name = &args.name;
+    if !payload::valid_client_id(name) {
+        return Err(anyhow!(
+            "device name must be 1-32 characters of A-Z a-z 0-9 - _ ."
+        ));
+    }
+    let mut registry = load_registry(&args.clients)?;
+    if registry.contains_key(name) {
+        return Err(anyhow!("{} is already in {}", name, args.clients.display()));
+    }
+    let kem_pub =
+        fs::read(&args.kem_pub).with_context(|| format!("read {}", args.kem_pub.display()))?;
+    let server_pub = match &args.wg_server_pub {
+        Some(k) => k.clone(),
+        None => wg_public_key(&args.wg_interface)?,
+    };
+    let wg_config = match fs::read_to_string(&args.wg_config) {
+        Ok(text) => text,
+        Err(e) => return Err(e).with_context(|| format!("read {}", args.wg_config.display())),
+    };
+
+    let server_ip = match args.wg_server_ip {
+        Some(ip) => ip,
+        None => args
+            .wg_net
+            .hosts()
+            .next()
+            .ok_or_else(|| anyhow!("--wg-net has no host addresses"))?,
+    };
+    let mut used = used_addresses(&registry, &wg_config);
+    used.insert(server_ip);
+    let address = args
+        .wg_net
+        .hosts()
+        .find(|ip| !used.contains(ip))
+        .ok_or_else(|| anyhow!("no free address left in the tunnel network"))?;
+
+    let spa_psk = random_key()?;
+    let wg_psk = STANDARD.encode(random_key()?);
+    let wg_secret = StaticSecret::from(random_key()?);
+    let wg_pub = STANDARD.encode(PublicKey::from(&wg_secret).as_bytes());
+    let wg_priv = STANDARD.encode(wg_secret.to_bytes());
+
+    // the bundle first: a failure leaves nothing registered
+    let out = args.out.clone().unwrap_or_else(|| PathBuf::from(name));
+    fs::create_dir_all(&out).with_context(|| format!("create {}", out.display()))?;
+    let mut client = json!({
+        "router_host": args.router_host,
+        "spa_port": args.spa_port,
+        "wg_port": args.wg_port,
+        "kem_pub_b64": STANDARD.encode(&kem_pub),
+        "psk_b64": STANDARD.encode(spa_psk),
+        "client_id": name,
+        "kem_fingerprint": Fingerprint::kem_pub(&kem_pub).hex(),
+    });
+    if let Some(port) = args.wg_src_port {
+        client["wg_src_port"] = json!(port);
+    }
+    if let Some((min, max)) = args.rotate_ports {
+        let secret = secrets::load(
+            &args.port_secret_file,
+            SecretKind::PortSecret.len(),
+            &Unseal::default(),
+        )?;
+        client["rotate_ports"] = json!(format!("{}-{}", min, max));
+        client["rotate_slot_secs"] = json!(args.rotate_slot_secs);
+        client["port_secret_b64"] = json!(STANDARD.encode(secret.expose()));
+    }
+    let client_path = out.join("spa-pq-client.json");
+    write_file(
+        &client_path,
+        serde_json::to_string_pretty(&client)?.as_bytes(),
+        Some(0o600),
+    )?;
+    let conf = client_conf(args, &wg_priv, address, &server_pub, &wg_psk);
+    let conf_path = out.join(format!("{}.conf", name));
+    write_file(&conf_path, conf.as_bytes(), Some(0o600))?;
+
+    // then the router side
+    fs::create_dir_all(&args.psk_dir)
+        .with_context(|| format!("create {}", args.psk_dir.display()))?;
+    // the daemon and `wg` resolve these from wherever they run
+    let psk_dir = fs::canonicalize(&args.psk_dir)?;
+    let psk_file = psk_dir.join(format!("{}.psk", name));
+    write_file(&psk_file, &spa_psk, Some(0o600))?;
+    let mut entry = json!({ "psk_file": psk_file });
+    let allowed_ip = format!("{}/32", address);
+    if args.peer_gating {
+        let wg_psk_file = psk_dir.join(format!("{}.wgpsk", name));
+        write_file(
+            &wg_psk_file,
+            format!("{}\n", wg_psk).as_bytes(),
+            Some(0o600),
+        )?;
+        entry["wg_peer"] = json!({
+            "public_key": wg_pub,
+            "allowed_ips": [allowed_ip],
+            "preshared_key_file": wg_psk_file,
+        });
+    } else {
+        let peer = format!(
+            "\n# {}\n[Peer]\nPublicKey = {}\nPresharedKey = {}\nAllowedIPs = {}\n",
+            name, wg_pub, wg_psk, allowed_ip
+        );
+        write_file(
+            &args.wg_config,
+            format!("{}{}", wg_config, peer).as_bytes(),
+            Some(0o600),
+        )?;
+    }
+    registry.insert(name.clone(), entry);
+    save_registry(&args.clients, &registry)?;
+
+    println!(
+        "{}",
+        QrCode::new(conf.as_bytes())?.render::<Dense1x2>().build()
+    );
+    println!("Provisioned {} with tunnel address {}.", name, address);
+    println!("  client config:    {}", client_path.display());
+    println!("  wg-quick config:  {}", conf_path.display());
+    println!("  registered in:    {}", args.clients.display());
+    if args.peer_gating {
+        println!("Restart the daemon to load the new client and its peer.");
+    } else {
+        println!(
+            "Restart the daemon to load the new client, and apply the peer with \
+             `wg syncconf {} <(wg-quick strip {})`.",
+            args.wg_interface, args.wg_interface
+        );
+    }
+    Ok(())
+}
+
+fn client_conf(
+    args: &ProvisionArgs,
+    private_key: &str,
+    address: Ipv4Addr,
+    server_pub: &str,
+    psk: &str,
+) -> String {
+    let mut conf = format!("[Interface]\nPrivateKey = {}\n", private_key);
+    conf += &format!("Address = {}/32\n", address);
+    if let Some(port) = args.wg_src_port {
+        conf += &format!("ListenPort = {}\n", port);
+    }
+    if let Some(dns) = &args.dns {
+        conf += &format!("DNS = {}\n", dns);
+    }
+    conf += &format!(
+        "\n[Peer]\nPublicKey = {}\nPresharedKey = {}\nEndpoint = {}:{}\nAllowedIPs = {}\nPersistentKeepalive = 25\n",
+        server_pub, psk, args.router_host, args.wg_port, args.client_allowed_ips
+    );
+    conf
+}
+
+/// The client file as raw JSON, so registering a device keeps every other
+/// entry as written. A missing file is an empty registry.
+fn load_registry(path: &Path) -> Result<Map<String, Value>> {
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text)
+            .with_context(|| format!("parse client policies in {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Map::new()),
+        Err(e) => Err(e).with_context(|| format!("read {}", path.display())),
+    }
+}
+
+fn save_registry(path: &Path, registry: &Map<String, Value>) -> Result<()> {
+    // refuse to write anything the daemon would not load
+    serde_json::from_value::<BTreeMap<String, ClientPolicy>>(Value::Object(registry.clone()))
+        .context("client policies")?;
+    let text = serde_json::to_string_pretty(registry)? + "\n";
+    fs::write(path, text).with_context(|| format!("write {}", path.display()))
+}
+
+/// Tunnel addresses taken by gated peers in the registry and by `Address`
+/// or `AllowedIPs` lines of the router's wg-quick config.
+fn used_addresses(registry: &Map<String, Value>, wg_config: &str) -> HashSet<Ipv4Addr> {
+    let from_registry = registry
+        .values()
+        .filter_map(|c| c.pointer("/wg_peer/allowed_ips")?.as_array())
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(str::to_string);
+    let from_config = wg_config.lines().filter_map(|l| {
+        let (key, value) = l.split_once('=')?;
+        matches!(key.trim(), "Address" | "AllowedIPs").then(|| value.to_string())
+    });
+    from_registry
+        .chain(from_config)
+        .flat_map(|v| {
+            v.split(',')
+                .filter_map(|a| a.trim().split('/').next()?.parse().ok())
+                .collect::<Vec<Ipv4Addr>>()
+        })
+        .collect()
+}
+
+fn wg_public_key(iface: &str) -> Result<String> {
+    let out = std::process::Command::new("wg")
+        .args(["show", iface, "public-key"])
+        .output()
+        .context("run wg show")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "wg show {} public-key failed; pass --wg-server-pub",
+            iface
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+fn random_key() -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key).map_err(|e| anyhow!(e))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowest_free_address_is_allocated() {
+        let registry: Map<String, Value> = serde_json::from_str(
+            r#"{"phone": {"wg_peer": {"public_key": "k", "allowed_ips": ["10.8.0.3/32"]}},
+                "tv": {"target": "match"}}"#,
+        )
+        .unwrap();
+        let conf =
+            "[Interface]\nAddress = 10.8.0.1/24\n\n[Peer]\nAllowedIPs = 10.8.0.2/32, fd00::2/128\n";
+        let used = used_addresses(&registry, conf);
+        let net: Cidr = "10.8.0.0/24".parse().unwrap();
+        let free = net.hosts().find(|ip| !used.contains(ip));
+        assert_eq!(free, Some(Ipv4Addr::new(10, 8, 0, 4)));
+        assert_eq!(net.hosts().count(), 254);
+    }
+}
@@ -0,0 +1,118 @@
+// Replies to valid knocks
+//
+// `ok` keeps the legacy plaintext "OK". `encrypted` answers with
+// nonce(12) | ChaCha20-Poly1305(ack_key, Ack) which is indistinguishable from
+// random to anyone without the knock's shared secret. `silent` never answers.
+// Invalid knocks never get a reply in any mode.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::secrets::Secret;
+
+const HKDF_INFO: &[u8] = b"open-winder spa-pq ack";
+const ACK_NONCE_LEN: usize = 12;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyMode {
+    /// Plaintext "OK" (legacy)
+    Ok,
+    /// AEAD-sealed acknowledgement keyed from the knock
+    Encrypted,
+    /// Never reply
+    Silent,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Ack {
+    pub grant_secs: u64,
+    /// Base64 session ticket id for compact knocks (see ticket.rs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket_secs: Option<u64>,
+}
+
+/// Per-knock key for the acknowledgement, bound to the KEM shared secret.
+pub fn ack_key(shared: &[u8], psk: &[u8]) -> Secret<[u8; 32]> {
+    let hk = Hkdf::<Sha256>::new(Some(psk), shared);
+    let mut okm = Secret::new([0u8; 32]);
+    hk.expand(HKDF_INFO, okm.expose_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+pub fn seal_ack(key: &[u8; 32], ack: &Ack) -> anyhow::Result<Vec<u8>> {
+    let mut nonce = [0u8; ACK_NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow::anyhow!(e))?;
+    seal_ack_with(key, ack, nonce)
+}
+
+fn seal_ack_with(key: &[u8; 32], ack: &Ack, nonce: [u8; ACK_NONCE_LEN]) -> anyhow::Result<Vec<u8>> {
+    let plain = serde_json::to_vec(ack)?;
+    let ct = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&nonce), plain.as_slice())
+        .map_err(|_| anyhow::anyhow!("ack seal"))?;
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&ct);
+    Ok(out)
+}
+
+/// Reply bytes for a successful knock, if the mode sends any.
+pub fn build(mode: ReplyMode, key: &[u8; 32], ack: &Ack) -> Option<Vec<u8>> {
+    match mode {
+        ReplyMode::Ok => Some(b"OK".to_vec()),
+        ReplyMode::Encrypted => seal_ack(key, ack).ok(),
+        ReplyMode::Silent => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_ack_roundtrip_and_modes() {
+        let key = ack_key(&[1u8; 32], &[2u8; 32]);
+        let key = key.expose();
+        let ack = Ack {
+            grant_secs: 45,
+            ..Default::default()
+        };
+        let sealed = build(ReplyMode::Encrypted, key, &ack).unwrap();
+        assert!(!sealed.starts_with(b"OK"));
+        let (nonce, ct) = sealed.split_at(ACK_NONCE_LEN);
+        let plain = ChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(Nonce::from_slice(nonce), ct)
+            .unwrap();
+        let got: Ack = serde_json::from_slice(&plain).unwrap();
+        assert_eq!(got, ack);
+        assert!(build(ReplyMode::Silent, key, &ack).is_none());
+        assert_eq!(build(ReplyMode::Ok, key, &ack).unwrap(), b"OK");
+    }
+    #[test]
+    fn seals_the_client_vector() {
+        // the client's reply::tests::opens_an_ack_sealed_by_the_daemon opens
+        // exactly this reply
+        let key = ack_key(&[1u8; 32], &[2u8; 32]);
+        let ack = Ack {
+            grant_secs: 45,
+            ticket: Some("BQUFBQUFBQUFBQUFBQUFBQ==".into()),
+            ticket_secs: Some(86400),
+        };
+        let nonce = [
+            0x03, 0x63, 0x18, 0x64, 0x93, 0x0e, 0x78, 0x40, 0x67, 0x3c, 0x17, 0xbb,
+        ];
+        let sealed = seal_ack_with(key.expose(), &ack, nonce).unwrap();
+        let hex: String = sealed.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "03631864930e7840673c17bbca9c54cc945e7d32f997d193743942313736a0d6a7c5d1d78f062aacce\
+             7111c7e713b88df69cf3fa1e73f9458af23fa8abf80181217abbfbdb827b150917b983a39b21c2b023\
+             31b7fc1b5ed5fe57b69fbac4d1682ccbcbd94e"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+// Self-sandboxing of the knock-reading process (on unless --no-sandbox; `run`
+// refuses to start with neither --privsep nor --no-sandbox).
+//
+// Once keys are loaded, sockets bound and capabilities dropped (--privsep),
+// the daemon confines itself:
+// - Landlock: no filesystem access outside --state-dir, and only reads there.
+// - seccomp: an allowlist of what the receive loop uses (socket I/O, memory,
+//   time, threads for TCP knocks, the helper's socket) and nothing else. No
+//   exec, no fork, no new socket families, no executable mappings. Anything
+//   else fails with EPERM.
+// The helper still runs nft and wg, so it is not sandboxed; that is why the
+// sandbox needs --privsep. Landlock binds the calling thread and the threads
+// it spawns later, so `enter` runs while the daemon is single-threaded; the
+// seccomp filters are synced onto every thread (TSYNC) as they go in, and TCP
+// knock threads inherit both.
+//
+// seccompiler builds filters for x86_64, aarch64 and riscv64 only; on other
+// targets (most OpenWRT routers) the filter is left out with a warning.
+// Kernels without Landlock likewise only get a warning.
+
+use anyhow::{anyhow, Context, Result};
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use std::path::Path;
+
+/// Confine this process; see the module comment.
+pub fn enter(state_dir: &Path) -> Result<()> {
+    restrict_paths(state_dir)?;
+    seccomp::enter()
+}
+
+fn restrict_paths(state_dir: &Path) -> Result<()> {
+    if !state_dir.is_dir() {
+        return Err(anyhow!(
+            "--state-dir {} is not a directory",
+            state_dir.display()
+        ));
+    }
+    let abi = ABI::V3;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules([state_dir], AccessFs::from_read(abi)))?
+        .restrict_self()
+        .context("landlock")?;
+    if status.ruleset == RulesetStatus::NotEnforced {
+        eprintln!("sandbox: kernel without Landlock; filesystem access not restricted");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+mod seccomp {
+    pub fn enter() -> anyhow::Result<()> {
+        eprintln!(
+            "sandbox: no seccomp filter for {}; continuing without one",
+            std::env::consts::ARCH
+        );
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod seccomp {
+    use anyhow::{Context, Result};
+    use nix::libc;
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule, TargetArch,
+    };
+    use std::collections::BTreeMap;
+
+    /// Syscalls allowed with any arguments.
+    const ALLOWED: &[libc::c_long] = &[
+        // sockets already open, the helper's socket, stdout and stderr
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_close,
+        libc::SYS_recvfrom,
+        libc::SYS_recvmsg,
+        libc::SYS_sendto,
+        libc::SYS_sendmsg,
+        libc::SYS_accept4,
+        libc::SYS_shutdown,
+        libc::SYS_bind,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_fcntl,
+        // waiting for knocks (aarch64 and riscv64 only have ppoll)
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        libc::SYS_ppoll,
+        // files: Landlock decides which
+        libc::SYS_openat,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_lseek,
+        libc::SYS_readlinkat,
+        // memory
+        libc::SYS_brk,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_madvise,
+        // time
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_gettimeofday,
+        // threads, signals, exit
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_sigaltstack,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        // nonces, privsep helper status
+        libc::SYS_getrandom,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+    ];
+
+    /// Install the filters on every thread.
+    pub fn enter() -> Result<()> {
+        let arch = TargetArch::try_from(std::env::consts::ARCH)?;
+        for prog in filters(arch)? {
+            seccompiler::apply_filter_all_threads(&prog).context("install seccomp filter")?;
+        }
+        Ok(())
+    }
+
+    /// A filter that makes clone3 look unimplemented, then the allowlist.
+    /// clone3's flags sit behind a pointer, so libc must fall back to clone,
+    /// whose flags can be checked; the allowlist lets clone3 through, and the
+    /// kernel takes the strictest answer. The allowlist goes last, as it
+    /// denies the prctl that installing a filter needs.
+    fn filters(arch: TargetArch) -> Result<Vec<BpfProgram>> {
+        let mut rules: BTreeMap<i64, Vec<SeccompRule>> =
+            ALLOWED.iter().map(|&nr| (nr, Vec::new())).collect();
+        let arg =
+            |index, op, value| SeccompCondition::new(index, SeccompCmpArgLen::Qword, op, value);
+        let one = |cond: SeccompCondition| SeccompRule::new(vec![cond]);
+        let no_exec = || arg(2, SeccompCmpOp::MaskedEq(libc::PROT_EXEC as u64), 0);
+        // threads only, never a new process
+        rules.insert(
+            libc::SYS_clone,
+            vec![one(arg(
+                0,
+                SeccompCmpOp::MaskedEq(libc::CLONE_THREAD as u64),
+                libc::CLONE_THREAD as u64,
+            )?)?],
+        );
+        rules.insert(libc::SYS_clone3, Vec::new());
+        rules.insert(libc::SYS_mmap, vec![one(no_exec()?)?]);
+        rules.insert(libc::SYS_mprotect, vec![one(no_exec()?)?]);
+        // rotating ports re-bind UDP sockets
+        rules.insert(
+            libc::SYS_socket,
+            vec![
+                one(arg(0, SeccompCmpOp::Eq, libc::AF_INET as u64)?)?,
+                one(arg(0, SeccompCmpOp::Eq, libc::AF_INET6 as u64)?)?,
+            ],
+        );
+        // set_nonblocking
+        rules.insert(
+            libc::SYS_ioctl,
+            vec![one(arg(1, SeccompCmpOp::Eq, libc::FIONBIO)?)?],
+        );
+        // thread names (TCP knocks)
+        rules.insert(
+            libc::SYS_prctl,
+            vec![one(arg(0, SeccompCmpOp::Eq, libc::PR_SET_NAME as u64)?)?],
+        );
+        let allow = SeccompFilter::new(
+            rules,
+            SeccompAction::Errno(libc::EPERM as u32),
+            SeccompAction::Allow,
+            arch,
+        )?;
+        let no_clone3 = SeccompFilter::new(
+            [(libc::SYS_clone3, Vec::new())].into_iter().collect(),
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::ENOSYS as u32),
+            arch,
+        )?;
+        Ok(vec![no_clone3.try_into()?, allow.try_into()?])
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::UdpSocket;
+        use std::os::unix::net::UnixStream;
+
+        #[test]
+        fn forbidden_syscalls_are_denied() {
+            let arch = TargetArch::try_from(std::env::consts::ARCH).unwrap();
+            // filters bind only the thread that installs them (and its children)
+            std::thread::spawn(move || {
+                for prog in filters(arch).unwrap() {
+                    seccompiler::apply_filter(&prog).unwrap();
+                }
+                let eperm = |e: std::io::Error| e.raw_os_error() == Some(libc::EPERM);
+                assert!(std::process::Command::new("true")
+                    .status()
+                    .is_err_and(eperm));
+                assert!(UnixStream::pair().is_err_and(eperm));
+                // ENOENT without the filter
+                assert!(std::fs::remove_file("/nonexistent/spa-pq").is_err_and(eperm));
+                // the receive loop still works, TCP knock threads included
+                let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+                sock.set_nonblocking(true).unwrap();
+                let mut fds = [nix::poll::PollFd::new(
+                    std::os::fd::AsFd::as_fd(&sock),
+                    nix::poll::PollFlags::POLLIN,
+                )];
+                assert_eq!(nix::poll::poll(&mut fds, 0u8).unwrap(), 0);
+                std::thread::spawn(|| ()).join().unwrap();
+            })
+            .join()
+            .unwrap();
+        }
+
+        /// `enter` confines the whole process for good, so it runs in a child
+        /// test process that this variable points at a state dir.
+        const ENTER_CHILD: &str = "SPA_PQ_SANDBOX_ENTER_DIR";
+
+        #[test]
+        fn enter_confines_the_process() {
+            if let Some(dir) = std::env::var_os(ENTER_CHILD) {
+                return entered(std::path::Path::new(&dir));
+            }
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("key"), b"k").unwrap();
+            let out = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "sandbox::seccomp::tests::enter_confines_the_process",
+                    "--exact",
+                    "--test-threads=1",
+                ])
+                .env(ENTER_CHILD, dir.path())
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            assert!(
+                out.status.success() && stdout.contains("1 passed"),
+                "{}{}",
+                stdout,
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+
+        fn entered(dir: &std::path::Path) {
+            let eperm = |e: std::io::Error| e.raw_os_error() == Some(libc::EPERM);
+            // a thread from before `enter` gets the filters too (TSYNC)
+            let (go, wait) = std::sync::mpsc::channel::<()>();
+            let early = std::thread::spawn(move || {
+                wait.recv().unwrap();
+                UnixStream::pair().map(drop)
+            });
+            crate::sandbox::enter(dir).unwrap();
+            go.send(()).unwrap();
+            assert!(early.join().unwrap().is_err_and(eperm));
+            assert!(UnixStream::pair().is_err_and(eperm));
+            // Landlock: reads beneath the state dir only, here and in new threads
+            let inside = dir.join("key");
+            assert_eq!(std::fs::read(&inside).unwrap(), b"k");
+            let denied = |e: std::io::Error| e.kind() == std::io::ErrorKind::PermissionDenied;
+            assert!(std::fs::read("/etc/passwd").is_err_and(denied));
+            assert!(std::fs::write(&inside, b"x").is_err_and(denied));
+            std::thread::spawn(move || {
+                assert!(std::fs::read(&inside).is_ok());
+                assert!(std::fs::read("/etc/passwd").is_err_and(denied));
+            })
+            .join()
+            .unwrap();
+        }
+    }
+}
@@ -0,0 +1,484 @@
+// Secret loading: --kem-priv, --psk-file and the `psk_file`s of --clients.
+//
+// A secret path is either a file or `cred:NAME`, which reads NAME from
+// $CREDENTIALS_DIRECTORY (systemd LoadCredential=/LoadCredentialEncrypted=).
+// The content may be the raw key, its base64 or hex text, or a sealed
+// envelope written by `seal-key`:
+//
+//   "OWSEAL" | version (1) | kdf (1) | m_kib, t, p (u32 BE each) | salt (16)
+//   | nonce (12) | ChaCha20-Poly1305(key, nonce, secret, aad = all of the above)
+//
+// kdf 1 derives the key from a passphrase with Argon2id(m_kib, t, p, salt),
+// the costs no higher than `seal` writes, so a planted file cannot make the
+// daemon allocate or spin without bound; kdf 2 from a key file with
+// HKDF-SHA256(salt), the cost fields then zero.
+//
+// Loaded secrets are `Secret`s: wiped on drop and redacted in `{:?}`. The
+// pqcrypto key and shared-secret types cannot be wiped, so the daemon keeps
+// the KEM key as bytes and copies shared secrets out at once (main.rs).
+// `harden` keeps all of it out of core dumps and, where allowed, swap.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+const MAGIC: &[u8] = b"OWSEAL";
+const VERSION: u8 = 1;
+const KDF_PASSPHRASE: u8 = 1;
+const KDF_KEY_FILE: u8 = 2;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 12 + SALT_LEN + NONCE_LEN;
+const HKDF_INFO: &[u8] = b"open-winder spa-pq sealed key v1";
+/// Argon2id cost for new envelopes: 64 MiB, 3 passes, 1 lane. Also the most
+/// an envelope may ask for.
+const ARGON2_M_KIB: u32 = 64 * 1024;
+const ARGON2_T: u32 = 3;
+const ARGON2_P: u32 = 1;
+/// Key files shorter than this are rejected as unwrapping keys.
+const MIN_KEY_FILE_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("{0}: cred: path used but $CREDENTIALS_DIRECTORY is not set (run under systemd with LoadCredential=)")]
+    NoCredentialsDirectory(String),
+    #[error("{path}: cannot read: {source}")]
+    Unreadable {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{path}: {got} bytes, expected {want} (raw, base64, hex or sealed)")]
+    WrongLength {
+        path: String,
+        got: usize,
+        want: usize,
+    },
+    #[error("{0}: sealed with envelope version {1}, this build reads version {VERSION}")]
+    UnsupportedVersion(String, u8),
+    #[error("{0}: sealed envelope is truncated or has an unknown key derivation")]
+    BadEnvelope(String),
+    #[error("{path}: Argon2 cost m={m_kib} KiB, t={t}, p={p} is above what seal-key writes ({ARGON2_M_KIB} KiB, {ARGON2_T}, {ARGON2_P})")]
+    KdfCost {
+        path: String,
+        m_kib: u32,
+        t: u32,
+        p: u32,
+    },
+    #[error("{0}: sealed with a passphrase; pass --unseal-passphrase-file")]
+    NeedsPassphrase(String),
+    #[error("{0}: sealed with a key file; pass --unseal-key-file")]
+    NeedsKeyFile(String),
+    #[error("{0}: cannot unseal: wrong passphrase or key file, or the file was modified")]
+    Unseal(String),
+    #[error("{0}: passphrase is empty")]
+    EmptyPassphrase(String),
+    #[error("{0}: unwrapping key must be at least {MIN_KEY_FILE_LEN} bytes")]
+    ShortKeyFile(String),
+    #[error("key derivation: {0}")]
+    Kdf(String),
+}
+
+/// Key material: wiped when dropped, never shown by `{:?}`.
+pub struct Secret<T: Zeroize>(T);
+
+pub type SecretBytes = Secret<Vec<u8>>;
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> ZeroizeOnDrop for Secret<T> {}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+/// Keep secrets out of core dumps: not dumpable (which also blocks ptrace
+/// by other users) and RLIMIT_CORE zero, so a child cannot raise it again.
+pub fn harden() -> nix::Result<()> {
+    use nix::sys::resource::{setrlimit, Resource};
+    nix::sys::prctl::set_dumpable(false)?;
+    setrlimit(Resource::RLIMIT_CORE, 0, 0)
+}
+
+/// Lock pages in RAM (mlockall), keeping secrets out of swap. Needs
+/// CAP_IPC_LOCK or a large enough RLIMIT_MEMLOCK. Future pages are only
+/// locked under an unlimited RLIMIT_MEMLOCK: once capabilities are dropped
+/// (--privsep), every allocation past a finite limit would fail.
+pub fn lock_memory() -> nix::Result<()> {
+    use nix::sys::mman::{mlockall, MlockAllFlags};
+    use nix::sys::resource::{getrlimit, Resource, RLIM_INFINITY};
+    let (soft, _) = getrlimit(Resource::RLIMIT_MEMLOCK)?;
+    let mut flags = MlockAllFlags::MCL_CURRENT;
+    if soft == RLIM_INFINITY {
+        flags |= MlockAllFlags::MCL_FUTURE;
+    }
+    mlockall(flags)
+}
+
+/// What unseals sealed secrets; both are secret paths themselves.
+#[derive(Clone, Debug, Default)]
+pub struct Unseal {
+    pub passphrase_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+impl Unseal {
+    fn key(&self, kdf: u8, header: &[u8], path: &str) -> Result<Zeroizing<[u8; 32]>, SecretError> {
+        let salt = &header[MAGIC.len() + 14..MAGIC.len() + 14 + SALT_LEN];
+        match kdf {
+            KDF_PASSPHRASE => {
+                let pp = self
+                    .passphrase_file
+                    .as_deref()
+                    .ok_or_else(|| SecretError::NeedsPassphrase(path.to_string()))?;
+                let cost = |i: usize| {
+                    let off = MAGIC.len() + 2 + 4 * i;
+                    u32::from_be_bytes(header[off..off + 4].try_into().expect("4 bytes"))
+                };
+                let (m_kib, t, p) = (cost(0), cost(1), cost(2));
+                if m_kib > ARGON2_M_KIB || t > ARGON2_T || p > ARGON2_P {
+                    return Err(SecretError::KdfCost {
+                        path: path.to_string(),
+                        m_kib,
+                        t,
+                        p,
+                    });
+                }
+                argon2_key(&read_passphrase(pp)?, salt, m_kib, t, p)
+            }
+            KDF_KEY_FILE => {
+                let kf = self
+                    .key_file
+                    .as_deref()
+                    .ok_or_else(|| SecretError::NeedsKeyFile(path.to_string()))?;
+                hkdf_key(&read_key_file(kf)?, salt)
+            }
+            _ => Err(SecretError::BadEnvelope(path.to_string())),
+        }
+    }
+}
+
+/// Load a secret of exactly `len` bytes.
+pub fn load(path: &Path, len: usize, unseal: &Unseal) -> Result<SecretBytes, SecretError> {
+    let (name, data) = read(path)?;
+    let secret = if data.starts_with(MAGIC) {
+        open(&name, &data, unseal)?
+    } else {
+        decode_text(&data, len).unwrap_or(data)
+    };
+    if secret.len() != len {
+        return Err(SecretError::WrongLength {
+            path: name,
+            got: secret.len(),
+            want: len,
+        });
+    }
+    // move the buffer out; nothing is copied
+    let mut secret = secret;
+    Ok(Secret::new(std::mem::take(&mut *secret)))
+}
+
+/// Seal `secret` for `seal-key`, with the passphrase or key file of `unseal`.
+pub fn seal(secret: &[u8], unseal: &Unseal) -> Result<Vec<u8>, SecretError> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    random(&mut salt);
+    random(&mut nonce);
+    let (kdf, cost, key) = match (&unseal.passphrase_file, &unseal.key_file) {
+        (Some(pp), _) => {
+            let key = argon2_key(
+                &read_passphrase(pp)?,
+                &salt,
+                ARGON2_M_KIB,
+                ARGON2_T,
+                ARGON2_P,
+            )?;
+            (KDF_PASSPHRASE, [ARGON2_M_KIB, ARGON2_T, ARGON2_P], key)
+        }
+        (None, Some(kf)) => (KDF_KEY_FILE, [0; 3], hkdf_key(&read_key_file(kf)?, &salt)?),
+        (None, None) => return Err(SecretError::NeedsPassphrase("seal-key".to_string())),
+    };
+    let mut out = Vec::with_capacity(HEADER_LEN + secret.len() + 16);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&[VERSION, kdf]);
+    for c in cost {
+        out.extend_from_slice(&c.to_be_bytes());
+    }
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+    let ct = ChaCha20Poly1305::new(Key::from_slice(&*key))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: secret,
+                aad: &out,
+            },
+        )
+        .expect("a key-sized message always seals");
+    out.extend_from_slice(&ct);
+    Ok(out)
+}
+
+fn open(name: &str, data: &[u8], unseal: &Unseal) -> Result<Zeroizing<Vec<u8>>, SecretError> {
+    if data.len() < HEADER_LEN + 16 {
+        return Err(SecretError::BadEnvelope(name.to_string()));
+    }
+    let version = data[MAGIC.len()];
+    if version != VERSION {
+        return Err(SecretError::UnsupportedVersion(name.to_string(), version));
+    }
+    let (header, ct) = data.split_at(HEADER_LEN);
+    let key = unseal.key(data[MAGIC.len() + 1], header, name)?;
+    ChaCha20Poly1305::new(Key::from_slice(&*key))
+        .decrypt(
+            Nonce::from_slice(&header[HEADER_LEN - NONCE_LEN..]),
+            Payload {
+                msg: ct,
+                aad: header,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| SecretError::Unseal(name.to_string()))
+}
+
+/// Read a secret path, resolving `cred:NAME`. Returns the name for messages.
+fn read(path: &Path) -> Result<(String, Zeroizing<Vec<u8>>), SecretError> {
+    let shown = path.display().to_string();
+    let file = match shown.strip_prefix("cred:") {
+        Some(cred) => {
+            let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+                .ok_or_else(|| SecretError::NoCredentialsDirectory(shown.clone()))?;
+            Path::new(&dir).join(cred)
+        }
+        None => path.to_path_buf(),
+    };
+    let data = fs::read(&file).map_err(|source| SecretError::Unreadable {
+        path: shown.clone(),
+        source,
+    })?;
+    Ok((shown, Zeroizing::new(data)))
+}
+
+/// Hex or base64 text of a `len`-byte secret; None if the data is neither.
+fn decode_text(data: &[u8], len: usize) -> Option<Zeroizing<Vec<u8>>> {
+    if data.len() == len {
+        return None;
+    }
+    let text = std::str::from_utf8(data).ok()?.trim();
+    if text.len() == 2 * len && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return (0..len)
+            .map(|i| u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .map(Zeroizing::new);
+    }
+    STANDARD.decode(text).ok().map(Zeroizing::new)
+}
+
+fn read_passphrase(path: &Path) -> Result<Zeroizing<Vec<u8>>, SecretError> {
+    let (name, mut pp) = read(path)?;
+    while matches!(pp.last(), Some(b'\n' | b'\r')) {
+        pp.pop();
+    }
+    if pp.is_empty() {
+        return Err(SecretError::EmptyPassphrase(name));
+    }
+    Ok(pp)
+}
+
+fn read_key_file(path: &Path) -> Result<Zeroizing<Vec<u8>>, SecretError> {
+    let (name, key) = read(path)?;
+    if key.len() < MIN_KEY_FILE_LEN {
+        return Err(SecretError::ShortKeyFile(name));
+    }
+    Ok(key)
+}
+
+fn argon2_key(
+    passphrase: &[u8],
+    salt: &[u8],
+    m_kib: u32,
+    t: u32,
+    p: u32,
+) -> Result<Zeroizing<[u8; 32]>, SecretError> {
+    let params = Params::new(m_kib, t, p, Some(32)).map_err(|e| SecretError::Kdf(e.to_string()))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, &mut *key)
+        .map_err(|e| SecretError::Kdf(e.to_string()))?;
+    Ok(key)
+}
+
+fn hkdf_key(ikm: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, SecretError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(HKDF_INFO, &mut *key)
+        .map_err(|e| SecretError::Kdf(e.to_string()))?;
+    Ok(key)
+}
+
+fn random(buf: &mut [u8]) {
+    getrandom::getrandom(buf).expect("OS random number generator");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ticket::{Terms, TicketStore};
+
+    fn tmp(dir: &tempfile::TempDir, name: &str, data: &[u8]) -> PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// Shares its bytes, so they can be inspected after the wrapper is gone.
+    struct Shared(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Zeroize for Shared {
+        fn zeroize(&mut self) {
+            self.0.borrow_mut().as_mut_slice().zeroize();
+        }
+    }
+
+    #[test]
+    fn secrets_are_wiped_on_drop_and_redacted() {
+        let bytes = std::rc::Rc::new(std::cell::RefCell::new(vec![0x5a; 32]));
+        let secret = Secret::new(Shared(bytes.clone()));
+        assert_eq!(format!("{:?}", secret), "Secret([REDACTED])");
+        assert!(bytes.borrow().iter().all(|b| *b == 0x5a));
+        drop(secret);
+        assert!(bytes.borrow().iter().all(|b| *b == 0));
+
+        let psk = Secret::new(vec![0x5au8; 32]);
+        assert!(!format!("{:?}", psk).contains("90"));
+
+        // derived keys and session tickets are wiped the same way: tickets
+        // stay in the store for up to --ticket-secs
+        fn wiped_on_drop<T: ZeroizeOnDrop>() {}
+        wiped_on_drop::<Secret<[u8; 32]>>();
+        wiped_on_drop::<crate::ticket::Ticket>();
+        let now = std::time::Instant::now();
+        let mut store = TicketStore::new(std::time::Duration::from_secs(60), 4);
+        let secret = crate::ticket::ticket_secret(&[1u8; 32], &[2u8; 32]);
+        let id = store.issue(secret.expose(), Terms::default(), now).unwrap();
+        let mut ticket = store.remove(&id).unwrap();
+        assert_eq!(ticket.secret(), secret.expose());
+        ticket.zeroize();
+        assert_eq!(ticket.secret(), &[0u8; 32]);
+    }
+
+    #[test]
+    fn raw_base64_and_hex_secrets_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = [0xabu8; 32];
+        let none = Unseal::default();
+        for (name, data) in [
+            ("raw", key.to_vec()),
+            ("b64", format!("{}\n", STANDARD.encode(key)).into_bytes()),
+            ("hex", "ab".repeat(32).into_bytes()),
+        ] {
+            assert_eq!(
+                load(&tmp(&dir, name, &data), 32, &none).unwrap().expose(),
+                &key
+            );
+        }
+        let short = tmp(&dir, "short", &[1u8; 16]);
+        assert!(matches!(
+            load(&short, 32, &none),
+            Err(SecretError::WrongLength { got: 16, .. })
+        ));
+    }
+
+    #[test]
+    fn sealed_secrets_need_the_right_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = [7u8; 32];
+        let unwrap = Unseal {
+            key_file: Some(tmp(&dir, "kf", &[1u8; 32])),
+            ..Default::default()
+        };
+        let sealed = tmp(&dir, "sealed", &seal(&key, &unwrap).unwrap());
+        assert_eq!(load(&sealed, 32, &unwrap).unwrap().expose(), &key);
+        assert!(matches!(
+            load(&sealed, 32, &Unseal::default()),
+            Err(SecretError::NeedsKeyFile(_))
+        ));
+        let wrong = Unseal {
+            key_file: Some(tmp(&dir, "kf2", &[2u8; 32])),
+            ..Default::default()
+        };
+        assert!(matches!(
+            load(&sealed, 32, &wrong),
+            Err(SecretError::Unseal(_))
+        ));
+    }
+
+    #[test]
+    fn argon2_cost_above_seal_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let unwrap = Unseal {
+            passphrase_file: Some(tmp(&dir, "pp", b"correct horse\n")),
+            ..Default::default()
+        };
+        // a passphrase envelope asking for 4 GiB, 3 passes, 1 lane
+        let mut data = seal(
+            &[7u8; 32],
+            &Unseal {
+                key_file: Some(tmp(&dir, "kf", &[1u8; 32])),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        data[MAGIC.len() + 1] = KDF_PASSPHRASE;
+        let costs = [4 * 1024 * 1024, ARGON2_T, ARGON2_P];
+        for (i, c) in costs.iter().enumerate() {
+            let off = MAGIC.len() + 2 + 4 * i;
+            data[off..off + 4].copy_from_slice(&c.to_be_bytes());
+        }
+        let sealed = tmp(&dir, "sealed", &data);
+        assert!(matches!(
+            load(&sealed, 32, &unwrap),
+            Err(SecretError::KdfCost { m_kib: 4194304, .. })
+        ));
+        // as are extra passes or lanes at the usual memory
+        for (i, c) in [(1, ARGON2_T + 1), (2, ARGON2_P + 1)] {
+            let mut data = data.clone();
+            let off = MAGIC.len() + 2;
+            data[off..off + 4].copy_from_slice(&ARGON2_M_KIB.to_be_bytes());
+            data[off + 4 * i..off + 4 * i + 4].copy_from_slice(&c.to_be_bytes());
+            let sealed = tmp(&dir, "sealed", &data);
+            assert!(matches!(
+                load(&sealed, 32, &unwrap),
+                Err(SecretError::KdfCost { .. })
+            ));
+        }
+    }
+}
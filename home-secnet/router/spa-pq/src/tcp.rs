@@ -0,0 +1,279 @@
+// Knocks over TCP, for networks that only let TCP out.
+//
+// A client connects, sends `u16 len (BE) | frame` where `frame` is exactly
+// what it would have put in a UDP datagram (any --encoding), and the daemon
+// closes the connection. The daemon never writes anything back, so a peer
+// cannot tell a valid knock from an invalid one; with --reply encrypted or ok
+// the reply is simply not sent over TCP.
+//
+// Each connection gets a short-lived thread with a hard deadline for the whole
+// frame (slow senders are cut off), and the number of connections in flight is
+// capped, overall and per source address, so one host cannot hold every slot.
+// Frames are handed to the main loop over a channel so they go through
+// the same rate limiter and checks as UDP knocks; a datagram on a socketpair
+// wakes the loop from poll(2) for each one.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Largest frame accepted; a UDP knock never exceeds one Ethernet datagram.
+/// The client sizes its TCP knocks to this (TCP_MAX_FRAME in spa-pq-client).
+pub const MAX_FRAME: usize = 1472;
+/// Connections in flight from one source address; a client sends one knock.
+const MAX_PER_SOURCE: usize = 2;
+
+pub struct TcpKnocks {
+    listener: TcpListener,
+    in_flight: Arc<AtomicUsize>,
+    max_conns: usize,
+    per_source: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    timeout: Duration,
+    tx: Sender<(Vec<u8>, SocketAddr)>,
+    rx: Receiver<(Vec<u8>, SocketAddr)>,
+    /// Connection threads send on the first after queueing a frame
+    wake: (Arc<UnixDatagram>, UnixDatagram),
+}
+
+impl TcpKnocks {
+    pub fn bind(addr: &str, max_conns: usize, timeout: Duration) -> Result<Self> {
+        let listener = TcpListener::bind(addr).with_context(|| format!("bind tcp {}", addr))?;
+        listener.set_nonblocking(true)?;
+        let (tx, rx) = mpsc::channel();
+        // opened now: the sandbox denies socketpair
+        let (wake_tx, wake_rx) = UnixDatagram::pair().context("tcp wake socketpair")?;
+        wake_tx.set_nonblocking(true)?;
+        wake_rx.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_conns,
+            per_source: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+            tx,
+            rx,
+            wake: (Arc::new(wake_tx), wake_rx),
+        })
+    }
+
+    /// The listener and the wake socket, for waiting on them.
+    pub fn fds(&self) -> [BorrowedFd<'_>; 2] {
+        [self.listener.as_fd(), self.wake.1.as_fd()]
+    }
+
+    /// Accept pending connections and return the next complete frame, if any.
+    pub fn poll(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        // drained before the channel, so a frame queued later wakes poll(2)
+        while self.wake.1.recv(&mut [0u8; 16]).is_ok() {}
+        loop {
+            match self.listener.accept() {
+                Ok((stream, src)) => self.spawn(stream, src),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("tcp accept: {}", e);
+                    break;
+                }
+            }
+        }
+        self.rx.try_recv().ok()
+    }
+
+    fn spawn(&self, stream: TcpStream, src: SocketAddr) {
+        if self.in_flight.load(Ordering::Relaxed) >= self.max_conns {
+            // over the cap: drop the connection unread
+            return;
+        }
+        if !take_slot(&self.per_source, src.ip()) {
+            return;
+        }
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let in_flight = Arc::clone(&self.in_flight);
+        let per_source = Arc::clone(&self.per_source);
+        let tx = self.tx.clone();
+        let wake = Arc::clone(&self.wake.0);
+        let deadline = Instant::now() + self.timeout;
+        let spawned = thread::Builder::new()
+            .name("spa-tcp".into())
+            .spawn(move || {
+                if let Some(frame) = read_frame(&stream, deadline) {
+                    let _ = tx.send((frame, src));
+                    // a full buffer means a wakeup is pending already
+                    let _ = wake.send(&[1]);
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+                release_slot(&per_source, src.ip());
+                in_flight.fetch_sub(1, Ordering::Relaxed);
+            });
+        if spawned.is_err() {
+            release_slot(&self.per_source, src.ip());
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Count a connection from `ip`, unless it already has MAX_PER_SOURCE.
+fn take_slot(per_source: &Mutex<HashMap<IpAddr, usize>>, ip: IpAddr) -> bool {
+    let Ok(mut conns) = per_source.lock() else {
+        return false;
+    };
+    let n = conns.entry(ip).or_insert(0);
+    if *n >= MAX_PER_SOURCE {
+        return false;
+    }
+    *n += 1;
+    true
+}
+
+fn release_slot(per_source: &Mutex<HashMap<IpAddr, usize>>, ip: IpAddr) {
+    let Ok(mut conns) = per_source.lock() else {
+        return;
+    };
+    if let Some(n) = conns.get_mut(&ip) {
+        *n -= 1;
+        if *n == 0 {
+            conns.remove(&ip);
+        }
+    }
+}
+
+/// Read one length-prefixed frame, giving up at `deadline`.
+fn read_frame(mut stream: &TcpStream, deadline: Instant) -> Option<Vec<u8>> {
+    stream.set_nonblocking(false).ok()?;
+    let mut len = [0u8; 2];
+    read_full(&mut stream, &mut len, deadline)?;
+    let len = u16::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_FRAME {
+        return None;
+    }
+    let mut frame = vec![0u8; len];
+    read_full(&mut stream, &mut frame, deadline)?;
+    Some(frame)
+}
+
+fn read_full(stream: &mut &TcpStream, buf: &mut [u8], deadline: Instant) -> Option<()> {
+    let mut off = 0;
+    while off < buf.len() {
+        let left = deadline.checked_duration_since(Instant::now())?;
+        if left.is_zero() {
+            return None;
+        }
+        stream.set_read_timeout(Some(left)).ok()?;
+        match stream.read(&mut buf[off..]) {
+            Ok(0) => return None,
+            Ok(n) => off += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn knocks() -> TcpKnocks {
+        TcpKnocks::bind("127.0.0.1:0", 4, Duration::from_millis(300)).unwrap()
+    }
+
+    fn wait_frame(t: &mut TcpKnocks) -> Option<Vec<u8>> {
+        let until = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < until {
+            if let Some((f, _)) = t.poll() {
+                return Some(f);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        None
+    }
+
+    #[test]
+    fn frame_is_delivered_and_connection_closed() {
+        let mut t = knocks();
+        let addr = t.listener.local_addr().unwrap();
+        let mut c = TcpStream::connect(addr).unwrap();
+        c.write_all(&[0, 3, 1, 2, 3]).unwrap();
+        assert_eq!(wait_frame(&mut t).unwrap(), vec![1, 2, 3]);
+        // nothing is ever written back
+        let mut buf = [0u8; 8];
+        c.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(c.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn connections_and_frames_wake_poll() {
+        use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+        let mut t = knocks();
+        let addr = t.listener.local_addr().unwrap();
+        let mut c = TcpStream::connect(addr).unwrap();
+        c.write_all(&[0, 2, 4, 5]).unwrap();
+        // each wait ends with work to do, never at the timeout
+        for _ in 0..4 {
+            let mut fds = t.fds().map(|fd| PollFd::new(fd, PollFlags::POLLIN));
+            assert!(poll(&mut fds, PollTimeout::from(2000u16)).unwrap() > 0);
+            if let Some((frame, _)) = t.poll() {
+                assert_eq!(frame, vec![4, 5]);
+                return;
+            }
+        }
+        panic!("frame never arrived");
+    }
+
+    #[test]
+    fn oversized_and_slow_frames_are_dropped() {
+        let mut t = knocks();
+        let addr = t.listener.local_addr().unwrap();
+        let mut big = TcpStream::connect(addr).unwrap();
+        big.write_all(&((MAX_FRAME + 1) as u16).to_be_bytes())
+            .unwrap();
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(&[0, 10, 1]).unwrap();
+        assert!(wait_frame(&mut t).is_none());
+    }
+
+    #[test]
+    fn connections_are_capped_per_source() {
+        let mut t = knocks();
+        let addr = t.listener.local_addr().unwrap();
+        // two idle connections hold this address's slots
+        let idle: Vec<TcpStream> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut third = TcpStream::connect(addr).unwrap();
+        third.write_all(&[0, 1, 7]).unwrap();
+        let mut buf = [0u8; 1];
+        third
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(t.poll().is_none());
+        // closed unread
+        assert!(matches!(third.read(&mut buf), Ok(0) | Err(_)));
+
+        // another source still gets in while they are held
+        let other =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        other
+            .bind(&"127.0.0.2:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        other.connect(&addr.into()).unwrap();
+        let mut other: TcpStream = other.into();
+        other.write_all(&[0, 1, 8]).unwrap();
+        assert_eq!(wait_frame(&mut t).unwrap(), vec![8]);
+
+        // once the idle ones time out, the address has its slots back
+        drop(idle);
+        thread::sleep(Duration::from_millis(400));
+        let mut again = TcpStream::connect(addr).unwrap();
+        again.write_all(&[0, 1, 9]).unwrap();
+        assert_eq!(wait_frame(&mut t).unwrap(), vec![9]);
+        assert!(t.per_source.lock().unwrap().len() <= 1);
+    }
+}
@@ -0,0 +1,341 @@
+// WireGuard peer configuration over generic netlink, as `wg set` does it.
+//
+// Peer gating adds and removes a peer per grant; running `wg` for each one
+// costs a fork and exec on the helper's hot path and ties it to the tools
+// being installed. Instead the helper speaks the kernel's WireGuard API
+// directly: it resolves the "wireguard" generic netlink family through
+// nlctrl, then sends WG_CMD_SET_DEVICE with one nested peer and waits for the
+// kernel's ack. Only the attributes `wg set` uses are encoded (see
+// include/uapi/linux/wireguard.h).
+
+use crate::secrets::Secret;
+use anyhow::{anyhow, Context, Result};
+use nix::libc;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::Read;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const NETLINK_GENERIC: i32 = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLA_F_NESTED: u16 = 0x8000;
+const NLMSG_HDR_LEN: usize = 16;
+const GENL_HDR_LEN: usize = 4;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_SET_DEVICE: u8 = 1;
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PEERS: u16 = 8;
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+const WGPEER_F_REMOVE_ME: u32 = 1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 2;
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+/// What to do with one peer of an interface.
+pub enum PeerChange<'a> {
+    /// Add or update the peer, replacing its allowed IPs
+    Set {
+        allowed_ips: &'a [(IpAddr, u8)],
+        preshared_key: Option<&'a [u8; 32]>,
+    },
+    Remove,
+}
+
+/// Apply `change` to the peer `public_key` of `iface`.
+pub fn set_peer(iface: &str, public_key: &[u8; 32], change: PeerChange) -> Result<()> {
+    let nl = Netlink::open()?;
+    let family = nl.family_id(WG_GENL_NAME).with_context(|| {
+        format!(
+            "resolve the {} netlink family (is the module loaded?)",
+            WG_GENL_NAME
+        )
+    })?;
+    // the message may carry the preshared key
+    let msg = Secret::new(set_device(family, 2, iface, public_key, &change));
+    nl.request(msg.expose())
+        .with_context(|| format!("configure peer on {}", iface))?;
+    Ok(())
+}
+
+struct Netlink {
+    sock: Socket,
+}
+
+impl Netlink {
+    fn open() -> Result<Self> {
+        let sock = Socket::new(
+            Domain::from(libc::AF_NETLINK),
+            Type::RAW,
+            Some(Protocol::from(NETLINK_GENERIC)),
+        )
+        .context("open generic netlink socket")?;
+        sock.set_read_timeout(Some(Duration::from_secs(3)))?;
+        Ok(Self { sock })
+    }
+
+    fn family_id(&self, name: &str) -> Result<u16> {
+        let reply = self.request(&get_family(1, name))?;
+        let attrs = reply
+            .get(GENL_HDR_LEN..)
+            .ok_or_else(|| anyhow!("short nlctrl reply"))?;
+        parse_attrs(attrs)
+            .into_iter()
+            .find(|(ty, _)| *ty == CTRL_ATTR_FAMILY_ID)
+            .and_then(|(_, v)| Some(u16::from_ne_bytes(v.get(..2)?.try_into().ok()?)))
+            .ok_or_else(|| anyhow!("nlctrl reply without a family id"))
+    }
+
+    /// Send one request (with NLM_F_ACK) and return the payload of the
+    /// kernel's reply message, or nothing for a bare ack. Errors become `Err`.
+    fn request(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        // unconnected netlink sockets send to the kernel
+        self.sock.send(msg).context("netlink send")?;
+        let seq = u32::from_ne_bytes(msg[8..12].try_into().expect("4 bytes"));
+        let mut buf = vec![0u8; 8192];
+        let mut reply = None;
+        loop {
+            let n = (&self.sock).read(&mut buf).context("netlink receive")?;
+            for (ty, msg_seq, payload) in parse_messages(&buf[..n]) {
+                if msg_seq != seq {
+                    continue;
+                }
+                // the answer, if any, comes before the ack
+                if ty != NLMSG_ERROR {
+                    reply = Some(payload.to_vec());
+                    continue;
+                }
+                let code = payload
+                    .get(..4)
+                    .map(|b| i32::from_ne_bytes(b.try_into().expect("4 bytes")))
+                    .ok_or_else(|| anyhow!("short netlink error"))?;
+                if code != 0 {
+                    return Err(std::io::Error::from_raw_os_error(-code).into());
+                }
+                return Ok(reply.unwrap_or_default());
+            }
+        }
+    }
+}
+
+/// nlctrl request for a family's id.
+fn get_family(seq: u32, name: &str) -> Vec<u8> {
+    let mut attrs = Vec::new();
+    put_attr(&mut attrs, CTRL_ATTR_FAMILY_NAME, &nul_terminated(name));
+    message(
+        GENL_ID_CTRL,
+        NLM_F_REQUEST | NLM_F_ACK,
+        seq,
+        CTRL_CMD_GETFAMILY,
+        1,
+        &attrs,
+    )
+}
+
+/// WG_CMD_SET_DEVICE for one peer of `iface`.
+fn set_device(
+    family: u16,
+    seq: u32,
+    iface: &str,
+    public_key: &[u8; 32],
+    change: &PeerChange,
+) -> Vec<u8> {
+    let mut attrs = Vec::new();
+    put_attr(&mut attrs, WGDEVICE_A_IFNAME, &nul_terminated(iface));
+    nest(&mut attrs, WGDEVICE_A_PEERS, |peers| {
+        nest(peers, 0, |peer| {
+            put_attr(peer, WGPEER_A_PUBLIC_KEY, public_key);
+            match change {
+                PeerChange::Remove => {
+                    put_attr(peer, WGPEER_A_FLAGS, &WGPEER_F_REMOVE_ME.to_ne_bytes());
+                }
+                PeerChange::Set {
+                    allowed_ips,
+                    preshared_key,
+                } => {
+                    let flags = WGPEER_F_REPLACE_ALLOWEDIPS;
+                    put_attr(peer, WGPEER_A_FLAGS, &flags.to_ne_bytes());
+                    if let Some(psk) = preshared_key {
+                        put_attr(peer, WGPEER_A_PRESHARED_KEY, *psk);
+                    }
+                    nest(peer, WGPEER_A_ALLOWEDIPS, |ips| {
+                        for (addr, cidr) in allowed_ips.iter() {
+                            nest(ips, 0, |ip| {
+                                let (family, bytes) = match addr {
+                                    IpAddr::V4(a) => (libc::AF_INET, a.octets().to_vec()),
+                                    IpAddr::V6(a) => (libc::AF_INET6, a.octets().to_vec()),
+                                };
+                                put_attr(ip, WGALLOWEDIP_A_FAMILY, &(family as u16).to_ne_bytes());
+                                put_attr(ip, WGALLOWEDIP_A_IPADDR, &bytes);
+                                put_attr(ip, WGALLOWEDIP_A_CIDR_MASK, &[*cidr]);
+                            });
+                        }
+                    });
+                }
+            }
+        });
+    });
+    message(
+        family,
+        NLM_F_REQUEST | NLM_F_ACK,
+        seq,
+        WG_CMD_SET_DEVICE,
+        WG_GENL_VERSION,
+        &attrs,
+    )
+}
+
+/// nlmsghdr | genlmsghdr | attributes
+fn message(ty: u16, flags: u16, seq: u32, cmd: u8, version: u8, attrs: &[u8]) -> Vec<u8> {
+    let len = (NLMSG_HDR_LEN + GENL_HDR_LEN + attrs.len()) as u32;
+    let mut m = Vec::with_capacity(len as usize);
+    m.extend_from_slice(&len.to_ne_bytes());
+    m.extend_from_slice(&ty.to_ne_bytes());
+    m.extend_from_slice(&flags.to_ne_bytes());
+    m.extend_from_slice(&seq.to_ne_bytes());
+    // port id 0: the kernel fills in ours
+    m.extend_from_slice(&0u32.to_ne_bytes());
+    m.extend_from_slice(&[cmd, version, 0, 0]);
+    m.extend_from_slice(attrs);
+    m
+}
+
+fn put_attr(buf: &mut Vec<u8>, ty: u16, data: &[u8]) {
+    buf.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&ty.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// An attribute holding the attributes `fill` writes.
+fn nest(buf: &mut Vec<u8>, ty: u16, fill: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    fill(buf);
+    let len = (buf.len() - start) as u16;
+    buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    buf[start + 2..start + 4].copy_from_slice(&(ty | NLA_F_NESTED).to_ne_bytes());
+}
+
+fn nul_terminated(s: &str) -> Vec<u8> {
+    let mut v = s.as_bytes().to_vec();
+    v.push(0);
+    v
+}
+
+/// (type without flags, payload) of each attribute in `buf`.
+fn parse_attrs(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut out = Vec::new();
+    while buf.len() >= 4 {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let ty = u16::from_ne_bytes([buf[2], buf[3]]) & !NLA_F_NESTED;
+        if len < 4 || len > buf.len() {
+            break;
+        }
+        out.push((ty, &buf[4..len]));
+        buf = &buf[len.next_multiple_of(4).min(buf.len())..];
+    }
+    out
+}
+
+/// (type, seq, payload after nlmsghdr) of each message in one datagram.
+fn parse_messages(mut buf: &[u8]) -> Vec<(u16, u32, &[u8])> {
+    let mut out = Vec::new();
+    while buf.len() >= NLMSG_HDR_LEN {
+        let len = u32::from_ne_bytes(buf[0..4].try_into().expect("4 bytes")) as usize;
+        if len < NLMSG_HDR_LEN || len > buf.len() {
+            break;
+        }
+        let ty = u16::from_ne_bytes([buf[4], buf[5]]);
+        let seq = u32::from_ne_bytes(buf[8..12].try_into().expect("4 bytes"));
+        out.push((ty, seq, &buf[NLMSG_HDR_LEN..len]));
+        buf = &buf[len.next_multiple_of(4).min(buf.len())..];
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested<'a>(attrs: &[(u16, &'a [u8])], ty: u16) -> &'a [u8] {
+        attrs.iter().find(|(t, _)| *t == ty).expect("attribute").1
+    }
+
+    #[test]
+    fn set_device_carries_one_peer() {
+        let key = [7u8; 32];
+        let psk = [9u8; 32];
+        let ips = [
+            ("10.8.0.2".parse().unwrap(), 32),
+            ("fd00::2".parse().unwrap(), 128),
+        ];
+        let change = PeerChange::Set {
+            allowed_ips: &ips,
+            preshared_key: Some(&psk),
+        };
+        let msg = set_device(0x1d, 5, "wg0", &key, &change);
+        assert_eq!(
+            msg.len(),
+            u32::from_ne_bytes(msg[..4].try_into().unwrap()) as usize
+        );
+        let [(ty, seq, payload)] = parse_messages(&msg)[..] else {
+            panic!("one message");
+        };
+        assert_eq!((ty, seq), (0x1d, 5));
+        assert_eq!(payload[..2], [WG_CMD_SET_DEVICE, WG_GENL_VERSION]);
+        let device = parse_attrs(&payload[GENL_HDR_LEN..]);
+        assert_eq!(nested(&device, WGDEVICE_A_IFNAME), b"wg0\0");
+        let peers = parse_attrs(nested(&device, WGDEVICE_A_PEERS));
+        let peer = parse_attrs(peers[0].1);
+        assert_eq!(nested(&peer, WGPEER_A_PUBLIC_KEY), key);
+        assert_eq!(nested(&peer, WGPEER_A_PRESHARED_KEY), psk);
+        assert_eq!(
+            nested(&peer, WGPEER_A_FLAGS),
+            WGPEER_F_REPLACE_ALLOWEDIPS.to_ne_bytes()
+        );
+        let allowed = parse_attrs(nested(&peer, WGPEER_A_ALLOWEDIPS));
+        assert_eq!(allowed.len(), 2);
+        let v6 = parse_attrs(allowed[1].1);
+        assert_eq!(
+            nested(&v6, WGALLOWEDIP_A_FAMILY),
+            (libc::AF_INET6 as u16).to_ne_bytes()
+        );
+        assert_eq!(nested(&v6, WGALLOWEDIP_A_IPADDR).len(), 16);
+        assert_eq!(nested(&v6, WGALLOWEDIP_A_CIDR_MASK), [128]);
+    }
+
+    #[test]
+    fn removal_only_names_the_key() {
+        let msg = set_device(0x1d, 6, "wg0", &[7; 32], &PeerChange::Remove);
+        let payload = parse_messages(&msg)[0].2;
+        let device = parse_attrs(&payload[GENL_HDR_LEN..]);
+        let peers = parse_attrs(nested(&device, WGDEVICE_A_PEERS));
+        let peer = parse_attrs(peers[0].1);
+        assert_eq!(peer.len(), 2);
+        assert_eq!(
+            nested(&peer, WGPEER_A_FLAGS),
+            WGPEER_F_REMOVE_ME.to_ne_bytes()
+        );
+    }
+
+    #[test]
+    fn families_resolve_through_nlctrl() {
+        // nlctrl is itself a generic netlink family, on every kernel
+        let nl = Netlink::open().unwrap();
+        assert_eq!(nl.family_id("nlctrl").unwrap(), GENL_ID_CTRL);
+        assert!(nl.family_id("no-such-family").is_err());
+    }
+}
@@ -103,6 +103,14 @@ export LAN_IF="${LAN_IF:-${ROUTER_LAN_IF:-br-lan}}"
 export WAN_IF="${WAN_IF:-${ROUTER_WAN_IF:-wan}}"
 # Ensure related variables are exported for template substitution (safe if unset)
 export LAN_ADDR LAN_NETMASK WG_PORT WG_SERVER_IP WG_SERVER_PRIVKEY
+# Wrapper gating: the Hysteria2 port only admits sources with a wrapper grant
+WRAP_SPA_MATCH=""
+SPA_WRAP_ARGS=""
+if [[ "${SPA_ENABLE:-false}" == "true" && "${SPA_PQ_WRAP_GATING:-false}" == "true" ]]; then
+  WRAP_SPA_MATCH="ip saddr @wrap_spa_allow "
+  SPA_WRAP_ARGS=" --wrap-gating"
+fi
+export WRAP_SPA_MATCH SPA_WRAP_ARGS
 log_info "[08] Using LAN_IF=${LAN_IF} WAN_IF=${WAN_IF}"
 
 # Render OpenWRT overlay templates (envsubst/perl)
@@ -348,6 +356,11 @@ if [[ "${SPA_ENABLE:-false}" == "true" ]]; then
   "psk_b64": "${PSK_B64}"
 }
 EOF
+    # Optional: only accept knocks on these interfaces (e.g. the WAN uplinks)
+    SPA_IFACE_ARGS=""
+    for iface in ${SPA_PQ_INTERFACES:-}; do
+      SPA_IFACE_ARGS+=" --interface ${iface}"
+    done
     # Render systemd unit with ExecStart args
     cat > "$ROOT_DIR/render/router/systemd/spa-pq.service" <<EOF
 [Unit]
@@ -357,17 +370,20 @@ Wants=network-online.target nftables.service
 
 [Service]
 ExecStart=/usr/local/bin/home-secnet-spa-pq run \
-  --listen 0.0.0.0:${SPA_PQ_PORT:-62201} \
+  --listen 0.0.0.0:${SPA_PQ_PORT:-62201}${SPA_IFACE_ARGS} \
   --wg-port ${WG_PORT} \
   --kem-priv /etc/spa/kem_priv.bin \
   --psk-file ${SPA_PQ_PSK_FILE:-/etc/spa/psk.bin} \
   --open-secs ${SPA_PQ_OPEN_SECS:-45} \
   --window-secs ${SPA_PQ_WINDOW_SECS:-30} \
-  --nft-table inet \
-  --nft-chain wg_spa_allow
+  --port-grants ${SPA_PQ_PORT_GRANTS:-off}${SPA_WRAP_ARGS} \
+  --privsep \
+  --manage-objects
 AmbientCapabilities=CAP_NET_ADMIN CAP_NET_RAW
 CapabilityBoundingSet=CAP_NET_ADMIN CAP_NET_RAW
 NoNewPrivileges=true
+LimitCORE=0
+LimitMEMLOCK=infinity
 ProtectSystem=strict
 ProtectHome=true
 PrivateTmp=true